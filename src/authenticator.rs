@@ -10,12 +10,25 @@
 //! TODO: Confirm that dependency injection of device logic
 //! into CTAPHID driver is the right approach.
 
+#[cfg(test)]
+pub mod mock;
+
+use ctap_types::ctaphid::Operation;
+
+use crate::constants::U2F_RESPONSE_LENGTH;
+use crate::pipe::{CtapStatus, WinkPattern};
 use crate::types::{
+    consts,
     AssertionResponses,
     AttestationObject,
+    AuthenticatorData,
     AuthenticatorInfo,
+    Bytes,
     GetAssertionParameters,
+    HmacSecretInput,
     MakeCredentialParameters,
+    PublicKeyCredentialDescriptor,
+    ctap1,
 };
 
 // trait SimpleFuture {
@@ -49,8 +62,9 @@ pub trait Ctap2Api {
 /// Maybe with core::future::Future?
 pub trait Api
 {
-    /// describe authenticator capabilities
-    fn get_info(&mut self) -> AuthenticatorInfo;
+    /// describe authenticator capabilities,
+    /// may fail if they need to be read from flash.
+    fn get_info(&mut self) -> Result<AuthenticatorInfo>;
 
     /// eventually generate a credential with specified options
     fn make_credential(&mut self, params: &MakeCredentialParameters)
@@ -61,10 +75,99 @@ pub trait Api
         -> Result<AssertionResponses>;
 
     fn reset(&mut self) -> Result<()>;
+
+    /// whether the credential ID was issued by us (and is intact),
+    /// e.g. for exclude lists
+    fn owns_credential(&self, descriptor: &PublicKeyCredentialDescriptor) -> bool;
+
+    /// the hmac-secret output of a get-assertion with a credential created
+    /// with the extension: the HMACs of the salts, encrypted with the shared
+    /// secret of the input's key agreement. `None` leaves out the output,
+    /// the default for authenticators without key agreement.
+    fn hmac_secret(&mut self, _credential: &PublicKeyCredentialDescriptor, _input: &HmacSecretInput)
+        -> Result<Option<Bytes<consts::U64>>>
+    {
+        Ok(None)
+    }
+
+    /// last chance to modify authenticator data before it is serialized
+    /// and signed, e.g. to add extension outputs or adjust flags.
+    fn decorate_auth_data(&mut self, _auth_data: &mut AuthenticatorData) {}
+
+    /// certificate chain for packed attestation, starting with the
+    /// attestation certificate itself. Empty means self attestation.
+    fn attestation_certificates(&self) -> &[&[u8]] {
+        &[]
+    }
+
+    /// called right before a (possibly slow) make-credential or get-assertion
+    /// runs, e.g. to light an LED. The app passes on what
+    /// `Pipe::take_user_interaction` hands out. Must not block.
+    fn begin_user_interaction(&mut self, _operation: Operation) {}
+
+    /// the host asked for a wink (CTAPHID_WINK), e.g. flash an LED.
+    /// The app passes on what `Pipe::take_wink` hands out, the pattern
+    /// is a suggestion. Must not block.
+    fn wink(&mut self, _pattern: WinkPattern) {}
+
+    /// answer a CTAP1/U2F request APDU (register or authenticate), without
+    /// the status word, which is added by `dispatch_msg`. The app passes on
+    /// what `Pipe::pending_msg` hands out, if it enabled `Pipe::set_ctap1`,
+    /// which it must not for authenticators lacking this.
+    fn msg(&mut self, _apdu: &[u8]) -> ctap1::Result<Bytes<U2F_RESPONSE_LENGTH>> {
+        Err(ctap1::Error::InsNotSupported)
+    }
+
+    /// CTAPHID_INIT handed out a new channel, e.g. to set up
+    /// per-channel state.
+    fn on_channel_allocated(&mut self, _channel: u32) {}
 }
 
-trait Wink {
-    fn wink(&self);
+/// Run a request against an `Api` implementation, notifying it via
+/// `begin_user_interaction` before operations that involve the user.
+pub fn dispatch<A: Api + ?Sized>(authenticator: &mut A, request: &Ctap2Request) -> Result<Ctap2Response> {
+    match request {
+        Ctap2Request::GetInfo => {
+            Ok(Ctap2Response::GetInfo(authenticator.get_info()?))
+        },
+        Ctap2Request::MakeCredential(params) => {
+            authenticator.begin_user_interaction(Operation::MakeCredential);
+            Ok(Ctap2Response::MakeCredential(authenticator.make_credential(params)?))
+        },
+        Ctap2Request::GetAssertions(params) => {
+            authenticator.begin_user_interaction(Operation::GetAssertion);
+            Ok(Ctap2Response::GetAssertions(authenticator.get_assertions(params)?))
+        },
+        Ctap2Request::Reset => {
+            authenticator.reset()?;
+            Ok(Ctap2Response::Reset)
+        },
+    }
+}
+
+const U2F_VERSION: u8 = 0x03;
+
+/// Run a CTAP1/U2F request APDU against an `Api` implementation, answering
+/// U2F_VERSION right away. The response, status word included, goes to
+/// `Pipe::answer_msg`.
+pub fn dispatch_msg<A: Api + ?Sized>(authenticator: &mut A, apdu: &[u8]) -> Bytes<U2F_RESPONSE_LENGTH> {
+    let result = match apdu {
+        apdu if apdu.len() < 4 => Err(ctap1::Error::WrongData),
+        [0x00, U2F_VERSION, ..] => Ok(Bytes::try_from_slice(b"U2F_V2").unwrap()),
+        [0x00, ..] => authenticator.msg(apdu),
+        _ => Err(ctap1::Error::ClaNotSupported),
+    };
+    let status_only = |error: ctap1::Error| Bytes::try_from_slice(&(error as u16).to_be_bytes()).unwrap();
+    match result {
+        Ok(mut response) => {
+            // no room left for the status word
+            if response.extend_from_slice(&ctap1::NO_ERROR.to_be_bytes()).is_err() {
+                return status_only(ctap1::Error::WrongLength);
+            }
+            response
+        }
+        Err(error) => status_only(error),
+    }
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -121,3 +224,121 @@ pub enum Error {
     VendorLast = 0xFF,
 }
 
+
+impl From<Error> for CtapStatus {
+    fn from(error: Error) -> CtapStatus {
+        match error {
+            Error::Success => CtapStatus::Success,
+            Error::InvalidCommand => CtapStatus::InvalidCommand,
+            Error::InvalidParameter => CtapStatus::InvalidParameter,
+            Error::InvalidLength => CtapStatus::InvalidLength,
+            Error::InvalidSeq => CtapStatus::InvalidSeq,
+            Error::Timeout => CtapStatus::Timeout,
+            Error::ChannelBusy => CtapStatus::ChannelBusy,
+            Error::LockRequired => CtapStatus::LockRequired,
+            Error::InvalidChannel => CtapStatus::InvalidChannel,
+            Error::CborUnexpectedType => CtapStatus::CborUnexpectedType,
+            Error::InvalidCbor => CtapStatus::InvalidCbor,
+            Error::MissingParameter => CtapStatus::MissingParameter,
+            Error::LimitExceeded => CtapStatus::LimitExceeded,
+            Error::UnsupportedExtension => CtapStatus::UnsupportedExtension,
+            Error::CredentialExcluded => CtapStatus::CredentialExcluded,
+            Error::Processing => CtapStatus::Processing,
+            Error::InvalidCredential => CtapStatus::InvalidCredential,
+            Error::UserActionPending => CtapStatus::UserActionPending,
+            Error::OperationPending => CtapStatus::OperationPending,
+            Error::NoOperations => CtapStatus::NoOperations,
+            Error::UnsupportedAlgorithm => CtapStatus::UnsupportedAlgorithm,
+            Error::OperationDenied => CtapStatus::OperationDenied,
+            Error::KeyStoreFull => CtapStatus::KeyStoreFull,
+            Error::NotBusy => CtapStatus::NotBusy,
+            Error::NoOperationPending => CtapStatus::NoOperationPending,
+            Error::UnsupportedOption => CtapStatus::UnsupportedOption,
+            Error::InvalidOption => CtapStatus::InvalidOption,
+            Error::KeepaliveCancel => CtapStatus::KeepaliveCancel,
+            Error::NoCredentials => CtapStatus::NoCredentials,
+            Error::UserActionTimeout => CtapStatus::UserActionTimeout,
+            Error::NotAllowed => CtapStatus::NotAllowed,
+            Error::PinInvalid => CtapStatus::PinInvalid,
+            Error::PinBlocked => CtapStatus::PinBlocked,
+            Error::PinAuthInvalid => CtapStatus::PinAuthInvalid,
+            Error::PinAuthBlocked => CtapStatus::PinAuthBlocked,
+            Error::PinNotSet => CtapStatus::PinNotSet,
+            Error::PinRequired => CtapStatus::PinRequired,
+            Error::PinPolicyViolation => CtapStatus::PinPolicyViolation,
+            Error::PinTokenExpired => CtapStatus::PinTokenExpired,
+            Error::RequestTooLarge => CtapStatus::RequestTooLarge,
+            Error::ActionTimeout => CtapStatus::ActionTimeout,
+            Error::UpRequired => CtapStatus::UpRequired,
+            Error::Other => CtapStatus::Other,
+            Error::SpecLast => CtapStatus::SpecLast,
+            Error::ExtensionFirst => CtapStatus::ExtensionFirst,
+            Error::ExtensionLast => CtapStatus::ExtensionLast,
+            Error::VendorFirst => CtapStatus::VendorFirst,
+            Error::VendorLast => CtapStatus::VendorLast,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_status_bytes() {
+        let cases = [
+            (Error::Success, 0x00),
+            (Error::InvalidCommand, 0x01),
+            (Error::InvalidParameter, 0x02),
+            (Error::InvalidLength, 0x03),
+            (Error::InvalidSeq, 0x04),
+            (Error::Timeout, 0x05),
+            (Error::ChannelBusy, 0x06),
+            (Error::LockRequired, 0x0A),
+            (Error::InvalidChannel, 0x0B),
+            (Error::CborUnexpectedType, 0x11),
+            (Error::InvalidCbor, 0x12),
+            (Error::MissingParameter, 0x14),
+            (Error::LimitExceeded, 0x15),
+            (Error::UnsupportedExtension, 0x16),
+            (Error::CredentialExcluded, 0x19),
+            (Error::Processing, 0x21),
+            (Error::InvalidCredential, 0x22),
+            (Error::UserActionPending, 0x23),
+            (Error::OperationPending, 0x24),
+            (Error::NoOperations, 0x25),
+            (Error::UnsupportedAlgorithm, 0x26),
+            (Error::OperationDenied, 0x27),
+            (Error::KeyStoreFull, 0x28),
+            (Error::NotBusy, 0x29),
+            (Error::NoOperationPending, 0x2A),
+            (Error::UnsupportedOption, 0x2B),
+            (Error::InvalidOption, 0x2C),
+            (Error::KeepaliveCancel, 0x2D),
+            (Error::NoCredentials, 0x2E),
+            (Error::UserActionTimeout, 0x2F),
+            (Error::NotAllowed, 0x30),
+            (Error::PinInvalid, 0x31),
+            (Error::PinBlocked, 0x32),
+            (Error::PinAuthInvalid, 0x33),
+            (Error::PinAuthBlocked, 0x34),
+            (Error::PinNotSet, 0x35),
+            (Error::PinRequired, 0x36),
+            (Error::PinPolicyViolation, 0x37),
+            (Error::PinTokenExpired, 0x38),
+            (Error::RequestTooLarge, 0x39),
+            (Error::ActionTimeout, 0x3A),
+            (Error::UpRequired, 0x3B),
+            (Error::Other, 0x7F),
+            (Error::SpecLast, 0xDF),
+            (Error::ExtensionFirst, 0xE0),
+            (Error::ExtensionLast, 0xEF),
+            (Error::VendorFirst, 0xF0),
+            (Error::VendorLast, 0xFF),
+        ];
+        for &(error, byte) in cases.iter() {
+            let status: u8 = CtapStatus::from(error).into();
+            assert_eq!(status, byte);
+        }
+    }
+}
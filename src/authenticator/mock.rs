@@ -0,0 +1,473 @@
+//! Deterministic authenticator for protocol tests.
+//!
+//! Unlike the `InsecureRamAuthenticator`, there is no crypto at all:
+//! keys, credential IDs and signatures are fixed byte patterns and the
+//! signature counter starts at zero, so tests can assert exact bytes.
+//! User presence and errors are controlled by the test.
+
+use ctap_types::ctaphid::Operation;
+use heapless::{consts, String, Vec};
+
+use crate::{
+    authenticator::{Api, Error, Result},
+    bytes::Bytes,
+    pipe::{KeepAliveStatus, WinkPattern},
+    types::{
+        cose,
+        AssertionResponse,
+        AssertionResponses,
+        AttestationObject,
+        AttestationStatement,
+        AttestedCredentialData,
+        AuthenticatorData,
+        AuthenticatorInfo,
+        GetAssertionParameters,
+        HmacSecretInput,
+        MakeCredentialParameters,
+        NoneAttestationStatement,
+        PublicKeyCredentialDescriptor,
+    },
+};
+
+pub const MOCK_AAGUID: [u8; 16] = *b"MOCKAAGUID012345";
+pub const MOCK_CREDENTIAL_ID: [u8; 16] = [0xc1; 16];
+pub const MOCK_SIGNATURE: [u8; 8] = [0x5a; 8];
+
+/// What the (imaginary) user does when presence is requested.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Presence {
+    Given,
+    Declined,
+    /// never reacts, operations time out
+    Pending,
+}
+
+pub struct MockAuthenticator {
+    pub presence: Presence,
+    /// returned by the next operation instead of doing anything
+    pub inject_error: Option<Error>,
+    pub sign_count: u32,
+    /// number of times user presence was requested
+    pub presence_requests: u32,
+    pub winks: Vec<WinkPattern, consts::U4>,
+    /// operations announced via `begin_user_interaction`,
+    /// with the number of presence requests made by then
+    pub interactions: Vec<(Operation, u32), consts::U4>,
+    /// hmac-secret input of the last get-assertion
+    pub hmac_secret_input: Option<HmacSecretInput>,
+    /// channels announced via `on_channel_allocated`
+    pub allocated_channels: Vec<u32, consts::U4>,
+    /// CBOR map of extension outputs `decorate_auth_data` adds,
+    /// setting the ED flag
+    pub extension_outputs: Option<Bytes<consts::U80>>,
+}
+
+impl Default for MockAuthenticator {
+    fn default() -> Self {
+        Self {
+            presence: Presence::Given,
+            inject_error: None,
+            sign_count: 0,
+            presence_requests: 0,
+            winks: Vec::new(),
+            interactions: Vec::new(),
+            hmac_secret_input: None,
+            allocated_channels: Vec::new(),
+            extension_outputs: None,
+        }
+    }
+}
+
+impl MockAuthenticator {
+    /// what the transport should report in keepalives right now
+    pub fn keepalive_status(&self) -> KeepAliveStatus {
+        match self.presence {
+            Presence::Pending => KeepAliveStatus::UpNeeded,
+            _ => KeepAliveStatus::Processing,
+        }
+    }
+
+    /// the RP ID, zero-padded, instead of its hash
+    pub fn rp_id_hash(rp_id: &str) -> Bytes<consts::U32> {
+        let mut rp_id_hash = [0u8; 32];
+        let len = rp_id.len().min(32);
+        rp_id_hash[..len].copy_from_slice(&rp_id.as_bytes()[..len]);
+        Bytes::try_from_slice(&rp_id_hash).unwrap()
+    }
+
+    fn check(&mut self) -> Result<()> {
+        match self.inject_error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn request_presence(&mut self) -> Result<()> {
+        self.presence_requests += 1;
+        match self.presence {
+            Presence::Given => Ok(()),
+            Presence::Declined => Err(Error::OperationDenied),
+            Presence::Pending => Err(Error::UserActionTimeout),
+        }
+    }
+
+    fn auth_data(&mut self, rp_id: &str, flags: u8, attested_credential_data: Option<AttestedCredentialData>)
+        -> AuthenticatorData
+    {
+        let mut auth_data = AuthenticatorData {
+            rp_id_hash: Self::rp_id_hash(rp_id),
+            flags,
+            sign_count: self.sign_count.into(),
+            attested_credential_data: attested_credential_data.map(|data| data.serialize()),
+            extensions: None,
+        };
+        self.sign_count += 1;
+        self.decorate_auth_data(&mut auth_data);
+        auth_data
+    }
+}
+
+impl Api for MockAuthenticator {
+    fn get_info(&mut self) -> Result<AuthenticatorInfo> {
+        self.check()?;
+        let mut versions = Vec::new();
+        versions.push(String::from("FIDO_2_0")).unwrap();
+
+        Ok(AuthenticatorInfo {
+            versions,
+            aaguid: Bytes::try_from_slice(&MOCK_AAGUID).unwrap(),
+            ..AuthenticatorInfo::default()
+        })
+    }
+
+    fn make_credential(&mut self, params: &MakeCredentialParameters) -> Result<AttestationObject> {
+        self.check()?;
+        if let Some(exclude_list) = &params.exclude_list {
+            if exclude_list.iter().any(|descriptor| self.owns_credential(descriptor)) {
+                return Err(Error::CredentialExcluded);
+            }
+        }
+        self.request_presence()?;
+
+        let attested_credential_data = AttestedCredentialData {
+            aaguid: Bytes::try_from_slice(&MOCK_AAGUID).unwrap(),
+            credential_id: Bytes::try_from_slice(&MOCK_CREDENTIAL_ID).unwrap(),
+            credential_public_key: cose::PublicKey::P256Key(cose::P256PublicKey {
+                x: Bytes::try_from_slice(&[0x0a; 32]).unwrap(),
+                y: Bytes::try_from_slice(&[0x0b; 32]).unwrap(),
+            }),
+        };
+        // USER_PRESENT | ATTESTED
+        let auth_data = self.auth_data(params.rp.id.as_str(), 0x01 | 0x40, Some(attested_credential_data));
+
+        Ok(AttestationObject {
+            fmt: String::from("none"),
+            auth_data: auth_data.serialize(),
+            att_stmt: AttestationStatement::None(NoneAttestationStatement {}),
+        })
+    }
+
+    fn get_assertions(&mut self, params: &GetAssertionParameters) -> Result<AssertionResponses> {
+        self.check()?;
+        self.hmac_secret_input = params.extensions.as_ref()
+            .and_then(|extensions| extensions.hmac_secret.clone());
+        if !params.allow_list.is_empty() && !params.allow_list.iter().any(|descriptor| self.owns_credential(descriptor)) {
+            return Err(Error::NoCredentials);
+        }
+        if params.up() {
+            self.request_presence()?;
+        }
+
+        let flags = if params.up() { 0x01 } else { 0x00 };
+        let auth_data = self.auth_data(params.rp_id.as_str(), flags, None);
+
+        let mut responses = AssertionResponses::new();
+        responses.push(AssertionResponse {
+            credential: Some(PublicKeyCredentialDescriptor {
+                id: Bytes::try_from_slice(&MOCK_CREDENTIAL_ID).unwrap(),
+                key_type: String::from("public-key"),
+            }),
+            auth_data: auth_data.serialize(),
+            signature: Bytes::try_from_slice(&MOCK_SIGNATURE).unwrap(),
+            user: None,
+            number_of_credentials: None,
+        }).unwrap();
+        Ok(responses)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.check()?;
+        self.request_presence()?;
+        self.sign_count = 0;
+        Ok(())
+    }
+
+    fn owns_credential(&self, descriptor: &PublicKeyCredentialDescriptor) -> bool {
+        descriptor.id[..] == MOCK_CREDENTIAL_ID[..]
+    }
+
+    fn decorate_auth_data(&mut self, auth_data: &mut AuthenticatorData) {
+        if let Some(extension_outputs) = &self.extension_outputs {
+            // EXTENSION_DATA
+            auth_data.flags |= 0x80;
+            auth_data.extensions = Some(extension_outputs.clone());
+        }
+    }
+
+    fn begin_user_interaction(&mut self, operation: Operation) {
+        // only remember the first few
+        self.interactions.push((operation, self.presence_requests)).ok();
+    }
+
+    fn wink(&mut self, pattern: WinkPattern) {
+        // only remember the first few
+        self.winks.push(pattern).ok();
+    }
+
+    fn on_channel_allocated(&mut self, channel: u32) {
+        // only remember the first few
+        self.allocated_channels.push(channel).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::class::mock::{self as bus, MockBus, Packets};
+    use crate::authenticator::dispatch_msg;
+    use crate::pipe::{Command, Pipe};
+    use usb_device::bus::UsbBusAllocator;
+
+    fn get_assertion_parameters() -> GetAssertionParameters {
+        GetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: Bytes::try_from_slice(&[0xcd; 32]).unwrap(),
+            allow_list: Vec::new(),
+            extensions: None,
+            options: None,
+            pin_auth: None,
+            pin_protocol: None,
+        }
+    }
+
+    #[test]
+    fn deterministic_assertion() {
+        let mut authenticator = MockAuthenticator::default();
+        authenticator.sign_count = 0x0102_0304;
+
+        let responses = authenticator.get_assertions(&get_assertion_parameters()).unwrap();
+        let auth_data = &responses[0].auth_data;
+        assert_eq!(auth_data.len(), 37);
+        assert_eq!(&auth_data[..11], b"example.com");
+        assert!(auth_data[11..32].iter().all(|byte| *byte == 0));
+        // UP flag, then big-endian sign count
+        assert_eq!(&auth_data[32..], &[0x01, 0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(&responses[0].signature[..], &MOCK_SIGNATURE);
+        assert_eq!(authenticator.sign_count, 0x0102_0305);
+    }
+
+    #[test]
+    fn decorated_auth_data() {
+        let mut authenticator = MockAuthenticator::default();
+        // {"example": true}
+        let extension_outputs = [0xa1, 0x67, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0xf5];
+        authenticator.extension_outputs = Some(Bytes::try_from_slice(&extension_outputs).unwrap());
+
+        let responses = authenticator.get_assertions(&get_assertion_parameters()).unwrap();
+        let auth_data = &responses[0].auth_data;
+        assert_eq!(auth_data.len(), 37 + extension_outputs.len());
+        // UP and ED flags, extension outputs after the sign count
+        assert_eq!(auth_data[32], 0x01 | 0x80);
+        assert_eq!(&auth_data[37..], &extension_outputs);
+    }
+
+    #[test]
+    fn injected_errors_map_to_status_bytes() {
+        let mut authenticator = MockAuthenticator::default();
+
+        for (error, status) in [
+            (Error::InvalidCbor, 0x12u8),
+            (Error::LimitExceeded, 0x15),
+            (Error::KeyStoreFull, 0x28),
+            (Error::Other, 0x7f),
+        ].iter() {
+            authenticator.inject_error = Some(*error);
+            let result = authenticator.get_assertions(&get_assertion_parameters());
+            assert_eq!(result.err().map(|error| error as u8), Some(*status));
+        }
+
+        // injection is one-shot, and nothing was counted meanwhile
+        assert!(authenticator.get_assertions(&get_assertion_parameters()).is_ok());
+        assert_eq!(authenticator.sign_count, 1);
+    }
+
+    #[test]
+    fn failing_get_info() {
+        let mut authenticator = MockAuthenticator::default();
+        authenticator.inject_error = Some(Error::Other);
+        assert_eq!(authenticator.get_info().err(), Some(Error::Other));
+        assert_eq!(&authenticator.get_info().unwrap().aaguid[..], &MOCK_AAGUID);
+    }
+
+    #[test]
+    fn user_presence() {
+        let mut authenticator = MockAuthenticator::default();
+        assert_eq!(authenticator.keepalive_status(), KeepAliveStatus::Processing);
+
+        authenticator.presence = Presence::Declined;
+        assert_eq!(authenticator.get_assertions(&get_assertion_parameters()).err(), Some(Error::OperationDenied));
+        assert_eq!(authenticator.reset(), Err(Error::OperationDenied));
+
+        authenticator.presence = Presence::Pending;
+        assert_eq!(authenticator.keepalive_status(), KeepAliveStatus::UpNeeded);
+        assert_eq!(authenticator.get_assertions(&get_assertion_parameters()).err(), Some(Error::UserActionTimeout));
+        assert_eq!(authenticator.presence_requests, 3);
+
+        // silent assertions don't ask
+        let mut params = get_assertion_parameters();
+        params.options = Some(crate::types::AuthenticatorOptions { rk: None, up: Some(false), uv: None });
+        let responses = authenticator.get_assertions(&params).unwrap();
+        assert_eq!(responses[0].auth_data[32], 0x00);
+        assert_eq!(authenticator.presence_requests, 3);
+    }
+
+    #[test]
+    fn wink_is_recorded() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _rpc) = bus::rpc();
+        let mut pipe = bus::pipe(&allocate, rpc);
+        let channel = bus::allocate_channel(&mut pipe, &packets);
+        let mut authenticator = MockAuthenticator::default();
+
+        // the app passes on what the pipe hands out
+        for _ in 0..2 {
+            bus::receive(&mut pipe, &packets, channel, Command::Wink, &[]);
+            if let Some(pattern) = pipe.take_wink() {
+                authenticator.wink(pattern);
+            }
+        }
+        assert_eq!(pipe.take_wink(), None);
+        assert_eq!(&authenticator.winks[..], &[WinkPattern::default(); 2]);
+    }
+
+    #[test]
+    fn msg_is_dispatched() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _rpc) = bus::rpc();
+        let mut pipe = bus::pipe(&allocate, rpc);
+        pipe.set_ctap1(true);
+        let channel = bus::allocate_channel(&mut pipe, &packets);
+        let mut authenticator = MockAuthenticator::default();
+
+        let mut answered = |pipe: &mut Pipe<MockBus>, apdu: &[u8]| {
+            bus::receive(pipe, &packets, channel, Command::Msg, apdu);
+            let response = dispatch_msg(&mut authenticator, pipe.pending_msg().unwrap());
+            pipe.answer_msg(&response);
+            let written = packets.take_written();
+            assert_eq!(written.len(), 1);
+            assert_eq!(written[0][4], 0x80 | Command::Msg.into_u8());
+            let length = u16::from_be_bytes([written[0][5], written[0][6]]) as usize;
+            written[0][7..][..length].to_vec()
+        };
+
+        // U2F_VERSION, with extended length Le
+        assert_eq!(answered(&mut pipe, &[0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00]), b"U2F_V2\x90\x00");
+        // the mock doesn't implement U2F_REGISTER
+        assert_eq!(answered(&mut pipe, &[0x00, 0x01, 0x00, 0x00]), [0x6d, 0x00]);
+        assert_eq!(answered(&mut pipe, &[0x80, 0x03, 0x00, 0x00]), [0x6e, 0x00]);
+        assert_eq!(answered(&mut pipe, &[0x00, 0x03]), [0x6a, 0x80]);
+    }
+
+    #[test]
+    fn allocated_channels_are_recorded() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _rpc) = bus::rpc();
+        let mut pipe = bus::pipe(&allocate, rpc);
+        let mut authenticator = MockAuthenticator::default();
+
+        for _ in 0..2 {
+            bus::receive(&mut pipe, &packets, 0xFFFF_FFFF, Command::Init, &[0x42; 8]);
+        }
+        assert_eq!(packets.take_written().len(), 2);
+        let allocated = pipe.take_allocated_channels();
+        assert_eq!(allocated, 1..3);
+        assert!(pipe.take_allocated_channels().is_empty());
+
+        // the app passes them on
+        for channel in allocated {
+            authenticator.on_channel_allocated(channel);
+        }
+        assert_eq!(&authenticator.allocated_channels[..], &[1, 2]);
+    }
+
+    #[test]
+    fn user_interaction_is_announced_first() {
+        use crate::authenticator::{dispatch, Ctap2Request, Ctap2Response};
+
+        let mut authenticator = MockAuthenticator::default();
+        let request = Ctap2Request::GetAssertions(get_assertion_parameters());
+        match dispatch(&mut authenticator, &request) {
+            Ok(Ctap2Response::GetAssertions(_)) => {},
+            _ => panic!("expected assertions"),
+        }
+        // before the user was asked
+        assert_eq!(&authenticator.interactions[..], &[(Operation::GetAssertion, 0)]);
+        assert_eq!(authenticator.presence_requests, 1);
+
+        // announced even if the operation then fails or times out
+        authenticator.presence = Presence::Pending;
+        assert!(dispatch(&mut authenticator, &request).is_err());
+        authenticator.inject_error = Some(Error::Other);
+        assert!(dispatch(&mut authenticator, &request).is_err());
+        assert_eq!(&authenticator.interactions[1..], &[(Operation::GetAssertion, 1), (Operation::GetAssertion, 2)]);
+        assert_eq!(authenticator.presence_requests, 2);
+
+        // no user involved
+        assert!(dispatch(&mut authenticator, &Ctap2Request::GetInfo).is_ok());
+        assert_eq!(authenticator.interactions.len(), 3);
+    }
+
+    #[test]
+    fn hmac_secret_input_reaches_authenticator() {
+        use crate::types::{cbor_serialize, GetAssertionExtensions};
+
+        let hmac_secret_input = HmacSecretInput {
+            key_agreement: cose::P256PublicKey {
+                x: Bytes::try_from_slice(&[0x0a; 32]).unwrap(),
+                y: Bytes::try_from_slice(&[0x0b; 32]).unwrap(),
+            },
+            salt_enc: Bytes::try_from_slice(&[0x5a; 64]).unwrap(),
+            salt_auth: Bytes::try_from_slice(&[0xa5; 16]).unwrap(),
+        };
+        let mut params = get_assertion_parameters();
+        params.extensions = Some(GetAssertionExtensions { hmac_secret: Some(hmac_secret_input.clone()) });
+
+        // as it comes in over the wire
+        let mut buffer = [0u8; 512];
+        let size = cbor_serialize(&params, &mut buffer).unwrap();
+        let params: GetAssertionParameters = serde_cbor::de::from_mut_slice(&mut buffer[..size]).unwrap();
+
+        let mut authenticator = MockAuthenticator::default();
+        authenticator.get_assertions(&params).unwrap();
+        assert_eq!(authenticator.hmac_secret_input, Some(hmac_secret_input));
+
+        // and forgotten with the next one
+        authenticator.get_assertions(&get_assertion_parameters()).unwrap();
+        assert_eq!(authenticator.hmac_secret_input, None);
+    }
+
+    #[test]
+    fn foreign_allow_list() {
+        let mut authenticator = MockAuthenticator::default();
+        let mut params = get_assertion_parameters();
+        params.allow_list.push(PublicKeyCredentialDescriptor {
+            id: Bytes::try_from_slice(b"foreign").unwrap(),
+            key_type: String::from("public-key"),
+        }).unwrap();
+        assert_eq!(authenticator.get_assertions(&params).err(), Some(Error::NoCredentials));
+        assert_eq!(authenticator.presence_requests, 0);
+    }
+}
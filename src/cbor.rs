@@ -0,0 +1,225 @@
+/*!
+Minimal CBOR encoder, writing into a buffer of the caller's.
+
+Covers what simple responses need: unsigned and negative integers,
+byte and text strings, booleans, and definite-length arrays and maps.
+Arrays and maps are announced by their length, followed by that many
+items (respectively key-value pairs). Canonical order of map keys is up
+to the caller.
+
+```ignore
+let mut buffer = [0u8; 16];
+let mut encoder = Encoder::new(&mut buffer);
+// {1: "a", 2: h'00ff'}
+encoder.map(2)?.unsigned(1)?.text("a")?.unsigned(2)?.bytes(&[0x00, 0xff])?;
+let encoded = encoder.finish();
+```
+*/
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum Error {
+    /// the buffer can't hold the next item, nothing of it was written
+    BufferTooSmall,
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+// major types
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+pub struct Encoder<'a> {
+    buffer: &'a mut [u8],
+    written: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, written: 0 }
+    }
+
+    /// Number of bytes encoded so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// The encoded bytes.
+    pub fn finish(self) -> &'a [u8] {
+        &self.buffer[..self.written]
+    }
+
+    pub fn unsigned(&mut self, value: u64) -> Result<&mut Self> {
+        self.header(UNSIGNED, value)
+    }
+
+    pub fn int(&mut self, value: i64) -> Result<&mut Self> {
+        if value >= 0 {
+            self.header(UNSIGNED, value as u64)
+        } else {
+            // -1 - value, which can't overflow this way
+            self.header(NEGATIVE, !value as u64)
+        }
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> Result<&mut Self> {
+        self.header_and_payload(BYTES, value)
+    }
+
+    pub fn text(&mut self, value: &str) -> Result<&mut Self> {
+        self.header_and_payload(TEXT, value.as_bytes())
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<&mut Self> {
+        // simple values 20 (false) and 21 (true)
+        self.header(SIMPLE, if value { 21 } else { 20 })
+    }
+
+    /// Start an array of `len` items.
+    pub fn array(&mut self, len: usize) -> Result<&mut Self> {
+        self.header(ARRAY, len as u64)
+    }
+
+    /// Start a map of `len` key-value pairs.
+    pub fn map(&mut self, len: usize) -> Result<&mut Self> {
+        self.header(MAP, len as u64)
+    }
+
+    fn header(&mut self, major: u8, value: u64) -> Result<&mut Self> {
+        let mut header = [0u8; 9];
+        let header = encode_header(major, value, &mut header);
+        self.write(header, &[])
+    }
+
+    fn header_and_payload(&mut self, major: u8, payload: &[u8]) -> Result<&mut Self> {
+        let mut header = [0u8; 9];
+        let header = encode_header(major, payload.len() as u64, &mut header);
+        self.write(header, payload)
+    }
+
+    // all or nothing, so a failed item doesn't leave half an encoding behind
+    fn write(&mut self, header: &[u8], payload: &[u8]) -> Result<&mut Self> {
+        let size = header.len() + payload.len();
+        if self.buffer.len() - self.written < size {
+            return Err(Error::BufferTooSmall);
+        }
+        let buffer = &mut self.buffer[self.written..];
+        buffer[..header.len()].copy_from_slice(header);
+        buffer[header.len()..size].copy_from_slice(payload);
+        self.written += size;
+        Ok(self)
+    }
+}
+
+// initial byte, followed by the value if it doesn't fit in there, in big-endian
+fn encode_header(major: u8, value: u64, header: &mut [u8; 9]) -> &[u8] {
+    let major = major << 5;
+    if value < 24 {
+        header[0] = major | value as u8;
+        &header[..1]
+    } else if value <= u8::max_value() as u64 {
+        header[0] = major | 24;
+        header[1] = value as u8;
+        &header[..2]
+    } else if value <= u16::max_value() as u64 {
+        header[0] = major | 25;
+        header[1..3].copy_from_slice(&(value as u16).to_be_bytes());
+        &header[..3]
+    } else if value <= u32::max_value() as u64 {
+        header[0] = major | 26;
+        header[1..5].copy_from_slice(&(value as u32).to_be_bytes());
+        &header[..5]
+    } else {
+        header[0] = major | 27;
+        header[1..9].copy_from_slice(&value.to_be_bytes());
+        &header[..9]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ctap_types::serde::cbor_serialize;
+
+    fn encode(f: impl FnOnce(&mut Encoder) -> Result<()>) -> ([u8; 32], usize) {
+        let mut buffer = [0u8; 32];
+        let mut encoder = Encoder::new(&mut buffer);
+        f(&mut encoder).unwrap();
+        let size = encoder.written();
+        (buffer, size)
+    }
+
+    #[test]
+    fn integers_like_serde() {
+        let values: [i64; 12] = [
+            0, 23, 24, 255, 256, 65_535, 65_536, 1_000_000_000_000,
+            -1, -24, -25, i64::min_value(),
+        ];
+        for &value in values.iter() {
+            let (encoded, size) = encode(|encoder| encoder.int(value).map(drop));
+            let mut expected = [0u8; 32];
+            let expected = cbor_serialize(&value, &mut expected).unwrap();
+            assert_eq!(&encoded[..size], expected);
+        }
+
+        let (encoded, size) = encode(|encoder| encoder.unsigned(u64::max_value()).map(drop));
+        let mut expected = [0u8; 32];
+        let expected = cbor_serialize(&u64::max_value(), &mut expected).unwrap();
+        assert_eq!(&encoded[..size], expected);
+    }
+
+    #[test]
+    fn arrays_like_serde() {
+        // tuples serialize as arrays
+        let value = (-7i32, "packed", true, (1u8, 2u16));
+        let (encoded, size) = encode(|encoder| encoder
+            .array(4)?.int(-7)?.text("packed")?.bool(true)?
+            .array(2)?.unsigned(1)?.unsigned(2).map(drop)
+        );
+        let mut expected = [0u8; 32];
+        let expected = cbor_serialize(&value, &mut expected).unwrap();
+        assert_eq!(&encoded[..size], expected);
+    }
+
+    #[test]
+    fn rfc7049_examples() {
+        // h'01020304'
+        let (encoded, size) = encode(|encoder| encoder.bytes(&[1, 2, 3, 4]).map(drop));
+        assert_eq!(&encoded[..size], &[0x44, 0x01, 0x02, 0x03, 0x04]);
+
+        // "IETF"
+        let (encoded, size) = encode(|encoder| encoder.text("IETF").map(drop));
+        assert_eq!(&encoded[..size], &[0x64, 0x49, 0x45, 0x54, 0x46]);
+
+        // {1: 2, 3: 4}
+        let (encoded, size) = encode(|encoder| encoder
+            .map(2)?.unsigned(1)?.unsigned(2)?.unsigned(3)?.unsigned(4).map(drop));
+        assert_eq!(&encoded[..size], &[0xa2, 0x01, 0x02, 0x03, 0x04]);
+
+        // {"a": 1, "b": [2, 3]}
+        let (encoded, size) = encode(|encoder| encoder
+            .map(2)?.text("a")?.unsigned(1)?.text("b")?.array(2)?.unsigned(2)?.unsigned(3).map(drop));
+        assert_eq!(&encoded[..size], &[0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x82, 0x02, 0x03]);
+
+        // -1000, false
+        let (encoded, size) = encode(|encoder| encoder.int(-1000)?.bool(false).map(drop));
+        assert_eq!(&encoded[..size], &[0x39, 0x03, 0xe7, 0xf4]);
+    }
+
+    #[test]
+    fn buffer_overflow_is_an_error() {
+        let mut buffer = [0u8; 4];
+        let mut encoder = Encoder::new(&mut buffer);
+        encoder.map(1).unwrap().unsigned(1).unwrap();
+        // doesn't fit, and leaves no trace
+        assert_eq!(encoder.text("IETF").err(), Some(Error::BufferTooSmall));
+        assert_eq!(encoder.unsigned(1_000_000).err(), Some(Error::BufferTooSmall));
+        assert_eq!(encoder.written(), 2);
+        encoder.text("a").unwrap();
+        assert_eq!(encoder.finish(), &[0xa1, 0x01, 0x61, 0x61]);
+    }
+}
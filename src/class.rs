@@ -1,9 +1,12 @@
 // use core::convert::TryInto as _;
 // use core::convert::TryFrom as _;
 
+#[cfg(test)]
+pub mod mock;
+
 use crate::{
-    constants::{INTERRUPT_POLL_MILLISECONDS, PACKET_SIZE},
-    pipe::Pipe,
+    constants::{INTERRUPT_POLL_MILLISECONDS, MESSAGE_SIZE, PACKET_SIZE},
+    pipe::{Pipe, State},
 };
 
 use ctap_types::{
@@ -15,47 +18,197 @@ use usb_device::{
     class::{ControlIn, ControlOut, UsbClass},
     control,
     descriptor::{DescriptorWriter},
-    endpoint::{EndpointAddress, EndpointIn, EndpointOut},
+    endpoint::{Endpoint, EndpointAddress, EndpointDirection, EndpointIn, EndpointOut, EndpointType},
     Result as UsbResult,
 };
 
 /// Packet-level implementation of the CTAPHID protocol.
-pub struct CtapHid<'alloc, Bus: UsbBus> {
+///
+/// `N` is the message buffer size, see `Pipe`.
+pub struct CtapHid<'alloc, Bus: UsbBus, Indicator: StatusIndicator = NoIndicator, const N: usize = MESSAGE_SIZE> {
     interface: InterfaceNumber,
-    pipe: Pipe<'alloc, Bus>,
+    pipe: Pipe<'alloc, Bus, N>,
+    usage_page: UsagePage,
+    indicator: Indicator,
+}
+
+/// Hooks to drive a status LED, called as transactions progress.
+pub trait StatusIndicator {
+    /// transaction over, nothing going on
+    fn on_idle(&mut self) {}
+    /// request being received, or processed by the authenticator
+    fn on_processing(&mut self) {}
+    /// started sending an error response
+    fn on_error(&mut self) {}
+    /// started sending a successful response
+    fn on_success(&mut self) {}
+}
+
+/// The default `StatusIndicator`, which does nothing.
+pub struct NoIndicator;
+
+impl StatusIndicator for NoIndicator {}
+
+// transaction progress, as far as an indicator is concerned
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+enum Phase {
+    Idle,
+    Processing,
+    Responding,
+}
+
+impl Phase {
+    fn of(state: &State) -> Self {
+        match state {
+            State::Idle => Phase::Idle,
+            State::Receiving(_) | State::WaitingOnAuthenticator(_) => Phase::Processing,
+            State::WaitingToSend(_) | State::Sending(_) => Phase::Responding,
+        }
+    }
+}
+
+fn indicate<Indicator: StatusIndicator>(indicator: &mut Indicator, before: Phase, after: Phase, error: bool) {
+    if before == after {
+        return;
+    }
+    match after {
+        Phase::Idle => indicator.on_idle(),
+        Phase::Processing => indicator.on_processing(),
+        Phase::Responding if error => indicator.on_error(),
+        Phase::Responding => indicator.on_success(),
+    }
+}
+
+/// HID usage page advertised in the report descriptor.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum UsagePage {
+    /// 0xF1D0, which is what FIDO clients look for
+    Fido,
+    /// 0xFF00, a generic vendor defined page.
+    ///
+    /// **Not conformant**, FIDO clients won't find the device. Only meant
+    /// for exercising the transport on hosts that restrict FIDO devices.
+    Vendor,
+}
+
+impl UsagePage {
+    pub fn code(self) -> u16 {
+        match self {
+            UsagePage::Fido => 0xF1D0,
+            UsagePage::Vendor => 0xFF00,
+        }
+    }
+}
+
+impl Default for UsagePage {
+    fn default() -> Self {
+        UsagePage::Fido
+    }
 }
 
 impl<'alloc, Bus> CtapHid<'alloc, Bus>
 where
 	Bus: UsbBus
 {
+	/// Panics if the endpoints can't be allocated, see `try_new`.
+	///
+	/// Manufacturer and product strings belong to the device, not to this
+	/// interface: set them via `UsbDeviceBuilder::manufacturer` and `::product`.
 	pub fn new(allocate: &'alloc UsbBusAllocator<Bus>, rpc: TransportEndpoint)
         -> Self
     {
-        // 64 bytes, interrupt endpoint polled every 5 milliseconds
-        let read_endpoint: EndpointOut<'alloc, Bus> =
-            allocate.interrupt(PACKET_SIZE as u16, INTERRUPT_POLL_MILLISECONDS);
-        // 64 bytes, interrupt endpoint polled every 5 milliseconds
-        let write_endpoint: EndpointIn<'alloc, Bus> =
-            allocate.interrupt(PACKET_SIZE as u16, INTERRUPT_POLL_MILLISECONDS);
+        Self::try_new(allocate, rpc).expect("CTAPHID endpoint allocation failed")
+	}
 
+	/// Fails if the bus can't provide two 64 byte interrupt endpoints,
+	/// which FIDO requires: the whole protocol is framed in 64 byte packets.
+	pub fn try_new(allocate: &'alloc UsbBusAllocator<Bus>, rpc: TransportEndpoint)
+        -> UsbResult<Self>
+    {
+        Self::try_with_message_size(allocate, rpc)
+	}
+}
+
+impl<'alloc, Bus, const N: usize> CtapHid<'alloc, Bus, NoIndicator, N>
+where
+	Bus: UsbBus
+{
+	/// Like `try_new`, with a message buffer of `N` bytes instead of
+	/// `MESSAGE_SIZE`, e.g. `CtapHid::<_, _, _, 1200>::try_with_message_size`.
+	///
+	/// Panics unless `N` is between `PACKET_SIZE` and `MESSAGE_SIZE`.
+	pub fn try_with_message_size(allocate: &'alloc UsbBusAllocator<Bus>, rpc: TransportEndpoint)
+        -> UsbResult<Self>
+    {
+        let (read_endpoint, write_endpoint) = allocate_endpoints(allocate)?;
         let pipe = Pipe::new(read_endpoint, write_endpoint, rpc);
 
-        Self {
+        Ok(Self {
             interface: allocate.interface(),
             pipe,
-        }
+            usage_page: UsagePage::default(),
+            indicator: NoIndicator,
+        })
 	}
+}
+
+impl<'alloc, Bus, Indicator, const N: usize> CtapHid<'alloc, Bus, Indicator, N>
+where
+	Bus: UsbBus,
+	Indicator: StatusIndicator,
+{
+    pub fn with_indicator<Other: StatusIndicator>(self, indicator: Other) -> CtapHid<'alloc, Bus, Other, N> {
+        CtapHid {
+            interface: self.interface,
+            pipe: self.pipe,
+            usage_page: self.usage_page,
+            indicator,
+        }
+    }
+
+    /// Hosts read the report descriptor during enumeration,
+    /// so this must be set before the device is enumerated.
+    pub fn set_usage_page(&mut self, usage_page: UsagePage) {
+        self.usage_page = usage_page;
+    }
 
     // pub fn borrow_mut_authenticator(&mut self) -> &mut Authenticator {
     //     self.pipe.borrow_mut_authenticator()
     // }
 
     // implement DerefMut<Target = Pipe> instead
-    pub fn pipe(&mut self) -> &mut Pipe<'alloc, Bus> {
+    pub fn pipe(&mut self) -> &mut Pipe<'alloc, Bus, N> {
         &mut self.pipe
     }
 
+    pub fn indicator(&mut self) -> &mut Indicator {
+        &mut self.indicator
+    }
+
+    // run a pipe step, telling the indicator about any progress
+    fn track(&mut self, step: impl FnOnce(&mut Pipe<'alloc, Bus, N>)) {
+        let before = Phase::of(&self.pipe.state);
+        step(&mut self.pipe);
+        let after = Phase::of(&self.pipe.state);
+        indicate(&mut self.indicator, before, after, self.pipe.responding_with_error());
+    }
+
+}
+
+// the allocator hands out endpoints of exactly the requested size or fails
+// (e.g. with `EndpointMemoryOverflow`), so we never get smaller ones,
+// which would break packet framing
+fn allocate_endpoints<Bus: UsbBus>(allocate: &UsbBusAllocator<Bus>)
+    -> UsbResult<(EndpointOut<'_, Bus>, EndpointIn<'_, Bus>)>
+{
+    // 64 bytes, interrupt endpoint polled every 5 milliseconds
+    let read_endpoint: EndpointOut<'_, Bus> = allocate.alloc(
+        None, EndpointType::Interrupt, PACKET_SIZE as u16, INTERRUPT_POLL_MILLISECONDS)?;
+    // 64 bytes, interrupt endpoint polled every 5 milliseconds
+    let write_endpoint: EndpointIn<'_, Bus> = allocate.alloc(
+        None, EndpointType::Interrupt, PACKET_SIZE as u16, INTERRUPT_POLL_MILLISECONDS)?;
+
+    Ok((read_endpoint, write_endpoint))
 }
 
 const HID_INTERFACE_CLASS: u8 = 0x03;
@@ -70,6 +223,11 @@ const INTERFACE_PROTOCOL_NONE: u8 = 0x0;
 const HID_DESCRIPTOR: u8 = 0x21;
 const HID_REPORT_DESCRIPTOR: u8 = 0x22;
 
+// usages from the CTAP spec, section 11.2.8.1
+const FIDO_USAGE_CTAPHID: u8 = 0x01;
+const FIDO_USAGE_DATA_IN: u8 = 0x20;
+const FIDO_USAGE_DATA_OUT: u8 = 0x21;
+
 // cf. https://git.io/Jebh8
 // integers are little-endian
 const FIDO_HID_REPORT_DESCRIPTOR_LENGTH: usize = 34;
@@ -77,31 +235,93 @@ const FIDO_HID_REPORT_DESCRIPTOR: [u8; FIDO_HID_REPORT_DESCRIPTOR_LENGTH] = [
     // Usage page (vendor defined): 0xF1D0 (FIDO_USAGE_PAGE)
     0x06, 0xD0, 0xF1,
     // Usage ID (vendor defined): 0x1 (FIDO_USAGE_CTAPHID)
-    0x09, 0x01,
+    0x09, FIDO_USAGE_CTAPHID,
 
     // Collection (application)
     0xA1, 0x01,
 
         // The Input report
-        0x09, 0x03,        // Usage ID - vendor defined: FIDO_USAGE_DATA_IN
+        0x09, FIDO_USAGE_DATA_IN, // Usage ID - vendor defined: FIDO_USAGE_DATA_IN
         0x15, 0x00,        // Logical Minimum (0)
         0x26, 0xFF, 0x00,  // Logical Maximum (255)
         0x75, 0x08,        // Report Size (8 bits)
         0x95, PACKET_SIZE as u8, // Report Count (64 fields)
-        0x81, 0x08,        // Input (Data, Variable, Absolute)
+        0x81, 0x02,        // Input (Data, Variable, Absolute)
 
         // The Output report
-        0x09, 0x04,        // Usage ID - vendor defined: FIDO_USAGE_DATA_OUT
+        0x09, FIDO_USAGE_DATA_OUT, // Usage ID - vendor defined: FIDO_USAGE_DATA_OUT
         0x15, 0x00,        // Logical Minimum (0)
         0x26, 0xFF, 0x00,  // Logical Maximum (255)
         0x75, 0x08,        // Report Size (8 bits)
         0x95, PACKET_SIZE as u8, // Report Count (64 fields)
-        0x91, 0x08,        // Output (Data, Variable, Absolute)
+        0x91, 0x02,        // Output (Data, Variable, Absolute)
 
     // EndCollection
     0xC0,
 ];
 
+fn report_descriptor(usage_page: UsagePage) -> [u8; FIDO_HID_REPORT_DESCRIPTOR_LENGTH] {
+    let mut descriptor = FIDO_HID_REPORT_DESCRIPTOR;
+    // the Usage Page item's data
+    descriptor[1..3].copy_from_slice(&usage_page.code().to_le_bytes());
+    descriptor
+}
+
+// the parts of `DescriptorWriter` we use, so tests can see what we write
+trait ConfigurationWriter {
+    fn interface(&mut self, number: InterfaceNumber, class: u8, subclass: u8, protocol: u8) -> UsbResult<()>;
+    fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> UsbResult<()>;
+    fn endpoint<Bus: UsbBus, Direction: EndpointDirection>(&mut self, endpoint: &Endpoint<'_, Bus, Direction>)
+        -> UsbResult<()>;
+}
+
+impl ConfigurationWriter for DescriptorWriter<'_> {
+    fn interface(&mut self, number: InterfaceNumber, class: u8, subclass: u8, protocol: u8) -> UsbResult<()> {
+        DescriptorWriter::interface(self, number, class, subclass, protocol)
+    }
+
+    fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> UsbResult<()> {
+        DescriptorWriter::write(self, descriptor_type, descriptor)
+    }
+
+    fn endpoint<Bus: UsbBus, Direction: EndpointDirection>(&mut self, endpoint: &Endpoint<'_, Bus, Direction>)
+        -> UsbResult<()>
+    {
+        DescriptorWriter::endpoint(self, endpoint)
+    }
+}
+
+// what the CTAPHID interface contributes to the configuration descriptor,
+// e.g. as part of a composite device
+fn write_configuration<Bus: UsbBus>(
+    writer: &mut impl ConfigurationWriter,
+    interface: InterfaceNumber,
+    read_endpoint: &EndpointOut<'_, Bus>,
+    write_endpoint: &EndpointIn<'_, Bus>,
+) -> UsbResult<()> {
+
+    writer.interface(
+        interface,
+        HID_INTERFACE_CLASS,
+        INTERFACE_SUBCLASS_NONE,
+        INTERFACE_PROTOCOL_NONE,
+    )?;
+
+    // little-endian integers
+    writer.write(HID_DESCRIPTOR, &[
+        0x11, 0x01, // bcdHID (le)
+        0x00, // country code: universal
+        0x01, // number of HID report descriptors
+        HID_REPORT_DESCRIPTOR, // 1st HID report descriptor type
+        FIDO_HID_REPORT_DESCRIPTOR_LENGTH as u8, 0x00, // 1st HID report descriptor length in bytes (le)
+    ])?;
+
+    writer.endpoint(read_endpoint)?;
+    writer.endpoint(write_endpoint)?;
+
+    Ok(())
+}
+
 // see hid1_11.pdf, section 7.2, p. 50
 #[derive(Copy,Clone,Eq,Debug,PartialEq)]
 pub enum ClassRequests {
@@ -117,54 +337,43 @@ pub enum ClassRequests {
     SetProtocol = 0xB,
 }
 
-impl<'alloc, Bus> UsbClass<Bus> for CtapHid<'alloc, Bus>
-where Bus: UsbBus
+impl<'alloc, Bus, Indicator, const N: usize> UsbClass<Bus> for CtapHid<'alloc, Bus, Indicator, N>
+where
+    Bus: UsbBus,
+    Indicator: StatusIndicator,
 {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        write_configuration(writer, self.interface, self.pipe.read_endpoint(), self.pipe.write_endpoint())
+    }
 
-        writer.interface(
-            self.interface,
-            HID_INTERFACE_CLASS,
-            INTERFACE_SUBCLASS_NONE,
-            INTERFACE_PROTOCOL_NONE,
-        )?;
-
-        // little-endian integers
-        writer.write(HID_DESCRIPTOR, &[
-            0x11, 0x01, // bcdHID (le)
-            0x00, // country code: universal
-            0x01, // number of HID report descriptors
-            HID_REPORT_DESCRIPTOR, // 1st HID report descriptor type
-            FIDO_HID_REPORT_DESCRIPTOR_LENGTH as u8, 0x00, // 1st HID report descriptor length in bytes as u16-be
-        ])?;
-
-        writer.endpoint(&self.pipe.read_endpoint())?;
-        writer.endpoint(&self.pipe.write_endpoint())?;
-
-        Ok(())
+    // bus reset: whatever the host was in the middle of, it won't finish
+    fn reset(&mut self) {
+        self.track(|pipe| pipe.reset());
     }
 
     fn poll(&mut self) {
-        if self.pipe.rpc.recv.ready() {
-            // hprintln!("recv pipe ready").ok();
+        // this gets called a lot, mostly with nothing to do:
+        // incoming packets are handled in `endpoint_out`, so if we're
+        // idle there is neither a response to collect nor one to send.
+        if let State::Idle = self.pipe.state {
+            return;
         }
-        // hprintln!("state = {:?}", self.pipe.state).ok();
-        self.pipe.handle_response();
-        self.pipe.maybe_write_packet();
+        self.track(|pipe| pipe.handle_response());
+        self.track(|pipe| pipe.flush());
     }
 
     // called when endpoint with given address received a packet
     // TODO: should misbehaving clients be blacklisted?
     fn endpoint_out(&mut self, addr: EndpointAddress) {
         if addr == self.pipe.read_address() {
-            self.pipe.read_and_handle_packet();
+            self.track(|pipe| pipe.read_and_handle_packet());
         }
     }
 
     // called when endpoint with given address sent a packet
     fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
         if addr == self.pipe.write_address() {
-            self.pipe.maybe_write_packet();
+            self.track(|pipe| pipe.flush());
         }
     }
 
@@ -204,10 +413,11 @@ where Bus: UsbBus
                 // wIndex: 0x0,
                 // wLength: 0x22, (34 bytes)
                 control::Request::GET_DESCRIPTOR => {
+                    let descriptor = report_descriptor(self.usage_page);
                     xfer.accept(|data| {
                         assert!(data.len() >= FIDO_HID_REPORT_DESCRIPTOR_LENGTH);
                         data[..FIDO_HID_REPORT_DESCRIPTOR_LENGTH]
-                            .copy_from_slice(&FIDO_HID_REPORT_DESCRIPTOR);
+                            .copy_from_slice(&descriptor);
                         Ok(FIDO_HID_REPORT_DESCRIPTOR_LENGTH)
                     }).ok();
                 },
@@ -218,9 +428,294 @@ where Bus: UsbBus
 
 }
 
-impl<'alloc, Bus: UsbBus> CtapHid<'alloc, Bus> {
+impl<'alloc, Bus: UsbBus, Indicator: StatusIndicator, const N: usize> CtapHid<'alloc, Bus, Indicator, N> {
     pub fn check_for_responses(&mut self) {
         self.poll();
     }
+
+    /// Pass in monotonic milliseconds, see `Pipe::tick`.
+    pub fn tick(&mut self, now_ms: u32) {
+        self.track(|pipe| pipe.tick(now_ms));
+    }
+
+    /// To be called by the app when the bus is suspended, e.g. once
+    /// `UsbDevice::state` is `Suspend`. Classes aren't told about this,
+    /// unlike bus resets, which `UsbClass::reset` handles. See `Pipe::reset`.
+    pub fn suspend(&mut self) {
+        self.track(|pipe| pipe.reset());
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::{Command, MessagePackets};
+    use mock::{MockBus, Packets};
+
+    // the host sends a message, the bus tells the class packet by packet
+    fn receive<I: StatusIndicator>(ctaphid: &mut CtapHid<MockBus, I>, packets: &Packets, channel: u32, command: Command, message: &[u8]) {
+        for packet in MessagePackets::new(channel, command, message) {
+            packets.send(&packet);
+            let read_address = ctaphid.pipe().read_address();
+            ctaphid.endpoint_out(read_address);
+        }
+    }
+
+    // CTAPHID_INIT on the broadcast channel, returns the channel assigned
+    fn allocate_channel<I: StatusIndicator>(ctaphid: &mut CtapHid<MockBus, I>, packets: &Packets) -> u32 {
+        receive(ctaphid, packets, 0xFFFF_FFFF, Command::Init, &[0x42; 8]);
+        let written = packets.take_written();
+        assert_eq!(written.len(), 1);
+        u32::from_be_bytes([written[0][15], written[0][16], written[0][17], written[0][18]])
+    }
+
+    #[test]
+    fn idle_poll_writes_nothing() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut ctaphid = CtapHid::try_new(&allocate, rpc).unwrap();
+        mock::enable(&allocate);
+
+        ctaphid.poll();
+        assert!(packets.take_written().is_empty());
+
+        // nor once a transaction is over
+        let channel = allocate_channel(&mut ctaphid, &packets);
+        receive(&mut ctaphid, &packets, channel, Command::Ping, &[0x11; 8]);
+        assert_eq!(packets.take_written().len(), 1);
+        for _ in 0..3 {
+            ctaphid.poll();
+        }
+        assert!(packets.take_written().is_empty());
+        assert_eq!(ctaphid.pipe().state, State::Idle);
+    }
+
+    #[test]
+    fn flush_writes_until_the_endpoint_blocks() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut ctaphid = CtapHid::try_new(&allocate, rpc).unwrap();
+        mock::enable(&allocate);
+        let channel = allocate_channel(&mut ctaphid, &packets);
+        let write_address = ctaphid.pipe().write_address();
+
+        // a four packet echo, the endpoint takes two
+        let mut message = [0u8; 200];
+        for (i, byte) in message.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let echo = || MessagePackets::new(channel, Command::Ping, &message);
+        packets.limit_writes(Some(2));
+        receive(&mut ctaphid, &packets, channel, Command::Ping, &message);
+        ctaphid.endpoint_in_complete(write_address);
+        let written = packets.take_written();
+        assert_eq!(written.len(), 2);
+        for (written, expected) in written.iter().zip(echo()) {
+            assert_eq!(written[..], expected[..]);
+        }
+
+        // once it would no longer block, one flush sends the rest
+        packets.limit_writes(None);
+        ctaphid.endpoint_in_complete(write_address);
+        let written = packets.take_written();
+        assert_eq!(written.len(), 2);
+        for (written, expected) in written.iter().zip(echo().skip(2)) {
+            assert_eq!(written[..], expected[..]);
+        }
+        assert_eq!(ctaphid.pipe().state, State::Idle);
+
+        // a short write abandons the response
+        packets.short_write(10);
+        receive(&mut ctaphid, &packets, channel, Command::Ping, &message);
+        assert_eq!(ctaphid.pipe().state, State::Idle);
+        ctaphid.endpoint_in_complete(write_address);
+        ctaphid.poll();
+        let written = packets.take_written();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0][..], echo().next().unwrap()[..10]);
+    }
+
+    #[test]
+    fn endpoints_must_hold_full_packets() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (read_endpoint, write_endpoint) = allocate_endpoints(&allocate).unwrap();
+        assert_eq!(read_endpoint.max_packet_size(), 64);
+        assert_eq!(write_endpoint.max_packet_size(), 64);
+
+        // the bus refuses, and construction passes that on
+        let allocate = UsbBusAllocator::new(MockBus::new(32, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let ctaphid = CtapHid::try_new(&allocate, rpc);
+        assert_eq!(ctaphid.err(), Some(usb_device::UsbError::EndpointMemoryOverflow));
+    }
+
+    #[test]
+    fn vendor_usage_page_in_report_descriptor() {
+        assert_eq!(report_descriptor(UsagePage::default()), FIDO_HID_REPORT_DESCRIPTOR);
+
+        let descriptor = report_descriptor(UsagePage::Vendor);
+        // Usage Page (vendor defined): 0xFF00
+        assert_eq!(&descriptor[..3], &[0x06, 0x00, 0xFF]);
+        assert_eq!(&descriptor[3..], &FIDO_HID_REPORT_DESCRIPTOR[3..]);
+    }
+
+    #[test]
+    fn fido_report_descriptor() {
+        let descriptor = report_descriptor(UsagePage::Fido);
+        // Usage Page (FIDO alliance), Usage (CTAPHID), Collection (application)
+        assert_eq!(&descriptor[..7], &[0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01]);
+        assert_eq!(u16::from_le_bytes([descriptor[1], descriptor[2]]), UsagePage::Fido.code());
+
+        // 64 byte input report on usage 0x20, output report on usage 0x21
+        let (input, output) = descriptor[7..FIDO_HID_REPORT_DESCRIPTOR_LENGTH - 1].split_at(13);
+        assert_eq!(&input[..2], &[0x09, FIDO_USAGE_DATA_IN]);
+        assert_eq!(&input[9..], &[0x95, 64, 0x81, 0x02]);
+        assert_eq!(&output[..2], &[0x09, FIDO_USAGE_DATA_OUT]);
+        assert_eq!(&output[9..], &[0x95, 64, 0x91, 0x02]);
+
+        // End Collection
+        assert_eq!(descriptor[FIDO_HID_REPORT_DESCRIPTOR_LENGTH - 1], 0xC0);
+    }
+
+    #[derive(Default)]
+    struct RecordingIndicator {
+        events: [&'static str; 8],
+        count: usize,
+    }
+
+    impl RecordingIndicator {
+        fn record(&mut self, event: &'static str) {
+            self.events[self.count] = event;
+            self.count += 1;
+        }
+
+        fn events(&self) -> &[&'static str] {
+            &self.events[..self.count]
+        }
+    }
+
+    impl StatusIndicator for RecordingIndicator {
+        fn on_idle(&mut self) { self.record("idle") }
+        fn on_processing(&mut self) { self.record("processing") }
+        fn on_error(&mut self) { self.record("error") }
+        fn on_success(&mut self) { self.record("success") }
+    }
+
+    #[test]
+    fn indicator_follows_transactions() {
+        use ctap_types::authenticator::{ctap2, Error, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut ctaphid = CtapHid::try_new(&allocate, rpc).unwrap()
+            .with_indicator(RecordingIndicator::default());
+        mock::enable(&allocate);
+        let channel = allocate_channel(&mut ctaphid, &packets);
+        // INIT is answered within the packet's handling
+        assert_eq!(ctaphid.indicator().events(), &[] as &[&str]);
+
+        // get-info, the response waits for the endpoint
+        receive(&mut ctaphid, &packets, channel, Command::Cbor, &[0x04]);
+        assert!(authenticator.request().is_some());
+        assert_eq!(ctaphid.indicator().events(), &["processing"]);
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+        packets.limit_writes(Some(0));
+        ctaphid.poll();
+        assert_eq!(ctaphid.indicator().events(), &["processing", "success"]);
+        packets.limit_writes(None);
+        ctaphid.poll();
+        assert_eq!(packets.take_written().len(), 1);
+        assert_eq!(ctaphid.indicator().events(), &["processing", "success", "idle"]);
+
+        // likewise when the authenticator fails
+        receive(&mut ctaphid, &packets, channel, Command::Cbor, &[0x04]);
+        assert!(authenticator.request().is_some());
+        authenticator.respond(Err(Error::Other));
+        packets.limit_writes(Some(0));
+        ctaphid.poll();
+        packets.limit_writes(None);
+        ctaphid.poll();
+        assert_eq!(packets.take_written()[0][7], Error::Other as u8);
+        assert_eq!(&ctaphid.indicator().events()[3..], &["processing", "error", "idle"]);
+    }
+
+    // lays out descriptors like `DescriptorWriter`, without the configuration header
+    struct BytesWriter {
+        bytes: [u8; 64],
+        position: usize,
+    }
+
+    impl BytesWriter {
+        fn descriptor(&mut self, descriptor_type: u8, descriptor: &[u8]) {
+            self.bytes[self.position] = descriptor.len() as u8 + 2;
+            self.bytes[self.position + 1] = descriptor_type;
+            self.bytes[self.position + 2..][..descriptor.len()].copy_from_slice(descriptor);
+            self.position += descriptor.len() + 2;
+        }
+    }
+
+    impl ConfigurationWriter for BytesWriter {
+        fn interface(&mut self, number: InterfaceNumber, class: u8, subclass: u8, protocol: u8) -> UsbResult<()> {
+            // no alternate setting, endpoints counted below, no string
+            self.descriptor(0x04, &[u8::from(number), 0, 0, class, subclass, protocol, 0]);
+            Ok(())
+        }
+
+        fn write(&mut self, descriptor_type: u8, descriptor: &[u8]) -> UsbResult<()> {
+            self.descriptor(descriptor_type, descriptor);
+            Ok(())
+        }
+
+        fn endpoint<Bus: UsbBus, Direction: EndpointDirection>(&mut self, endpoint: &Endpoint<'_, Bus, Direction>)
+            -> UsbResult<()>
+        {
+            let max_packet_size = endpoint.max_packet_size().to_le_bytes();
+            self.descriptor(0x05, &[
+                endpoint.address().into(),
+                endpoint.ep_type() as u8,
+                max_packet_size[0], max_packet_size[1],
+                endpoint.interval(),
+            ]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn configuration_descriptors() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let interface = allocate.interface();
+        let (read_endpoint, write_endpoint) = allocate_endpoints(&allocate).unwrap();
+
+        let mut writer = BytesWriter { bytes: [0u8; 64], position: 0 };
+        write_configuration(&mut writer, interface, &read_endpoint, &write_endpoint).unwrap();
+        let bytes = &writer.bytes[..writer.position];
+        assert_eq!(bytes.len(), 9 + 9 + 7 + 7);
+
+        // interface: HID, no subclass or protocol
+        let (interface_descriptor, bytes) = bytes.split_at(9);
+        assert_eq!(interface_descriptor[1], 0x04);
+        assert_eq!(&interface_descriptor[5..8], &[HID_INTERFACE_CLASS, 0, 0]);
+
+        // HID: one report descriptor, of the right length
+        let (hid_descriptor, bytes) = bytes.split_at(9);
+        assert_eq!(&hid_descriptor[..2], &[9, HID_DESCRIPTOR]);
+        assert_eq!(&hid_descriptor[2..4], &[0x11, 0x01]);
+        assert_eq!(hid_descriptor[5], 1);
+        assert_eq!(hid_descriptor[6], HID_REPORT_DESCRIPTOR);
+        let report_descriptor_length = u16::from_le_bytes([hid_descriptor[7], hid_descriptor[8]]);
+        assert_eq!(report_descriptor_length as usize, report_descriptor(UsagePage::Fido).len());
+
+        // OUT then IN, both 64 byte interrupt endpoints
+        for (endpoint_descriptor, direction) in bytes.chunks(7).zip([0x00u8, 0x80].iter()) {
+            assert_eq!(&endpoint_descriptor[..2], &[7, 0x05]);
+            assert_eq!(endpoint_descriptor[2] & 0x80, *direction);
+            assert_eq!(endpoint_descriptor[3], EndpointType::Interrupt as u8);
+            assert_eq!(&endpoint_descriptor[4..6], &[64, 0]);
+            assert_eq!(endpoint_descriptor[6], INTERRUPT_POLL_MILLISECONDS);
+        }
+    }
+}
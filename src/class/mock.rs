@@ -0,0 +1,190 @@
+//! USB bus for tests that drive `Pipe` and `CtapHid` with actual packets.
+//!
+//! The test plays the host via `Packets`, which the bus reads incoming
+//! packets from and records written ones in. The authenticator behind
+//! the RPC queues is played by the test as well, see `rpc`.
+
+extern crate std;
+
+use std::{boxed::Box, collections::VecDeque, sync::Mutex, vec::Vec};
+
+use ctap_types::{
+    authenticator::{Error as AuthenticatorError, Request, Response},
+    rpc::TransportEndpoint,
+};
+use heapless::spsc::Queue;
+use usb_device::{
+    bus::{PollResult, UsbBus, UsbBusAllocator},
+    device::{UsbDeviceBuilder, UsbVidPid},
+    endpoint::{EndpointAddress, EndpointType},
+    Result as UsbResult,
+    UsbDirection,
+    UsbError,
+};
+
+use crate::pipe::{Command, MessagePackets, Pipe};
+
+#[derive(Default)]
+struct Transfers {
+    // from the host, read one by one
+    incoming: VecDeque<Vec<u8>>,
+    // to the host, as far as the endpoint took them
+    written: Vec<Vec<u8>>,
+    // writes the endpoint takes before it is busy, `None` for any number
+    write_budget: Option<usize>,
+    // the next write only takes this many bytes
+    short_write: Option<usize>,
+}
+
+/// Packets in both directions, shared by the test and the bus.
+#[derive(Default)]
+pub struct Packets(Mutex<Transfers>);
+
+impl Packets {
+    /// the host sends a packet, for the next read
+    pub fn send(&self, packet: &[u8]) {
+        self.0.lock().unwrap().incoming.push_back(packet.to_vec());
+    }
+
+    /// packets written since the last call, oldest first
+    pub fn take_written(&self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut self.0.lock().unwrap().written)
+    }
+
+    /// the write endpoint takes this many more packets, then would block
+    pub fn limit_writes(&self, budget: Option<usize>) {
+        self.0.lock().unwrap().write_budget = budget;
+    }
+
+    /// the next write only takes the first `size` bytes of the packet
+    pub fn short_write(&self, size: usize) {
+        self.0.lock().unwrap().short_write = Some(size);
+    }
+}
+
+/// Bus whose endpoints can't take more than `max_packet_size` bytes.
+pub struct MockBus<'a> {
+    max_packet_size: u16,
+    next_index: u8,
+    packets: &'a Packets,
+}
+
+impl<'a> MockBus<'a> {
+    pub fn new(max_packet_size: u16, packets: &'a Packets) -> Self {
+        Self { max_packet_size, next_index: 0, packets }
+    }
+}
+
+impl UsbBus for MockBus<'_> {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> UsbResult<EndpointAddress> {
+        if max_packet_size > self.max_packet_size {
+            return Err(UsbError::EndpointMemoryOverflow);
+        }
+        // the control endpoints ask for address 0
+        if let Some(ep_addr) = ep_addr {
+            return Ok(ep_addr);
+        }
+        self.next_index += 1;
+        Ok(EndpointAddress::from_parts(self.next_index as usize, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+    fn reset(&self) {}
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> UsbResult<usize> {
+        let mut transfers = self.packets.0.lock().unwrap();
+        match transfers.write_budget {
+            Some(0) => return Err(UsbError::WouldBlock),
+            Some(ref mut budget) => *budget -= 1,
+            None => {},
+        }
+        let size = transfers.short_write.take().unwrap_or(buf.len()).min(buf.len());
+        transfers.written.push(buf[..size].to_vec());
+        Ok(size)
+    }
+
+    fn read(&self, _ep_addr: EndpointAddress, buf: &mut [u8]) -> UsbResult<usize> {
+        match self.packets.0.lock().unwrap().incoming.pop_front() {
+            Some(packet) => {
+                buf[..packet.len()].copy_from_slice(&packet);
+                Ok(packet.len())
+            },
+            None => Err(UsbError::WouldBlock),
+        }
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool { false }
+    fn suspend(&self) {}
+    fn resume(&self) {}
+    fn poll(&self) -> PollResult { PollResult::None }
+}
+
+/// Endpoints only transfer once the device is built, as on hardware,
+/// so this goes after allocating them.
+pub fn enable(allocate: &UsbBusAllocator<MockBus<'_>>) {
+    let _device = UsbDeviceBuilder::new(allocate, UsbVidPid(0x1209, 0x0001)).build();
+}
+
+/// A pipe on the endpoints `CtapHid` would allocate, ready for transfers.
+pub fn pipe<'alloc, 'a>(allocate: &'alloc UsbBusAllocator<MockBus<'a>>, rpc: TransportEndpoint)
+    -> Pipe<'alloc, MockBus<'a>>
+{
+    let (read_endpoint, write_endpoint) = super::allocate_endpoints(allocate).unwrap();
+    enable(allocate);
+    Pipe::new(read_endpoint, write_endpoint, rpc)
+}
+
+/// the host sends a message to the pipe, packet by packet
+pub fn receive(pipe: &mut Pipe<MockBus>, packets: &Packets, channel: u32, command: Command, message: &[u8]) {
+    for packet in MessagePackets::new(channel, command, message) {
+        packets.send(&packet);
+        pipe.read_and_handle_packet();
+    }
+}
+
+/// CTAPHID_INIT on the broadcast channel, returns the channel assigned
+pub fn allocate_channel(pipe: &mut Pipe<MockBus>, packets: &Packets) -> u32 {
+    receive(pipe, packets, 0xFFFF_FFFF, Command::Init, &[0x42; 8]);
+    let written = packets.take_written();
+    assert_eq!(written.len(), 1);
+    u32::from_be_bytes([written[0][15], written[0][16], written[0][17], written[0][18]])
+}
+
+/// The authenticator's ends of the RPC queues.
+pub struct MockRpc {
+    requests: Box<dyn FnMut() -> Option<Request>>,
+    responses: Box<dyn FnMut(Result<Response, AuthenticatorError>) -> bool>,
+}
+
+impl MockRpc {
+    /// the request passed on by the pipe, if any
+    pub fn request(&mut self) -> Option<Request> {
+        (self.requests)()
+    }
+
+    /// answer a request, late or not
+    pub fn respond(&mut self, response: Result<Response, AuthenticatorError>) {
+        assert!((self.responses)(response), "response queue full");
+    }
+}
+
+/// RPC queues between a pipe and the test, which plays the authenticator.
+/// They're leaked, as the pipe wants them for `'static`.
+pub fn rpc() -> (TransportEndpoint, MockRpc) {
+    let (send, mut requests) = Box::leak(Box::new(Queue::new())).split();
+    let (mut responses, recv) = Box::leak(Box::new(Queue::new())).split();
+    let authenticator = MockRpc {
+        requests: Box::new(move || requests.dequeue()),
+        responses: Box::new(move |response| responses.enqueue(response).is_ok()),
+    };
+    (TransportEndpoint { send, recv }, authenticator)
+}
@@ -1,6 +1,47 @@
+#[cfg(feature = "legacy-api")]
+use heapless::consts;
+
 pub const INTERRUPT_POLL_MILLISECONDS: u8 = 5;
 
+// give up on an unresponsive authenticator after this long,
+// user presence checks typically time out after 30 seconds
+pub const OPERATION_TIMEOUT_MILLISECONDS: u32 = 30_000;
+
+// hosts give up on silent authenticators, so while one works we send
+// CTAPHID_KEEPALIVE this often (the spec suggests every 100ms)
+pub const KEEPALIVE_INTERVAL_MILLISECONDS: u32 = 100;
+
+// a message stalled in between its packets this long is abandoned (spec: 500ms)
+pub const MESSAGE_TIMEOUT_MILLISECONDS: u32 = 500;
+
+// authenticatorReset is only allowed this soon after power-up
+pub const RESET_WINDOW_MILLISECONDS: u32 = 10_000;
+
+// a vendor stream (e.g., firmware update) with no message for this long is abandoned
+pub const VENDOR_STREAM_TIMEOUT_MILLISECONDS: u32 = 2_000;
+
+// transactions remembered with the `transaction-history` feature
+pub const TRANSACTION_HISTORY_LENGTH: usize = 8;
+
 pub const PACKET_SIZE: usize = 64;
 
 // 7609 bytes
 pub const MESSAGE_SIZE: usize = PACKET_SIZE - 7 + 128 * (PACKET_SIZE - 5);
+
+// aaguid, credential ID (length and up to 128 bytes), COSE key (up to 128 bytes)
+pub const ATTESTED_CREDENTIAL_DATA_LENGTH_BYTES: usize = 16 + 2 + 128 + 128;
+#[cfg(feature = "legacy-api")]
+pub type ATTESTED_CREDENTIAL_DATA_LENGTH = consts::U274;
+
+// RP ID hash, flags, signature count, attested credential data, extensions
+pub const AUTHENTICATOR_DATA_LENGTH_BYTES: usize = 32 + 1 + 4 + ATTESTED_CREDENTIAL_DATA_LENGTH_BYTES + 80;
+#[cfg(feature = "legacy-api")]
+pub type AUTHENTICATOR_DATA_LENGTH = consts::U391;
+
+// U2F register responses carry the attestation certificate, a small one
+#[cfg(feature = "legacy-api")]
+pub type U2F_RESPONSE_LENGTH = consts::U1024;
+
+// DER encoded P256 signatures take at most 72 bytes, Ed25519 ones 64
+#[cfg(feature = "legacy-api")]
+pub type ASN1_SIGNATURE_LENGTH = consts::U72;
@@ -8,12 +8,12 @@ const CONSTRUCTED: u8 = 1 << 5;
 #[repr(u8)]
 pub enum Tag {
     // Eoc = 0x00,
-    // Boolean = 0x01,
+    Boolean = 0x01,
     Integer = 0x02,
-    // BitString = 0x03,
-    // OctetString = 0x04,
+    BitString = 0x03,
+    OctetString = 0x04,
     // Null = 0x05,
-    // Oid = 0x06,
+    Oid = 0x06,
     Sequence = CONSTRUCTED | 0x10, // 0x30 or decimal 48
     // UtcTime = 0x17,
     // GeneralizedTime = 0x18,
@@ -98,6 +98,35 @@ impl<N: ArrayLength<u8>> Der<N> {
        self.extend_from_slice(value)
    }
 
+   /// Write a `BOOLEAN`, DER demands all bits set for true.
+   pub fn boolean(&mut self, value: bool) -> Result {
+       self.raw_tlv(Tag::Boolean, &[if value { 0xff } else { 0x00 }])
+   }
+
+   /// Write an `OCTET STRING`.
+   pub fn octet_string(&mut self, octet_string: &[u8]) -> Result {
+       self.raw_tlv(Tag::OctetString, octet_string)
+   }
+
+   /// Write an `OBJECT IDENTIFIER`, given its encoded value
+   /// (e.g. `2A 86 48 CE 3D 02 01` for id-ecPublicKey).
+   pub fn oid(&mut self, oid: &[u8]) -> Result {
+       self.raw_tlv(Tag::Oid, oid)
+   }
+
+   /// Write a `BIT STRING` whose last byte has `unused_bits` padding bits,
+   /// e.g. 0 for public keys in a SubjectPublicKeyInfo.
+   pub fn bit_string(&mut self, unused_bits: u8, bit_string: &[u8]) -> Result {
+       // an empty bit string has no byte to pad
+       if unused_bits > 7 || (bit_string.is_empty() && unused_bits != 0) {
+           return Err(());
+       }
+       self.extend_from_slice(&[Tag::BitString as u8])?;
+       self.write_length_field(bit_string.len() + 1)?;
+       self.extend_from_slice(&[unused_bits])?;
+       self.extend_from_slice(bit_string)
+   }
+
    /// Write the given input as integer.
    ///
    /// Assumes `input` is the big-endian representation of a non-negative `Integer`
@@ -110,6 +139,8 @@ impl<N: ArrayLength<u8>> Der<N> {
    /// The ASN.1 encoding is signed, so its leading bit must have value 0;
    /// it must also be of minimal length (so leading bytes of value 0 must be
    /// removed, except if that would contradict the rule about the sign bit).
+   ///
+   /// An all-zero (or empty) input encodes as the integer 0, i.e. `02 01 00`.
    pub fn non_negative_integer(&mut self, mut integer: &[u8]) -> Result {
        self.extend_from_slice(&[Tag::Integer as u8])?;
 
@@ -165,6 +196,82 @@ impl<N: ArrayLength<u8>> Der<N> {
    }
 }
 
+/// Encode a fixed-size `r || s` ECDSA signature as DER `SEQUENCE` of two `INTEGER`s.
+pub fn fixed_to_der_ecdsa(fixed: &[u8; 64]) -> core::result::Result<Der<consts::U72>, ()> {
+    let mut der = Der::new();
+    der.sequence(|der| {
+        der.non_negative_integer(&fixed[..32])?;
+        der.non_negative_integer(&fixed[32..])
+    })?;
+    Ok(der)
+}
+
+/// Decode a DER `SEQUENCE` of two `INTEGER`s into the fixed-size `r || s` form,
+/// as needed for U2F and for checking signatures.
+///
+/// Only strict DER is accepted: minimal lengths, no superfluous leading zeros,
+/// no negative integers and nothing after the sequence.
+pub fn der_to_fixed_ecdsa(der: &[u8], out: &mut [u8; 64]) -> Result {
+    let (sequence, rest) = read_tlv(der, Tag::Sequence)?;
+    if !rest.is_empty() {
+        return Err(());
+    }
+
+    let (r, sequence) = read_tlv(sequence, Tag::Integer)?;
+    let (s, sequence) = read_tlv(sequence, Tag::Integer)?;
+    if !sequence.is_empty() {
+        return Err(());
+    }
+
+    let mut fixed = [0u8; 64];
+    read_scalar(r, &mut fixed[..32])?;
+    read_scalar(s, &mut fixed[32..])?;
+    *out = fixed;
+    Ok(())
+}
+
+// split off the value of a TLV with given tag, returns (value, rest)
+fn read_tlv(input: &[u8], tag: Tag) -> core::result::Result<(&[u8], &[u8]), ()> {
+    if input.len() < 2 || input[0] != tag as u8 {
+        return Err(());
+    }
+
+    let (length, header) = match input[1] {
+        length if length < 0x80 => (length as usize, 2),
+        // long form is only allowed if the short form doesn't do
+        0x81 if input.len() > 2 && input[2] >= 0x80 => (input[2] as usize, 3),
+        // anything longer is not an ECDSA signature
+        _ => return Err(()),
+    };
+
+    let input = &input[header..];
+    if input.len() < length {
+        return Err(());
+    }
+    Ok(input.split_at(length))
+}
+
+// right-align a non-negative DER integer in `out`
+fn read_scalar(integer: &[u8], out: &mut [u8]) -> Result {
+    let integer = match integer {
+        [] => return Err(()),
+        // negative
+        [first, ..] if *first >= 0x80 => return Err(()),
+        // leading zero only to keep the sign bit clear
+        [0, second, ..] if *second >= 0x80 => &integer[1..],
+        [0, _, ..] => return Err(()),
+        _ => integer,
+    };
+
+    if integer.len() > out.len() {
+        return Err(());
+    }
+    let offset = out.len() - integer.len();
+    out[..offset].iter_mut().for_each(|byte| *byte = 0);
+    out[offset..].copy_from_slice(integer);
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -184,6 +291,152 @@ mod test {
 //        assert_eq!([0, 136, 255, 255, 255, 255, 255, 255, 255, 255], prefix.as_ref());
 //    }
 
+   #[test]
+   fn length_field_boundaries() {
+       // (length, expected encoding)
+       let cases: [(usize, &[u8]); 5] = [
+           // short form, single byte
+           (127, &[0x7f]),
+           // long form kicks in at 0x80
+           (128, &[0x81, 0x80]),
+           (255, &[0x81, 0xff]),
+           (256, &[0x82, 0x01, 0x00]),
+           (65535, &[0x82, 0xff, 0xff]),
+       ];
+
+       for (length, expected) in cases.iter() {
+           let mut der = Der::<consts::U12>::new();
+           der.write_length_field(*length).unwrap();
+           assert_eq!(&der[..], *expected);
+       }
+   }
+
+   #[test]
+   fn non_negative_integer_edge_cases() {
+       // zero, however many leading zero bytes, is `02 01 00`
+       for zero in [&[][..], &[0u8][..], &[0u8; 32][..]].iter() {
+           let mut der = Der::<consts::U8>::new();
+           der.non_negative_integer(zero).unwrap();
+           assert_eq!(&der[..], &[0x02, 0x01, 0x00]);
+       }
+
+       // high bit set needs a leading zero to stay non-negative
+       let mut der = Der::<consts::U8>::new();
+       der.non_negative_integer(&[0x80]).unwrap();
+       assert_eq!(&der[..], &[0x02, 0x02, 0x00, 0x80]);
+
+       // ...but only after stripping redundant leading zeros
+       let mut der = Der::<consts::U8>::new();
+       der.non_negative_integer(&[0x00, 0x00, 0x7f]).unwrap();
+       assert_eq!(&der[..], &[0x02, 0x01, 0x7f]);
+   }
+
+   #[test]
+   fn fixed_der_round_trip() {
+       // r and s both with high bit set, so both get padded in DER
+       let mut fixed = [0xa7u8; 64];
+       fixed[32..].copy_from_slice(&[0xb8; 32]);
+       let der = fixed_to_der_ecdsa(&fixed).unwrap();
+       assert_eq!(der.len(), 2 + 2 * (2 + 33));
+       let mut decoded = [0u8; 64];
+       der_to_fixed_ecdsa(&der, &mut decoded).unwrap();
+       assert_eq!(&decoded[..], &fixed[..]);
+
+       // short r (leading zero bytes), high bit s
+       let mut fixed = [0xffu8; 64];
+       fixed[..3].copy_from_slice(&[0x00, 0x00, 0x01]);
+       let der = fixed_to_der_ecdsa(&fixed).unwrap();
+       assert_eq!(&der[2..5], &[0x02, 30, 0x01]);
+       let mut decoded = [0u8; 64];
+       der_to_fixed_ecdsa(&der, &mut decoded).unwrap();
+       assert_eq!(&decoded[..], &fixed[..]);
+   }
+
+   #[test]
+   fn der_to_fixed_rejects_non_der() {
+       let mut out = [0u8; 64];
+       // valid: r = 1, s = 0x80
+       let valid = [0x30, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00, 0x80];
+       assert!(der_to_fixed_ecdsa(&valid, &mut out).is_ok());
+       assert_eq!(out[31], 0x01);
+       assert_eq!(&out[62..], &[0x00, 0x80]);
+
+       let invalid: [&[u8]; 6] = [
+           // trailing byte
+           &[0x30, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00, 0x80, 0x00],
+           // negative s
+           &[0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x80],
+           // superfluous leading zero in r
+           &[0x30, 0x07, 0x02, 0x02, 0x00, 0x01, 0x02, 0x01, 0x01],
+           // truncated
+           &[0x30, 0x07, 0x02, 0x01, 0x01, 0x02, 0x02, 0x00],
+           // only one integer
+           &[0x30, 0x03, 0x02, 0x01, 0x01],
+           // r too long
+           &[0x30, 0x26, 0x02, 0x21, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02, 0x01, 0x01],
+       ];
+       for der in invalid.iter() {
+           assert!(der_to_fixed_ecdsa(der, &mut out).is_err());
+       }
+   }
+
+   #[test]
+   fn write_octet_string() {
+       let mut der = Der::<consts::U8>::new();
+       der.octet_string(&[]).unwrap();
+       assert_eq!(&der[..], &[0x04, 0x00]);
+
+       let mut der = Der::<consts::U8>::new();
+       der.octet_string(&[0x0a, 0x0b, 0x0c]).unwrap();
+       assert_eq!(&der[..], &[0x04, 0x03, 0x0a, 0x0b, 0x0c]);
+   }
+
+   #[test]
+   fn write_bit_string() {
+       let mut der = Der::<consts::U8>::new();
+       der.bit_string(0, &[]).unwrap();
+       assert_eq!(&der[..], &[0x03, 0x01, 0x00]);
+
+       // 1010 1 plus 3 padding bits
+       let mut der = Der::<consts::U8>::new();
+       der.bit_string(3, &[0xa8]).unwrap();
+       assert_eq!(&der[..], &[0x03, 0x02, 0x03, 0xa8]);
+
+       let mut der = Der::<consts::U8>::new();
+       assert!(der.bit_string(8, &[0xa8]).is_err());
+       assert!(der.bit_string(1, &[]).is_err());
+       assert!(der.is_empty());
+   }
+
+   #[test]
+   fn write_oid_and_boolean() {
+       // SubjectPublicKeyInfo algorithm of a P-256 key
+       let mut der = Der::<consts::U32>::new();
+       der.sequence(|der| {
+           // id-ecPublicKey
+           der.oid(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01])?;
+           // prime256v1
+           der.oid(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07])
+       }).unwrap();
+       #[rustfmt::skip]
+       let expected = [
+           0x30, 0x13,
+               0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01,
+               0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07,
+       ];
+       assert_eq!(&der[..], &expected[..]);
+
+       let mut der = Der::<consts::U8>::new();
+       der.boolean(true).unwrap();
+       der.boolean(false).unwrap();
+       assert_eq!(&der[..], &[0x01, 0x01, 0xff, 0x01, 0x01, 0x00]);
+
+       // no room
+       let mut der = Der::<consts::U2>::new();
+       assert!(der.octet_string(&[0x0a]).is_err());
+   }
+
    #[test]
    fn write_asn1_der_ecdsa_signature() {
        let r = [
@@ -233,36 +486,12 @@ mod test {
 //// })
 //// .unwrap();
 
-//// /// Write an `OBJECT IDENTIFIER`.
-//// pub fn oid(&mut self, input: &[u8]) -> Result<()> {
-////     self.writer.0.extend_from_slice(&[Tag::Oid as u8])?;
-////     self.write_length_field(input.len())?;
-////     self.writer.0.extend_from_slice(&input)?;
-////     Ok(())
-//// }
-
 //// /// Write raw bytes to `self`. This does not calculate length or apply. This should only be used
 //// /// when you know you are dealing with bytes that are already DER encoded.
 //// pub fn raw(&mut self, input: &[u8]) -> Result<()> {
 ////     Ok(self.writer.0.extend_from_slice(input)?)
 //// }
 
-//// /// Write a `BIT STRING`.
-//// pub fn bit_string(&mut self, unused_bits: u8, bit_string: &[u8]) -> Result<()> {
-////     self.writer.0.extend_from_slice(&[Tag::BitString as u8])?;
-////     self.write_length_field(bit_string.len() + 1)?;
-////     self.writer.0.extend_from_slice(&[unused_bits])?;
-////     self.writer.0.extend_from_slice(&bit_string)?;
-////     Ok(())
-//// }
-
-//// /// Write an `OCTET STRING`.
-//// pub fn octet_string(&mut self, octet_string: &[u8]) -> Result<()> {
-////     self.writer.0.extend_from_slice(&[Tag::OctetString as u8])?;
-////     self.write_length_field(octet_string.len())?;
-////     self.writer.0.extend_from_slice(&octet_string)?;
-////     Ok(())
-//// }
 //// }
 
 //// #[cfg(test)]
@@ -298,23 +527,4 @@ mod test {
 
 ////         assert_eq!(buf.as_slice(), RSA_2048_PKCS1);
 ////     }
-
-////     #[test]
-////     fn write_octet_string() {
-////         let mut buf = Vec::new();
-////         {
-////             let mut der = Der::new(&mut buf);
-////             der.octet_string(&[]).unwrap();
-////         }
-
-////         assert_eq!(&buf, &[0x04, 0x00]);
-
-////         let mut buf = Vec::new();
-////         {
-////             let mut der = Der::new(&mut buf);
-////             der.octet_string(&[0x0a, 0x0b, 0x0c]).unwrap();
-////         }
-
-////         assert_eq!(&buf, &[0x04, 0x03, 0x0a, 0x0b, 0x0c]);
-////     }
 //// }
@@ -15,7 +15,7 @@
 //! # Goal:
 //!
 //! Here we implement a dumb FIDO2 device that just outputs
-//! diagnostic messages using semihosting
+//! diagnostic messages via the `log-*` features
 //!
 //! Maybe a better place is in a separate crate.
 //!
@@ -24,21 +24,19 @@
 //! Similar to littlefs2, the idea is to run test using this MVP implementation
 
 use core::{
-    convert::TryInto,
+    convert::{TryFrom, TryInto},
     ops::DerefMut,
 };
 
-#[cfg(feature = "logging")]
-use funnel::info;
-
-// use cortex_m_semihosting::hprintln;
 use cosey::PublicKey as CosePublicKey;
 use heapless::{
+    ArrayLength,
     Vec,
     String,
     consts,
 };
 use serde_indexed::{SerializeIndexed, DeserializeIndexed};
+use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use crate::{
     authenticator::{
@@ -52,6 +50,8 @@ use crate::{
         AUTHENTICATOR_DATA_LENGTH_BYTES,
     },
     types::{
+        cbor_deserialize,
+        cbor_serialize,
         AssertionResponse,
         AssertionResponses,
         AttestationObject,
@@ -59,15 +59,26 @@ use crate::{
         AttestedCredentialData,
         AuthenticatorData,
         AuthenticatorInfo,
+        CtapOptions,
+        GetAssertionExtensionsOutput,
         GetAssertionParameters,
+        MakeCredentialExtensions,
         MakeCredentialParameters,
         // NoneAttestationStatement,
         PackedAttestationStatement,
-        // PublicKeyCredentialUserEntity,
+        PublicKeyCredentialDescriptor,
+        PublicKeyCredentialUserEntity,
+        Transports,
     },
 };
 
 pub const SOLO_HACKER_ATTN_CERT: [u8; 511] = *include_bytes!("solo-hacker-attn-cert.der");
+/// P-256 secret scalar of the attestation key.
+///
+/// Despite the file name, the bytes are *big-endian*, which is what
+/// `nisty::Keypair::try_from_bytes` expects. Read as little-endian, they'd
+/// give a valid but different key, so attestation signatures would silently
+/// fail to verify against `SOLO_HACKER_ATTN_CERT`.
 pub const SOLO_HACKER_ATTN_KEY: [u8; 32] = *include_bytes!("solo-hacker-attn-key.le.raw");
 
 pub enum Keypair {
@@ -89,27 +100,154 @@ impl Keypair {
         }
     }
 
-    pub fn asn1_sign_prehashed(&self, digest: &[u8; 32]) -> Bytes<consts::U72> {
+    /// Reconstruct the keypair of a credential we issued.
+    ///
+    /// Credential IDs are not authenticated (yet), so a tampered
+    /// one can carry a short seed, which we report as `Error::Other`.
+    pub fn from_credential(credential_inner: &CredentialInner) -> Result<Self> {
+        let seed: [u8; 32] = credential_inner.seed.as_ref().try_into()
+            .map_err(|_| Error::Other)?;
+        Ok(match credential_inner.alg {
+            CredAlgorithm::EdDsa => Self::Ed25519(salty::Keypair::from(&seed)),
+            CredAlgorithm::Es256 => Self::P256(nisty::Keypair::generate_patiently(&seed)),
+        })
+    }
+
+    /// Ed25519 signature, always 64 bytes, of the message itself (not a digest).
+    ///
+    /// Calling this on a P256 keypair is a logic error on our side,
+    /// which we report as `Error::Other` instead of panicking.
+    pub fn sign_fixed(&self, message: &[u8]) -> Result<[u8; 64]> {
         match self {
-            Self::Ed25519(keypair) => {
-                let sig_fixed = keypair.sign(digest).to_bytes();
-                Bytes::<consts::U72>::try_from_slice(&sig_fixed).unwrap()
-            },
+            Self::Ed25519(keypair) => Ok(keypair.sign(message).to_bytes()),
+            Self::P256(_) => Err(Error::Other),
+        }
+    }
 
-            Self::P256(keypair) => {
-                keypair.sign_prehashed(digest).to_asn1_der()
-            },
+    /// P256 signature of a SHA-256 digest, DER encoded (at most 72 bytes).
+    ///
+    /// Calling this on an Ed25519 keypair is a logic error, as with `sign_fixed`.
+    pub fn sign_der(&self, digest: &[u8; 32]) -> Result<Bytes<consts::U72>> {
+        match self {
+            Self::P256(keypair) => Ok(keypair.sign_prehashed(digest).to_asn1_der()),
+            Self::Ed25519(_) => Err(Error::Other),
         }
     }
 }
 
+/// SHA-256 of the RP ID, as used in authenticator data.
+pub fn rp_id_hash(rp_id: &str) -> Bytes<consts::U32> {
+    // SHA-256 digests have exactly 32 bytes, this can't fail
+    Bytes::try_from_slice(&nisty::prehash(rp_id.as_bytes())).unwrap()
+}
+
+/// Like `Bytes::from_serialized`, but reports an object that doesn't fit
+/// as `Error::Other` instead of panicking.
+pub fn try_from_serialized<N, T>(object: &T) -> Result<Bytes<N>>
+where
+    N: ArrayLength<u8>,
+    T: serde::Serialize,
+{
+    let mut bytes = Vec::<u8, N>::new();
+    bytes.resize_default(N::to_usize()).map_err(|_| Error::Other)?;
+    let size = cbor_serialize(object, &mut bytes).map_err(|_| Error::Other)?;
+    bytes.truncate(size);
+    Ok(Bytes::from(bytes))
+}
+
+/// Collect bytes without an intermediate buffer, reporting
+/// more than fit as `Error::Other` instead of panicking.
+pub fn try_from_iter<N, I>(iter: I) -> Result<Bytes<N>>
+where
+    N: ArrayLength<u8>,
+    I: IntoIterator<Item = u8>,
+{
+    let mut bytes = Vec::<u8, N>::new();
+    for byte in iter {
+        bytes.push(byte).map_err(|_| Error::Other)?;
+    }
+    Ok(Bytes::from(bytes))
+}
+
 pub struct InsecureRamAuthenticator {
     aaguid: Bytes<consts::U16>,
     master_secret: [u8; 32],
     signature_count: u32,
+    // RAM only, so they're gone on power cycle, another feature ^^
+    resident_credentials: Vec<ResidentCredential, consts::U8>,
+    // would be set via clientPin, which we don't implement yet
+    pin_set: bool,
+    attestation_chain: &'static [&'static [u8]],
+    transports: Transports,
 }
 
 impl InsecureRamAuthenticator {
+    /// Decode a credential ID we issued, `None` for foreign or garbled ones.
+    fn credential_inner(credential_id: &Bytes<consts::U128>) -> Option<CredentialInner> {
+        let mut credential_id = credential_id.clone();
+        cbor_deserialize(credential_id.deref_mut()).ok()
+    }
+
+    /// Attest with the given certificate chain instead of just
+    /// `SOLO_HACKER_ATTN_CERT`, which must still come first, as
+    /// attestation signatures are made with `SOLO_HACKER_ATTN_KEY`.
+    pub fn with_attestation_chain(self, attestation_chain: &'static [&'static [u8]]) -> Self {
+        Self { attestation_chain, ..self }
+    }
+
+    /// The signature counter the next operation will report.
+    pub fn signature_count(&self) -> u32 {
+        self.signature_count
+    }
+
+    /// Free slots for resident credentials.
+    pub fn remaining_discoverable_credentials(&self) -> usize {
+        self.resident_credentials.capacity() - self.resident_credentials.len()
+    }
+
+    // strictly increasing: once exhausted, we'd rather fail than repeat a count
+    fn next_signature_count(&mut self) -> Result<u32> {
+        let count = self.signature_count;
+        self.signature_count = count.checked_add(1).ok_or(Error::Other)?;
+        Ok(count)
+    }
+
+    /// Advertise the transports the device actually exposes, USB only by default.
+    pub fn with_transports(self, transports: Transports) -> Self {
+        Self { transports, ..self }
+    }
+
+    /// The hmac-secret extension's CredRandom of a credential.
+    ///
+    /// Instead of storing it, we derive it from the master secret
+    /// and the credential's seed, which the credential ID carries.
+    pub fn cred_random(&self, credential_inner: &CredentialInner) -> [u8; 32] {
+        use sha2::digest::Digest;
+        let mut hash = sha2::Sha256::new();
+        hash.input(&self.master_secret);
+        hash.input(&credential_inner.seed);
+        hash.input(b"hmac-secret");
+        let mut cred_random = [0u8; 32];
+        cred_random.copy_from_slice(&hash.result());
+        cred_random
+    }
+
+    /// We can't verify users ourselves, so requested UV always means a PIN:
+    /// if there is none, the host must set one first (PinNotSet),
+    /// otherwise it should have sent a pinAuth (PinRequired).
+    fn check_user_verification(&self, uv: bool, pin_auth: &Option<Bytes<consts::U16>>) -> Result<()> {
+        if !uv {
+            return Ok(());
+        }
+        if !self.pin_set {
+            return Err(Error::PinNotSet);
+        }
+        match pin_auth {
+            None => Err(Error::PinRequired),
+            // without clientPin, there is no pinToken to check it against
+            Some(_) => Err(Error::PinAuthInvalid),
+        }
+    }
 }
 
 impl Default for InsecureRamAuthenticator {
@@ -119,6 +257,10 @@ impl Default for InsecureRamAuthenticator {
             // Haaha. See why this is called an "insecure" authenticator? :D
             master_secret: [37u8; 32],
             signature_count: 123,
+            resident_credentials: Vec::new(),
+            pin_set: false,
+            attestation_chain: &[&SOLO_HACKER_ATTN_CERT],
+            transports: Transports::default(),
         }
     }
 }
@@ -132,82 +274,197 @@ impl Default for InsecureRamAuthenticator {
 // For resident keys, it uses (CredentialId, UserEntity)
 #[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed,DeserializeIndexed)]
 pub struct CredentialInner {
+    // format of the credential ID, see `CREDENTIAL_VERSION`
+    pub version: u8,
     pub user_id: Bytes<consts::U64>,
-    pub alg: i8,
+    pub alg: CredAlgorithm,
     pub seed: Bytes<consts::U32>,
+    // created with the hmac-secret extension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hmac_secret: Option<bool>,
+    // credProtect level, absent means 1 (userVerificationOptional)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<u8>,
+}
+
+/// Signature algorithms of our credentials, serialized as their COSE identifier.
+#[derive(Copy,Clone,Debug,Eq,PartialEq,Serialize_repr,Deserialize_repr)]
+#[repr(i8)]
+pub enum CredAlgorithm {
+    Es256 = -7,
+    EdDsa = -8,
+}
+
+impl From<CredAlgorithm> for i32 {
+    fn from(alg: CredAlgorithm) -> i32 {
+        alg as i8 as i32
+    }
+}
+
+impl TryFrom<i32> for CredAlgorithm {
+    type Error = ();
+
+    fn try_from(alg: i32) -> core::result::Result<Self, ()> {
+        match alg {
+            -7 => Ok(CredAlgorithm::Es256),
+            -8 => Ok(CredAlgorithm::EdDsa),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Current format of `CredentialInner`, to be bumped with any change to it
+/// other than appending optional fields, which older IDs simply lack.
+///
+/// Credential IDs of other versions are recognized as ours, but rejected
+/// as invalid instead of being misinterpreted.
+pub const CREDENTIAL_VERSION: u8 = 1;
+
+/// Longest serialized `CredentialInner`, i.e. credential ID, we issue.
+///
+/// A map of six entries with single byte keys: version (1, while < 24),
+/// user_id (2 + 64), alg (1), seed (2 + 32), hmac_secret (1) and
+/// cred_protect (1). Shorter user IDs give shorter credential IDs.
+pub const MAX_CREDENTIAL_ID_LENGTH: usize =
+    1 + (1 + 1) + (1 + 66) + (1 + 1) + (1 + 34) + (1 + 1) + (1 + 1);
+
+/// `{"hmac-secret": true}`, the make-credential extension output
+pub const HMAC_SECRET_CREATED: [u8; 14] = [
+    0xa1,
+        0x6b, 0x68, 0x6d, 0x61, 0x63, 0x2d, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74,
+        0xf5,
+];
+
+/// Whether a credential of the given credProtect level may be used,
+/// otherwise it is treated as if it didn't exist.
+fn cred_protect_allows(cred_protect: Option<u8>, uv_performed: bool, in_allow_list: bool) -> bool {
+    match cred_protect.unwrap_or(1) {
+        // userVerificationRequired
+        3 => uv_performed,
+        // userVerificationOptionalWithCredentialIDList
+        2 => uv_performed || in_allow_list,
+        _ => true,
+    }
+}
+
+/// A resident (discoverable) credential, found via the RP ID
+/// instead of an allow list entry.
+#[derive(Clone,Debug,Eq,PartialEq)]
+pub struct ResidentCredential {
+    pub rp_id_hash: Bytes<consts::U32>,
+    pub user: PublicKeyCredentialUserEntity,
+    pub credential_id: Bytes<consts::U128>,
 }
 
 impl authenticator::Api for InsecureRamAuthenticator {
 
     fn get_assertions(&mut self, params: &GetAssertionParameters) -> Result<AssertionResponses>
     {
-        if params.allow_list.len() == 0 {
-            return Err(Error::NoCredentials);
-        }
+        self.check_user_verification(params.uv(), &params.pin_auth)?;
+        // getting here with UV requested means it was performed
+        let uv_performed = params.uv();
 
-        if params.allow_list.len() != 1 {
-            return Err(Error::Other);
+        let rp_id_hash = rp_id_hash(params.rp_id.as_str());
+
+        let resolved = if params.allow_list.is_empty() {
+            // no allow list, so this has to be a resident credential
+            self.resident_credentials.iter().rev()
+                .filter(|credential| credential.rp_id_hash == rp_id_hash)
+                .find_map(|credential| Self::credential_inner(&credential.credential_id)
+                    .filter(|inner| cred_protect_allows(inner.cred_protect, uv_performed, false))
+                    .map(|inner| (credential.credential_id.clone(), inner)))
+        } else {
+            // first entry of the allow list that is actually ours
+            params.allow_list.iter()
+                .find_map(|descriptor| Self::credential_inner(&descriptor.id)
+                    .filter(|inner| cred_protect_allows(inner.cred_protect, uv_performed, true))
+                    .map(|inner| (descriptor.id.clone(), inner)))
+        };
+
+        let (credential_id, credential_inner) = match resolved {
+            Some(resolved) => resolved,
+            None => return Err(Error::NoCredentials),
+        };
+        if credential_inner.version != CREDENTIAL_VERSION {
+            return Err(Error::InvalidCredential);
         }
 
-        let mut cloned_credential_id = params.allow_list[0].id.clone();
-        let credential_inner: CredentialInner =
-            ctapcbor::de::from_bytes(cloned_credential_id.deref_mut()).unwrap();
+        // resident credentials are the ones in our store (whether found
+        // via allow list or not), they come with a user entity.
+        // non-resident credentials only have the user ID baked in.
+        let user = self.resident_credentials.iter()
+            .find(|resident| resident.credential_id == credential_id)
+            .map(|resident| resident.user.clone());
 
-        let keypair = if credential_inner.alg == -8 {
-            Keypair::Ed25519(salty::Keypair::from(&credential_inner.seed.as_ref().try_into().unwrap()))
+        let descriptor = PublicKeyCredentialDescriptor {
+            id: credential_id.clone(),
+            key_type: String::from("public-key"),
+        };
+        // optional if the allow list had exactly one entry,
+        // the platform knows which one it was.
+        let credential = if params.allow_list.len() == 1 {
+            None
         } else {
-            let seed_array: [u8; 32] = credential_inner.seed.as_ref().try_into().unwrap();
-            Keypair::P256(nisty::Keypair::generate_patiently(&seed_array))
+            Some(descriptor.clone())
         };
 
-        let rp_id_hash = Bytes::<consts::U32>::try_from_slice(
-            &nisty::prehash(&params.rp_id.as_str().as_bytes()
-        )).unwrap();
+        let keypair = Keypair::from_credential(&credential_inner)?;
+
+        // only credentials created with hmac-secret answer it
+        let hmac_secret_input = params.extensions.as_ref()
+            .and_then(|extensions| extensions.hmac_secret.as_ref())
+            .filter(|_| credential_inner.hmac_secret == Some(true));
+        let hmac_secret = match hmac_secret_input {
+            Some(input) => self.hmac_secret(&descriptor, input)?,
+            None => None,
+        };
+        let extensions = match hmac_secret {
+            Some(output) => Some(try_from_serialized(
+                &GetAssertionExtensionsOutput { hmac_secret: Some(output) })?),
+            None => None,
+        };
 
-        let auth_data = AuthenticatorData {
+        let mut auth_data = AuthenticatorData {
             rp_id_hash,
             // USER_PRESENT = 0x01
             // USER_VERIFIED = 0x04
-            flags: 0x01, // | 0x40,
-            sign_count: self.signature_count,
+            // EXTENSION_DATA = 0x80
+            flags: if params.up() { 0x01 } else { 0x00 } | if extensions.is_some() { 0x80 } else { 0x00 },
+            sign_count: self.next_signature_count()?.into(),
             attested_credential_data: None,
+            extensions,
         };
-        self.signature_count += 1;
+        self.decorate_auth_data(&mut auth_data);
         let serialized_auth_data = auth_data.serialize();
 
         use sha2::digest::Digest;
         let mut hash = sha2::Sha256::new();
         hash.input(&serialized_auth_data);
         hash.input(&params.client_data_hash);
-        let digest: [u8; 32] = hash.result().try_into().unwrap();
+        let digest: [u8; 32] = hash.result().try_into().map_err(|_| Error::Other)?;
 
-        let sig = if credential_inner.alg == -8 {
+        let sig = if credential_inner.alg == CredAlgorithm::EdDsa {
             let mut buf = [0u8; AUTHENTICATOR_DATA_LENGTH_BYTES + 32];
             let auth_data_size = serialized_auth_data.len();
             buf[..auth_data_size].copy_from_slice(&serialized_auth_data);
             buf[auth_data_size..][..params.client_data_hash.len()].copy_from_slice(&params.client_data_hash);
 
-            let sig_fixed = match keypair {
-                Keypair::Ed25519(keypair) => {
-                    keypair.sign(&buf[..auth_data_size + params.client_data_hash.len()]).to_bytes()
-                },
-                _ => { unreachable!(); },
-            };
-            Bytes::<consts::U72>::try_from_slice(&sig_fixed).unwrap()
+            let sig_fixed = keypair.sign_fixed(&buf[..auth_data_size + params.client_data_hash.len()])?;
+            Bytes::try_from_slice(&sig_fixed).map_err(|_| Error::Other)?
         } else {
-            keypair.asn1_sign_prehashed(&digest)
+            keypair.sign_der(&digest)?
         };
 
         let response = AssertionResponse {
-            user: None,
+            user,
             auth_data: serialized_auth_data,
             signature: sig,
-            credential: None, //Some(params.allow_list[0].clone()),
+            credential,
             number_of_credentials: None, // Some(1),
         };
 
         let mut responses = AssertionResponses::new();
-        responses.push(response).unwrap();
+        responses.push(response).map_err(|_| Error::Other)?;
 
         Ok(responses)
     }
@@ -216,33 +473,43 @@ impl authenticator::Api for InsecureRamAuthenticator {
 
         // 1. excludeList present, contains credential ID on this authenticator bound to RP?
         // --> wait for UP, error CredentialExcluded
+        if let Some(ref exclude_list) = &params.exclude_list {
+            // we can't tell whether a dropped entry would have matched
+            if exclude_list.limit_exceeded {
+                return Err(Error::LimitExceeded);
+            }
+            if exclude_list.iter().any(|descriptor| self.owns_credential(descriptor)) {
+                return Err(Error::CredentialExcluded);
+            }
+        }
 
         // 2. check pubKeyCredParams algorithm is valid + supported COSE identifier
-        let mut supported_algorithm = false;
-        let mut eddsa = false;
+        let mut algorithm = None;
         for param in params.pub_key_cred_params.iter() {
-            match param.alg {
-                -7 => { supported_algorithm = true; },
-                -8 => { eddsa = true; supported_algorithm = true; },
+            match CredAlgorithm::try_from(param.alg) {
+                // prefer Ed25519
+                Ok(CredAlgorithm::EdDsa) => { algorithm = Some(CredAlgorithm::EdDsa); },
+                Ok(CredAlgorithm::Es256) if algorithm.is_none() => { algorithm = Some(CredAlgorithm::Es256); },
                 _ => {},
             }
         }
-        if !supported_algorithm {
-            return Err(Error::UnsupportedAlgorithm);
-        }
+        // an empty list can't contain anything we support either, so it
+        // gets the same CTAP2_ERR_UNSUPPORTED_ALGORITHM (the CBOR was fine)
+        let algorithm = algorithm.ok_or(Error::UnsupportedAlgorithm)?;
 
         // 3. check for known but unsupported options
-        match &params.options {
-            Some(ref options) => {
-                if Some(true) == options.rk {
-                    return Err(Error::UnsupportedOption);
-                }
-                if Some(true) == options.uv {
-                    return Err(Error::UnsupportedOption);
-                }
-            },
-            _ => {},
-        }
+        let rk = params.rk();
+        self.check_user_verification(params.uv(), &params.pin_auth)?;
+
+        let hmac_secret = params.extensions.as_ref()
+            .and_then(|extensions| extensions.hmac_secret)
+            == Some(true);
+        // unknown levels are ignored
+        let cred_protect = params.extensions.as_ref()
+            .and_then(|extensions| extensions.cred_protect)
+            .filter(|level| (1..=3).contains(level));
+
+        let rp_id_hash = rp_id_hash(params.rp.id.as_str());
 
         // 9. generate new key pair \o/
         // We do it quick n' dirty here because YOLO
@@ -253,25 +520,19 @@ impl authenticator::Api for InsecureRamAuthenticator {
         let digest: [u8; 64] = hash.finalize();
         let seed = nisty::prehash(&digest);
 
-        // let keypair = if eddsa {
-        let keypair = if eddsa {
-            // prefer Ed25519
-            #[cfg(feature = "logging")]
-            info!("making Ed25519 credential, woo!").ok();
-            Keypair::Ed25519(salty::Keypair::from(&seed))
-        } else {
-            #[cfg(feature = "logging")]
-            info!("making P256 credential, eww!").ok();
-            Keypair::P256(nisty::Keypair::generate_patiently(&seed))
+        let keypair = match algorithm {
+            CredAlgorithm::EdDsa => {
+                info!("making Ed25519 credential, woo!").ok();
+                Keypair::Ed25519(salty::Keypair::from(&seed))
+            },
+            CredAlgorithm::Es256 => {
+                info!("making P256 credential, eww!").ok();
+                Keypair::P256(nisty::Keypair::generate_patiently(&seed))
+            },
         };
 
         let credential_public_key: CosePublicKey = keypair.as_cose_public_key();
 
-        // hprintln!("serialized public_key: {:?}", &credential_public_key).ok();
-
-        // 10. if `rk` option is set, attempt to store it
-        // -> ruled out by above
-
         // 11. generate attestation statement.
         // For now, only "none" format, which has serialized "empty map" (0xa0) as its statement
 
@@ -280,11 +541,13 @@ impl authenticator::Api for InsecureRamAuthenticator {
         // as credential ID ^^
         // TODO: do some AEAD based on xchacha20, later reject tampered/invalid credential IDs
         let credential_inner = CredentialInner {
+            version: CREDENTIAL_VERSION,
             user_id: params.user.id.clone(),
-            alg: if eddsa { -8 } else { -7 },
-            seed: Bytes::try_from_slice(&seed).unwrap(),
+            alg: algorithm,
+            seed: Bytes::try_from_slice(&seed).map_err(|_| Error::Other)?,
+            hmac_secret: if hmac_secret { Some(true) } else { None },
+            cred_protect,
         };
-        // hprintln!("credential inner: {:?}", &credential_inner);
                         // let writer = serde_cbor::ser::SliceWrite::new(&mut self.buffer[1..]);
                         // let mut ser = serde_cbor::Serializer::new(writer)
                         //     .packed_format()
@@ -297,17 +560,30 @@ impl authenticator::Api for InsecureRamAuthenticator {
                         // let writer = ser.into_inner();
                         // let size = 1 + writer.bytes_written();
 
-        let credential_id = Bytes::<consts::U128>::from_serialized(&credential_inner);
-        // hprintln!("credential_id: {:?}", &credential_id).ok();
-        // let mut credential_id = Bytes::<consts::U128>::new();
-        // credential_id.extend_from_slice(&seed).unwrap();
+        let credential_id: Bytes<consts::U128> = try_from_serialized(&credential_inner)?;
+
+        // 10. if `rk` option is set, attempt to store it,
+        // replacing an earlier one for the same RP and user
+        if rk {
+            let resident = ResidentCredential {
+                rp_id_hash: rp_id_hash.clone(),
+                user: params.user.clone(),
+                credential_id: credential_id.clone(),
+            };
+            let existing = self.resident_credentials.iter_mut()
+                .find(|credential| credential.rp_id_hash == rp_id_hash && credential.user.id == params.user.id);
+            match existing {
+                Some(credential) => *credential = resident,
+                None => self.resident_credentials.push(resident)
+                    .map_err(|_| Error::KeyStoreFull)?,
+            }
+        }
 
         let attested_credential_data = AttestedCredentialData {
             aaguid: self.aaguid.clone(),
             credential_id,
             credential_public_key,
         };
-        // hprintln!("attested credential data = {:?}", attested_credential_data).ok();
 
         // flags:
         //
@@ -315,20 +591,25 @@ impl authenticator::Api for InsecureRamAuthenticator {
         // USER_VERIFIED = 0x04
         // ATTESTED = 0x40
         // EXTENSION_DATA = 0x80
-        let auth_data = AuthenticatorData {
-            rp_id_hash: Bytes::<consts::U32>::from({
-                let mut bytes = Vec::<u8, consts::U32>::new();
-                bytes.extend_from_slice(&nisty::prehash(&params.rp.id.as_str().as_bytes())).unwrap();
-                bytes
-            }),
-            flags: 0x01 | 0x40,
+        let extensions_output = MakeCredentialExtensions {
+            cred_protect,
+            hmac_secret: if hmac_secret { Some(true) } else { None },
+        };
+        let extensions = if extensions_output == MakeCredentialExtensions::default() {
+            None
+        } else {
+            Some(try_from_serialized(&extensions_output)?)
+        };
+        let mut auth_data = AuthenticatorData {
+            rp_id_hash,
+            flags: 0x01 | 0x40 | if extensions.is_some() { 0x80 } else { 0x00 },
             // flags: 0x0,
-            sign_count: self.signature_count,
+            sign_count: self.next_signature_count()?.into(),
             attested_credential_data: Some(attested_credential_data.serialize()),
             // attested_credential_data: None,
+            extensions,
         };
-        self.signature_count += 1;
-        // hprintln!("auth data = {:?}", &auth_data).ok();
+        self.decorate_auth_data(&mut auth_data);
 
         let serialized_auth_data = auth_data.serialize();
 
@@ -341,17 +622,21 @@ impl authenticator::Api for InsecureRamAuthenticator {
         let mut hash = sha2::Sha256::new();
         hash.input(&serialized_auth_data);
         hash.input(&params.client_data_hash);
-        let digest: [u8; 32] = hash.result().try_into().unwrap();
+        let digest: [u8; 32] = hash.result().try_into().map_err(|_| Error::Other)?;
         // data.into()
-        let attn_keypair = Keypair::P256(nisty::Keypair::try_from_bytes(&SOLO_HACKER_ATTN_KEY).unwrap());
-        let sig = attn_keypair.asn1_sign_prehashed(&digest);
+        let attn_keypair = Keypair::P256(nisty::Keypair::try_from_bytes(&SOLO_HACKER_ATTN_KEY)
+            .map_err(|_| Error::Other)?);
+        let sig = attn_keypair.sign_der(&digest)?;
 
         let mut packed_attn_stmt = PackedAttestationStatement {
             alg: -7,
             sig,
             x5c: Vec::new(),
         };
-        packed_attn_stmt.x5c.push(Bytes::try_from_slice(&SOLO_HACKER_ATTN_CERT).unwrap()).unwrap();
+        for certificate in self.attestation_certificates() {
+            let certificate = Bytes::try_from_slice(certificate).map_err(|_| Error::Other)?;
+            packed_attn_stmt.x5c.push(certificate).map_err(|_| Error::Other)?;
+        }
 
         let fmt = String::<consts::U32>::from("packed");
         let att_stmt = AttestationStatement::Packed(packed_attn_stmt);
@@ -365,22 +650,715 @@ impl authenticator::Api for InsecureRamAuthenticator {
         Ok(attestation_object)
     }
 
-    fn get_info(&mut self) -> AuthenticatorInfo {
+    fn get_info(&mut self) -> Result<AuthenticatorInfo> {
 
         use core::str::FromStr;
         let mut versions = Vec::<String<consts::U12>, consts::U3>::new();
         versions.push(String::from_str("FIDO_2_0").unwrap()).unwrap();
+        let mut extensions = Vec::new();
+        extensions.push(String::from_str("credProtect").unwrap()).unwrap();
+        extensions.push(String::from_str("hmac-secret").unwrap()).unwrap();
 
-        AuthenticatorInfo {
+        Ok(AuthenticatorInfo {
             versions,
+            extensions: Some(extensions),
             aaguid: self.aaguid.clone(),
+            options: Some(CtapOptions { rk: true, ..CtapOptions::default() }),
             max_msg_size: Some(constants::MESSAGE_SIZE),
+            max_cred_id_length: Some(MAX_CREDENTIAL_ID_LENGTH),
+            transports: Some(self.transports.names()),
+            remaining_discoverable_credentials: Some(self.remaining_discoverable_credentials()),
             ..AuthenticatorInfo::default()
-        }
+        })
+    }
+
+    fn owns_credential(&self, descriptor: &PublicKeyCredentialDescriptor) -> bool {
+        Self::credential_inner(&descriptor.id).is_some()
+    }
+
+    fn attestation_certificates(&self) -> &[&[u8]] {
+        self.attestation_chain
     }
 
     fn reset(&mut self) -> Result<()> {
-        self.master_secret[0] += 1;
+        // rotate the master secret, forget everything else
+        let mut master_secret = self.master_secret;
+        master_secret[0] = master_secret[0].wrapping_add(1);
+        *self = Self {
+            master_secret,
+            attestation_chain: self.attestation_chain,
+            transports: self.transports,
+            ..Self::default()
+        };
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use authenticator::Api;
+    use crate::types::{
+        serialize_make_credential_response,
+        AuthenticatorOptions,
+        ExcludeList,
+        PublicKeyCredentialParameters,
+        PublicKeyCredentialRpEntity,
+    };
+
+    fn make_credential_parameters(rk: bool) -> MakeCredentialParameters {
+        let mut pub_key_cred_params = Vec::new();
+        pub_key_cred_params.push(PublicKeyCredentialParameters {
+            alg: -7,
+            key_type: String::from("public-key"),
+        }).unwrap();
+
+        MakeCredentialParameters {
+            client_data_hash: Bytes::try_from_slice(b"1234567890ABCDEF1234567890ABCDEF").unwrap(),
+            rp: PublicKeyCredentialRpEntity {
+                id: String::from("yamnord.com"),
+                name: None,
+                url: None,
+            },
+            user: PublicKeyCredentialUserEntity::from(Bytes::try_from_slice(b"nickray").unwrap()),
+            pub_key_cred_params,
+            exclude_list: None,
+            extensions: None,
+            options: Some(AuthenticatorOptions { rk: Some(rk), up: None, uv: None }),
+            pin_auth: None,
+            pin_protocol: None,
+        }
+    }
+
+    fn get_assertion_parameters(
+        allow_list: Vec<PublicKeyCredentialDescriptor, consts::U8>,
+    ) -> GetAssertionParameters {
+        GetAssertionParameters {
+            rp_id: String::from("yamnord.com"),
+            client_data_hash: Bytes::try_from_slice(b"some_client_data_hash_abcdefghij").unwrap(),
+            allow_list,
+            extensions: None,
+            options: None,
+            pin_auth: None,
+            pin_protocol: None,
+        }
+    }
+
+    #[test]
+    fn resident_assertion_contains_credential() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+        let credential_id = authenticator.resident_credentials[0].credential_id.clone();
+
+        let responses = authenticator.get_assertions(&get_assertion_parameters(Vec::new())).unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].credential, Some(PublicKeyCredentialDescriptor {
+            id: credential_id.clone(),
+            key_type: String::from("public-key"),
+        }));
+
+        // resident credentials come with their user
+        assert_eq!(responses[0].user, Some(make_credential_parameters(true).user));
+    }
+
+    #[test]
+    fn non_resident_assertion_omits_user() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        // make it resident to get hold of the ID, then "forget" it was resident
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+        let credential_id = authenticator.resident_credentials.pop().unwrap().credential_id;
+
+        let descriptor = PublicKeyCredentialDescriptor {
+            id: credential_id,
+            key_type: String::from("public-key"),
+        };
+        let mut allow_list = Vec::new();
+        allow_list.push(descriptor.clone()).unwrap();
+
+        // the platform knows which credential it allowed
+        let responses = authenticator.get_assertions(&get_assertion_parameters(allow_list.clone())).unwrap();
+        assert_eq!(responses[0].credential, None);
+        assert_eq!(responses[0].user, None);
+
+        // but not which of several
+        allow_list.push(PublicKeyCredentialDescriptor {
+            id: Bytes::try_from_slice(b"not ours").unwrap(),
+            key_type: String::from("public-key"),
+        }).unwrap();
+        let responses = authenticator.get_assertions(&get_assertion_parameters(allow_list)).unwrap();
+        assert_eq!(responses[0].credential, Some(descriptor));
+        assert_eq!(responses[0].user, None);
+    }
+
+    #[test]
+    fn foreign_allow_list_has_no_credentials() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+
+        let mut allow_list = Vec::new();
+        for id in [&b"not ours"[..], &[0xffu8; 64][..]].iter() {
+            allow_list.push(PublicKeyCredentialDescriptor {
+                id: Bytes::try_from_slice(id).unwrap(),
+                key_type: String::from("public-key"),
+            }).unwrap();
+        }
+
+        assert_eq!(
+            authenticator.get_assertions(&get_assertion_parameters(allow_list)),
+            Err(Error::NoCredentials),
+        );
+    }
+
+    #[test]
+    fn signature_count_strictly_increases() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let mut last_count = None;
+        for operation in 0..10 {
+            let auth_data = if operation % 3 == 0 {
+                authenticator.make_credential(&make_credential_parameters(true)).unwrap().auth_data
+            } else {
+                authenticator.get_assertions(&get_assertion_parameters(Vec::new())).unwrap()[0].auth_data.clone()
+            };
+            // after RP ID hash and flags, big-endian
+            let count = u32::from_be_bytes(auth_data[33..37].try_into().unwrap());
+            if let Some(last_count) = last_count {
+                assert!(count > last_count);
+            }
+            assert_eq!(authenticator.signature_count(), count + 1);
+            last_count = Some(count);
+        }
+
+        // exhausted, no operation may reuse the last count
+        authenticator.signature_count = u32::MAX;
+        assert_eq!(authenticator.get_assertions(&get_assertion_parameters(Vec::new())).err(), Some(Error::Other));
+        assert_eq!(authenticator.signature_count(), u32::MAX);
+    }
+
+    #[test]
+    fn hmac_secret_needs_key_agreement() {
+        use crate::types::{cose, GetAssertionExtensions, HmacSecretInput};
+
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let mut params = make_credential_parameters(true);
+        params.extensions = Some(MakeCredentialExtensions { hmac_secret: Some(true), ..Default::default() });
+        authenticator.make_credential(&params).unwrap();
+
+        let mut params = get_assertion_parameters(Vec::new());
+        params.extensions = Some(GetAssertionExtensions { hmac_secret: Some(HmacSecretInput {
+            key_agreement: cose::P256PublicKey {
+                x: Bytes::try_from_slice(&[0x0a; 32]).unwrap(),
+                y: Bytes::try_from_slice(&[0x0b; 32]).unwrap(),
+            },
+            salt_enc: Bytes::try_from_slice(&[0x5a; 32]).unwrap(),
+            salt_auth: Bytes::try_from_slice(&[0xa5; 16]).unwrap(),
+        })});
+        // we don't do key agreement, so the default hook leaves out the output
+        let responses = authenticator.get_assertions(&params).unwrap();
+        assert_eq!(responses[0].auth_data[32] & 0x80, 0x00);
+        assert_eq!(responses[0].auth_data.len(), 37);
+    }
+
+    #[test]
+    fn remaining_discoverable_credentials() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let capacity = authenticator.remaining_discoverable_credentials();
+        assert_eq!(authenticator.get_info().unwrap().remaining_discoverable_credentials, Some(capacity));
+
+        let mut params = make_credential_parameters(true);
+        authenticator.make_credential(&params).unwrap();
+        assert_eq!(authenticator.remaining_discoverable_credentials(), capacity - 1);
+        // same RP and user again overwrites
+        authenticator.make_credential(&params).unwrap();
+        assert_eq!(authenticator.remaining_discoverable_credentials(), capacity - 1);
+        // a different user takes another slot
+        params.user.id = Bytes::try_from_slice(b"someone else").unwrap();
+        authenticator.make_credential(&params).unwrap();
+        assert_eq!(authenticator.remaining_discoverable_credentials(), capacity - 2);
+        // non-resident ones take none
+        authenticator.make_credential(&make_credential_parameters(false)).unwrap();
+        assert_eq!(authenticator.get_info().unwrap().remaining_discoverable_credentials, Some(capacity - 2));
+
+        authenticator.reset().unwrap();
+        assert_eq!(authenticator.remaining_discoverable_credentials(), capacity);
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let initial_count = authenticator.signature_count;
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+        assert!(authenticator.get_assertions(&get_assertion_parameters(Vec::new())).is_ok());
+        assert!(authenticator.signature_count > initial_count);
+
+        authenticator.reset().unwrap();
+
+        assert!(authenticator.resident_credentials.is_empty());
+        assert_eq!(authenticator.signature_count, initial_count);
+        assert_eq!(
+            authenticator.get_assertions(&get_assertion_parameters(Vec::new())),
+            Err(Error::NoCredentials),
+        );
+    }
+
+    #[test]
+    fn owns_only_own_credentials() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+
+        let own = PublicKeyCredentialDescriptor {
+            id: authenticator.resident_credentials[0].credential_id.clone(),
+            key_type: String::from("public-key"),
+        };
+        let foreign = PublicKeyCredentialDescriptor {
+            id: Bytes::try_from_slice(b"garbage").unwrap(),
+            key_type: String::from("public-key"),
+        };
+        assert!(authenticator.owns_credential(&own));
+        assert!(!authenticator.owns_credential(&foreign));
+    }
+
+    #[test]
+    fn unknown_credential_version() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+        let credential_id = authenticator.resident_credentials[0].credential_id.clone();
+        let mut credential_inner = InsecureRamAuthenticator::credential_inner(&credential_id).unwrap();
+        assert_eq!(credential_inner.version, CREDENTIAL_VERSION);
+
+        let descriptor = |id| PublicKeyCredentialDescriptor {
+            id,
+            key_type: String::from("public-key"),
+        };
+
+        // current version is fine
+        let mut allow_list = Vec::new();
+        allow_list.push(descriptor(credential_id)).unwrap();
+        assert!(authenticator.get_assertions(&get_assertion_parameters(allow_list)).is_ok());
+
+        // a future one is not
+        credential_inner.version += 1;
+        let mut allow_list = Vec::new();
+        allow_list.push(descriptor(Bytes::from_serialized(&credential_inner))).unwrap();
+        assert_eq!(
+            authenticator.get_assertions(&get_assertion_parameters(allow_list)).err(),
+            Some(Error::InvalidCredential),
+        );
+    }
+
+    #[test]
+    fn hmac_secret_creation() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let extensions = authenticator.get_info().unwrap().extensions.unwrap();
+        assert!(extensions.iter().any(|extension| extension.as_str() == "hmac-secret"));
+
+        let mut params = make_credential_parameters(true);
+        params.extensions = Some(MakeCredentialExtensions { hmac_secret: Some(true), ..Default::default() });
+        let attestation_object = authenticator.make_credential(&params).unwrap();
+        let auth_data = &attestation_object.auth_data;
+        // ED flag, and the output at the very end
+        assert_eq!(auth_data[32] & 0x80, 0x80);
+        assert!(auth_data.ends_with(&HMAC_SECRET_CREATED));
+
+        let credential_id = &authenticator.resident_credentials[0].credential_id;
+        let credential_inner = InsecureRamAuthenticator::credential_inner(credential_id).unwrap();
+        assert_eq!(credential_inner.hmac_secret, Some(true));
+        let cred_random = authenticator.cred_random(&credential_inner);
+        authenticator.reset().unwrap();
+        assert_ne!(authenticator.cred_random(&credential_inner), cred_random);
+
+        // not requested, no output
+        let attestation_object = authenticator.make_credential(&make_credential_parameters(false)).unwrap();
+        assert_eq!(attestation_object.auth_data[32] & 0x80, 0x00);
+    }
+
+    #[test]
+    fn uv_without_pin() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let mut params = make_credential_parameters(false);
+        params.options = Some(AuthenticatorOptions { rk: None, up: None, uv: Some(true) });
+        assert_eq!(authenticator.make_credential(&params).err(), Some(Error::PinNotSet));
+
+        let mut params = get_assertion_parameters(Vec::new());
+        params.options = Some(AuthenticatorOptions { rk: None, up: None, uv: Some(true) });
+        assert_eq!(authenticator.get_assertions(&params).err(), Some(Error::PinNotSet));
+    }
+
+    #[test]
+    fn uv_not_performed() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        authenticator.pin_set = true;
+        let mut params = make_credential_parameters(false);
+        params.options = Some(AuthenticatorOptions { rk: None, up: None, uv: Some(true) });
+        assert_eq!(authenticator.make_credential(&params).err(), Some(Error::PinRequired));
+
+        // no UV requested, no PIN needed
+        params.options = None;
+        assert!(authenticator.make_credential(&params).is_ok());
+    }
+
+    #[test]
+    fn oversized_exclude_list_is_rejected() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let mut params = make_credential_parameters(false);
+        params.exclude_list = Some(ExcludeList { limit_exceeded: true, ..Default::default() });
+        assert_eq!(authenticator.make_credential(&params).err(), Some(Error::LimitExceeded));
+
+        params.exclude_list = Some(ExcludeList::default());
+        assert!(authenticator.make_credential(&params).is_ok());
+    }
+
+    #[test]
+    fn excluded_credential_is_rejected() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        authenticator.make_credential(&make_credential_parameters(true)).unwrap();
+        let own = PublicKeyCredentialDescriptor {
+            id: authenticator.resident_credentials[0].credential_id.clone(),
+            key_type: String::from("public-key"),
+        };
+        let foreign = PublicKeyCredentialDescriptor {
+            id: Bytes::try_from_slice(b"garbage").unwrap(),
+            key_type: String::from("public-key"),
+        };
+
+        // someone else's credentials don't get in the way
+        let mut exclude_list = ExcludeList::default();
+        exclude_list.descriptors.push(foreign).unwrap();
+        let mut params = make_credential_parameters(false);
+        params.exclude_list = Some(exclude_list.clone());
+        assert!(authenticator.make_credential(&params).is_ok());
+
+        // ours do, wherever they are in the list
+        exclude_list.descriptors.push(own).unwrap();
+        params.exclude_list = Some(exclude_list);
+        assert_eq!(authenticator.make_credential(&params).err(), Some(Error::CredentialExcluded));
+    }
+
+    #[test]
+    fn unsupported_algorithms() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+
+        let mut params = make_credential_parameters(false);
+        params.pub_key_cred_params.clear();
+        assert_eq!(authenticator.make_credential(&params), Err(Error::UnsupportedAlgorithm));
+
+        // RS256 and PS256, neither of which we do
+        for alg in [-257, -37].iter() {
+            params.pub_key_cred_params.push(PublicKeyCredentialParameters {
+                alg: *alg,
+                key_type: String::from("public-key"),
+            }).unwrap();
+        }
+        assert_eq!(authenticator.make_credential(&params), Err(Error::UnsupportedAlgorithm));
+    }
+
+    #[test]
+    fn attestation_key_matches_certificate() {
+        // the subjectPublicKey BIT STRING, an uncompressed point 04 || x || y
+        const PUBLIC_KEY_OFFSET: usize = 257;
+        assert_eq!(&SOLO_HACKER_ATTN_CERT[PUBLIC_KEY_OFFSET..][..4], &[0x03, 0x42, 0x00, 0x04]);
+        let x = &SOLO_HACKER_ATTN_CERT[PUBLIC_KEY_OFFSET + 4..][..32];
+        let y = &SOLO_HACKER_ATTN_CERT[PUBLIC_KEY_OFFSET + 36..][..32];
+
+        let keypair = Keypair::P256(nisty::Keypair::try_from_bytes(&SOLO_HACKER_ATTN_KEY).unwrap());
+        match keypair.as_cose_public_key() {
+            CosePublicKey::P256Key(public_key) => {
+                assert_eq!(&public_key.x[..], x);
+                assert_eq!(&public_key.y[..], y);
+            },
+            _ => panic!("attestation key is not P-256"),
+        }
+    }
+
+    #[test]
+    fn rp_id_hash_is_sha256() {
+        // python3 -c "import hashlib; print(hashlib.sha256(b'yamnord.com').hexdigest())"
+        let expected = [
+            0x5f, 0x47, 0xc9, 0xe1, 0x0b, 0x91, 0xc7, 0x50, 0x2d, 0x58, 0x96, 0x12, 0x62, 0x19, 0xdd, 0x9b,
+            0x5d, 0xe3, 0x5a, 0x69, 0x93, 0x24, 0x2a, 0xa6, 0xd4, 0x77, 0x57, 0xa1, 0x6e, 0x7d, 0xd2, 0xcf,
+        ];
+        assert_eq!(&rp_id_hash("yamnord.com")[..], &expected[..]);
+    }
+
+    #[test]
+    fn signature_formats() {
+        let ed25519 = Keypair::Ed25519(salty::Keypair::from(&[37u8; 32]));
+        let p256 = Keypair::P256(nisty::Keypair::try_from_bytes(&SOLO_HACKER_ATTN_KEY).unwrap());
+        let digest = nisty::prehash(b"message");
+
+        // raw R || S, deterministic
+        let signature: [u8; 64] = ed25519.sign_fixed(b"message").unwrap();
+        assert_eq!(ed25519.sign_fixed(b"message").unwrap()[..], signature[..]);
+        assert_ne!(ed25519.sign_fixed(b"massage").unwrap()[..], signature[..]);
+
+        // SEQUENCE { INTEGER r, INTEGER s }, each up to 33 bytes. r and s
+        // vary in length, so do a few to hit leading zeros and high bits
+        for message in 0..32u8 {
+            let digest = nisty::prehash(&[message]);
+            let signature = p256.sign_der(&digest).unwrap();
+            assert!(signature.len() <= 72);
+
+            let mut fixed = [0u8; 64];
+            crate::derpy::der_to_fixed_ecdsa(&signature, &mut fixed).unwrap();
+            let der = crate::derpy::fixed_to_der_ecdsa(&fixed).unwrap();
+            assert_eq!(&der[..], &signature[..]);
+        }
+
+        // wrong algorithm for the key
+        assert_eq!(p256.sign_fixed(b"message"), Err(Error::Other));
+        assert_eq!(ed25519.sign_der(&digest), Err(Error::Other));
+    }
+
+    #[test]
+    fn tampered_seed_is_an_error() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let credential_inner = CredentialInner {
+            version: CREDENTIAL_VERSION,
+            user_id: Bytes::try_from_slice(b"nickray").unwrap(),
+            alg: CredAlgorithm::EdDsa,
+            seed: Bytes::try_from_slice(b"short").unwrap(),
+            hmac_secret: None,
+            cred_protect: None,
+        };
+        let mut allow_list = Vec::new();
+        allow_list.push(PublicKeyCredentialDescriptor {
+            id: Bytes::<consts::U128>::from_serialized(&credential_inner),
+            key_type: String::from("public-key"),
+        }).unwrap();
+
+        assert_eq!(
+            authenticator.get_assertions(&get_assertion_parameters(allow_list)).err(),
+            Some(Error::Other),
+        );
+    }
+
+    #[test]
+    fn credential_algorithm_round_trip() {
+        for (alg, cose) in [(CredAlgorithm::Es256, -7), (CredAlgorithm::EdDsa, -8)].iter() {
+            assert_eq!(i32::from(*alg), *cose);
+            assert_eq!(CredAlgorithm::try_from(*cose), Ok(*alg));
+
+            let credential_inner = CredentialInner {
+                version: CREDENTIAL_VERSION,
+                user_id: Bytes::try_from_slice(b"nickray").unwrap(),
+                alg: *alg,
+                seed: Bytes::try_from_slice(&[0x42; 32]).unwrap(),
+                hmac_secret: None,
+                cred_protect: None,
+            };
+            let credential_id = Bytes::<consts::U128>::from_serialized(&credential_inner);
+            let deserialized = InsecureRamAuthenticator::credential_inner(&credential_id).unwrap();
+            assert_eq!(deserialized.alg, *alg);
+
+            match (alg, Keypair::from_credential(&deserialized).unwrap()) {
+                (CredAlgorithm::Es256, Keypair::P256(_)) => {},
+                (CredAlgorithm::EdDsa, Keypair::Ed25519(_)) => {},
+                _ => panic!("wrong keypair type for {:?}", alg),
+            }
+        }
+        assert_eq!(CredAlgorithm::try_from(-257), Err(()));
+    }
+
+    #[test]
+    fn credential_ids_fit_advertised_length() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let max_cred_id_length = authenticator.get_info().unwrap().max_cred_id_length.unwrap();
+        assert_eq!(max_cred_id_length, MAX_CREDENTIAL_ID_LENGTH);
+
+        let mut longest = 0;
+        for user_id_length in 0..=64 {
+            for alg in [CredAlgorithm::Es256, CredAlgorithm::EdDsa].iter() {
+                for hmac_secret in [None, Some(true)].iter() {
+                    let credential_inner = CredentialInner {
+                        version: CREDENTIAL_VERSION,
+                        user_id: Bytes::try_from_slice(&[0xff; 64][..user_id_length]).unwrap(),
+                        alg: *alg,
+                        seed: Bytes::try_from_slice(&[0xff; 32]).unwrap(),
+                        hmac_secret: *hmac_secret,
+                        cred_protect: Some(3),
+                    };
+                    let credential_id = Bytes::<consts::U128>::from_serialized(&credential_inner);
+                    assert!(credential_id.len() <= max_cred_id_length);
+                    longest = longest.max(credential_id.len());
+                }
+            }
+        }
+        // the bound is tight
+        assert_eq!(longest, max_cred_id_length);
+    }
+
+    #[test]
+    fn cred_protect_hides_credentials_without_uv() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let extensions = authenticator.get_info().unwrap().extensions.unwrap();
+        assert!(extensions.iter().any(|extension| extension.as_str() == "credProtect"));
+
+        let mut params = make_credential_parameters(true);
+        params.extensions = Some(MakeCredentialExtensions { cred_protect: Some(3), ..Default::default() });
+        let attestation_object = authenticator.make_credential(&params).unwrap();
+        // {"credProtect": 3}
+        assert!(attestation_object.auth_data.ends_with(b"\xa1\x6bcredProtect\x03"));
+        let credential_id = authenticator.resident_credentials[0].credential_id.clone();
+
+        // silent and without UV, neither discoverable nor via allow list
+        let mut params = get_assertion_parameters(Vec::new());
+        params.options = Some(AuthenticatorOptions { rk: None, up: Some(false), uv: None });
+        assert_eq!(authenticator.get_assertions(&params).err(), Some(Error::NoCredentials));
+        params.allow_list.push(PublicKeyCredentialDescriptor {
+            id: credential_id,
+            key_type: String::from("public-key"),
+        }).unwrap();
+        assert_eq!(authenticator.get_assertions(&params).err(), Some(Error::NoCredentials));
+
+        // we can't verify users ourselves, but once UV was performed it's usable
+        assert!(cred_protect_allows(Some(3), true, false));
+        assert!(cred_protect_allows(Some(3), true, true));
+        // level 2 only hides it from discovery
+        assert!(!cred_protect_allows(Some(2), false, false));
+        assert!(cred_protect_allows(Some(2), false, true));
+        assert!(cred_protect_allows(None, false, false));
+    }
+
+    #[test]
+    fn transports_in_get_info() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let transports = authenticator.get_info().unwrap().transports.unwrap();
+        assert_eq!(&transports[..], &[String::<consts::U8>::from("usb")]);
+
+        let mut authenticator = InsecureRamAuthenticator::default()
+            .with_transports(Transports::USB | Transports::NFC);
+        authenticator.reset().unwrap();
+        let transports = authenticator.get_info().unwrap().transports.unwrap();
+        assert_eq!(&transports[..], &[String::<consts::U8>::from("usb"), String::from("nfc")]);
+    }
+
+    #[test]
+    fn attestation_chain_in_x5c() {
+        const INTERMEDIATE_CERT: &[u8] = b"intermediate CA";
+        let mut authenticator = InsecureRamAuthenticator::default()
+            .with_attestation_chain(&[&SOLO_HACKER_ATTN_CERT, INTERMEDIATE_CERT]);
+        let attestation_object = authenticator.make_credential(&make_credential_parameters(false)).unwrap();
+        let statement = match attestation_object.att_stmt {
+            AttestationStatement::Packed(statement) => statement,
+            _ => panic!("not a packed attestation"),
+        };
+
+        let serialized: Bytes<consts::U2048> = try_from_serialized(&statement).unwrap();
+        // "x5c": [bstr(511), bstr(15)]
+        let x5c: Bytes<consts::U2048> = try_from_iter(b"\x63x5c\x82\x59\x01\xff".iter()
+            .chain(SOLO_HACKER_ATTN_CERT.iter())
+            .chain(b"\x4f".iter())
+            .chain(INTERMEDIATE_CERT.iter())
+            .copied()
+        ).unwrap();
+        assert!(serialized.ends_with(&x5c));
+    }
+
+    #[test]
+    fn assertion_signature_verifies() {
+        use authenticator::{dispatch, Ctap2Request, Ctap2Response};
+        let mut authenticator = InsecureRamAuthenticator::default();
+
+        // Ed25519, so the signature is over the message itself
+        let mut params = make_credential_parameters(false);
+        params.pub_key_cred_params[0].alg = -8;
+        let attestation_object = match dispatch(&mut authenticator, &Ctap2Request::MakeCredential(params)) {
+            Ok(Ctap2Response::MakeCredential(attestation_object)) => attestation_object,
+            _ => panic!("no attestation object"),
+        };
+        // without extensions, the COSE key's x coordinate ends the authenticator data
+        let auth_data = &attestation_object.auth_data;
+        let public_key = &auth_data[auth_data.len() - 32..];
+
+        // there's only the credential ID to go by
+        // after RP ID hash, flags, counter and AAGUID
+        let credential_id_length = u16::from_be_bytes([auth_data[53], auth_data[54]]) as usize;
+        let credential_id = Bytes::try_from_slice(&auth_data[55..][..credential_id_length]).unwrap();
+        let mut allow_list = Vec::new();
+        allow_list.push(PublicKeyCredentialDescriptor {
+            id: credential_id,
+            key_type: String::from("public-key"),
+        }).unwrap();
+        let params = get_assertion_parameters(allow_list);
+        let responses = match dispatch(&mut authenticator, &Ctap2Request::GetAssertions(params.clone())) {
+            Ok(Ctap2Response::GetAssertions(responses)) => responses,
+            _ => panic!("no assertion"),
+        };
+
+        // the one allowed credential, which isn't echoed
+        assert_eq!(responses[0].credential, None);
+        let credential_inner = InsecureRamAuthenticator::credential_inner(&params.allow_list[0].id).unwrap();
+        let keypair = Keypair::from_credential(&credential_inner).unwrap();
+        match keypair.as_cose_public_key() {
+            CosePublicKey::Ed25519Key(key) => assert_eq!(&key.x[..], public_key),
+            _ => panic!("not an Ed25519 credential"),
+        }
+        let keypair = match keypair {
+            Keypair::Ed25519(keypair) => keypair,
+            _ => unreachable!(),
+        };
+
+        let mut message = Vec::<u8, consts::U256>::new();
+        message.extend_from_slice(&responses[0].auth_data).unwrap();
+        message.extend_from_slice(&params.client_data_hash).unwrap();
+        let signature: [u8; 64] = responses[0].signature.as_ref().try_into().unwrap();
+        assert!(keypair.public.verify(&message, &salty::Signature::from(&signature)).is_ok());
+
+        // and not for another challenge
+        message[0] ^= 1;
+        assert!(keypair.public.verify(&message, &salty::Signature::from(&signature)).is_err());
+    }
+
+    #[test]
+    fn make_credential_response_layout() {
+        let mut authenticator = InsecureRamAuthenticator::default();
+        let attestation_object = authenticator.make_credential(&make_credential_parameters(false)).unwrap();
+        let mut buffer = [0u8; 1024];
+        let size = serialize_make_credential_response(&attestation_object, &mut buffer).unwrap();
+
+        // what python-fido2 parses: status, then {1: fmt, 2: authData, 3: attStmt}
+        let auth_data: Bytes<consts::U1024> = try_from_serialized(&attestation_object.auth_data).unwrap();
+        let att_stmt: Bytes<consts::U1024> = try_from_serialized(&attestation_object.att_stmt).unwrap();
+        let expected: Bytes<consts::U2048> = try_from_iter(b"\x00\xa3\x01\x66packed\x02".iter()
+            .chain(auth_data.iter())
+            .chain(b"\x03".iter())
+            .chain(att_stmt.iter())
+            .copied()
+        ).unwrap();
+        assert_eq!(&buffer[..size], &expected[..]);
+        // {"alg": -7, "sig": ..., "x5c": [...]}
+        assert!(att_stmt.starts_with(b"\xa3\x63alg\x26\x63sig"));
+
+        // no room, not even for the status
+        assert_eq!(serialize_make_credential_response(&attestation_object, &mut buffer[..100]), Err(Error::Other));
+        assert_eq!(serialize_make_credential_response(&attestation_object, &mut []), Err(Error::Other));
+    }
+
+    #[test]
+    fn bytes_from_iterator() {
+        let bytes: Bytes<consts::U4> = try_from_iter((1..=4).map(|i| i * 0x11)).unwrap();
+        assert_eq!(bytes.as_ref(), &[0x11, 0x22, 0x33, 0x44]);
+        let empty: Bytes<consts::U4> = try_from_iter(core::iter::empty()).unwrap();
+        assert!(empty.is_empty());
+
+        let too_many: Result<Bytes<consts::U4>> = try_from_iter(0..5);
+        assert_eq!(too_many, Err(Error::Other));
+    }
+
+    #[test]
+    fn serialization_overflow_is_an_error() {
+        let credential_inner = CredentialInner {
+            version: CREDENTIAL_VERSION,
+            user_id: Bytes::try_from_slice(b"nickray").unwrap(),
+            alg: CredAlgorithm::Es256,
+            seed: Bytes::try_from_slice(&[0x42; 32]).unwrap(),
+            hmac_secret: None,
+            cred_protect: None,
+        };
+        let credential_id: Bytes<consts::U128> = try_from_serialized(&credential_inner).unwrap();
+        assert_eq!(credential_id, Bytes::<consts::U128>::from_serialized(&credential_inner));
+
+        // no panic, and no truncated output
+        let too_small: Result<Bytes<consts::U32>> = try_from_serialized(&credential_inner);
+        assert_eq!(too_small, Err(Error::Other));
+    }
+}
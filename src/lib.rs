@@ -6,33 +6,36 @@ usbd-ctaphid
 See "proposed standard":
 https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#usb
 
+`CtapHid` passes requests on to the authenticator, and takes its responses,
+via ctap-types' RPC (`ctap_types::rpc::TransportEndpoint`). That is the
+public interface, see the `prelude`.
+
+The `authenticator` and `types` modules are an older API of this crate's own,
+only compiled with the `legacy-api` feature. They can't be plugged into
+`CtapHid`, an app using them has to translate from and to ctap-types.
 */
 
-// use heapless_bytes as bytes;
+#[cfg(feature = "legacy-api")]
+use heapless_bytes as bytes;
+
+// first, so the macros are visible in all other modules
+#[macro_use]
+mod logging;
 
-// pub mod authenticator;
+#[cfg(feature = "legacy-api")]
+pub mod authenticator;
 
+pub mod cbor;
 pub mod constants;
 pub mod class;
 pub use class::CtapHid;
+#[cfg(feature = "legacy-api")]
+pub mod derpy;
 pub mod pipe;
+pub mod prelude;
 
-// #[cfg(feature = "insecure-ram-authenticator")]
-// pub mod insecure;
-
-// #[cfg(not(feature = "logging"))]
-// mod logging;
-
-// // TODO: not really sure what's going on here...
-// // Goal: have `logging` feature, that can be completely turned off
-
-// #[cfg(feature = "logging")]
-// // use ufmt::UnstableDoAsFormatter;
-
-// #[cfg(feature = "logging")]
-// use funnel::debug;
-
-// #[cfg(feature = "logging")]
-// use funnel::error;
+#[cfg(feature = "insecure-ram-authenticator")]
+pub mod insecure;
 
-// pub mod types;
+#[cfg(feature = "legacy-api")]
+pub mod types;
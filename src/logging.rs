@@ -1,12 +1,60 @@
-// #[cfg(not(feature = "logging"))]
-#[macro_export]
-macro_rules! debug {
-    ($($tt:tt)*) => {{ }}
+//! Leveled logging via `funnel`, selected at compile time.
+//!
+//! Each `log-<level>` feature enables its level and all more severe ones,
+//! e.g. `log-warn` keeps `error!` and `warn!`. Disabled levels expand to
+//! `Ok(())` without evaluating their arguments, so call sites can keep
+//! using `info!(...).ok()` regardless. Values only computed for a log
+//! message are then unused, so their bindings start with an underscore.
+//!
+//! Per-packet chatter belongs in `trace!`, per-request information in `info!`.
+
+macro_rules! log_at_level {
+    ($feature:literal, $funnel:ident, $($tt:tt)*) => {{
+        #[cfg(feature = $feature)]
+        let result = funnel::$funnel!($($tt)*);
+        #[cfg(not(feature = $feature))]
+        let result: core::result::Result<(), ()> = Ok(());
+        result
+    }}
 }
 
-// #[cfg(not(feature = "logging"))]
-#[macro_export]
+#[allow(unused_macros)]
 macro_rules! error {
-    ($($tt:tt)*) => {{ }}
+    ($($tt:tt)*) => { log_at_level!("log-error", error, $($tt)*) }
+}
+
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($tt:tt)*) => { log_at_level!("log-warn", warn, $($tt)*) }
+}
+
+#[allow(unused_macros)]
+macro_rules! info {
+    ($($tt:tt)*) => { log_at_level!("log-info", info, $($tt)*) }
 }
 
+#[allow(unused_macros)]
+macro_rules! debug {
+    ($($tt:tt)*) => { log_at_level!("log-debug", debug, $($tt)*) }
+}
+
+#[allow(unused_macros)]
+macro_rules! trace {
+    ($($tt:tt)*) => { log_at_level!("log-trace", trace, $($tt)*) }
+}
+
+#[cfg(test)]
+mod tests {
+    // runs with the default features, which log nothing
+    #[test]
+    #[cfg(not(feature = "log-info"))]
+    fn disabled_levels_expand_to_nothing() {
+        // arguments are not even evaluated
+        let evaluated = core::cell::Cell::new(false);
+        info!("{}", { evaluated.set(true); 1 }).ok();
+        debug!("{}", { evaluated.set(true); 2 }).ok();
+        trace!("{}", { evaluated.set(true); 3 }).ok();
+        assert!(!evaluated.get());
+        assert_eq!(info!("anything"), Ok(()));
+    }
+}
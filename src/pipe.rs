@@ -7,13 +7,13 @@ an *initialization* packet, followed by zero or more *continuation* packets.
 
 In the case of multiple clients, the first to get through its initialization
 packet in device idle state locks the device for other channels (they will
-receive busy errors).
+receive busy errors), until its response is sent.
 
 No state is maintained between transactions.
 */
 
-use core::convert::TryInto;
 use core::convert::TryFrom;
+use core::ops::Range;
 
 use ctap_types::{
     authenticator::Error as AuthenticatorError,
@@ -23,8 +23,6 @@ use ctap_types::{
 };
 
 // use serde::Serialize;
-#[cfg(feature = "semihosting")]
-use cortex_m_semihosting::hprintln;
 use usb_device::{
     bus::{UsbBus},
     endpoint::{EndpointAddress, EndpointIn, EndpointOut},
@@ -32,18 +30,23 @@ use usb_device::{
     // Result as UsbResult,
 };
 
-#[cfg(feature = "logging")]
-use funnel::{debug, info};
-
 use crate::{
     constants::{
         // 7609
         MESSAGE_SIZE,
+        KEEPALIVE_INTERVAL_MILLISECONDS,
+        MESSAGE_TIMEOUT_MILLISECONDS,
+        OPERATION_TIMEOUT_MILLISECONDS,
+        RESET_WINDOW_MILLISECONDS,
+        VENDOR_STREAM_TIMEOUT_MILLISECONDS,
         // 64
         PACKET_SIZE,
     },
 };
 
+#[cfg(feature = "transaction-history")]
+use crate::constants::TRANSACTION_HISTORY_LENGTH;
+
 /// The actual payload of given length is dealt with separately
 #[derive(Copy,Clone,Debug,Eq,PartialEq)]
 pub struct Request {
@@ -61,7 +64,9 @@ pub struct Response {
 }
 
 impl Response {
+    /// `size` must fit the 16-bit length of CTAPHID, see `try_from_request_and_size`.
     pub fn from_request_and_size(request: Request, size: usize) -> Self {
+        debug_assert!(size <= u16::max_value() as usize, "response of {} bytes", size);
         Self {
             channel: request.channel,
             command: request.command,
@@ -69,6 +74,16 @@ impl Response {
         }
     }
 
+    /// `None` if `size` doesn't fit the 16-bit length of CTAPHID.
+    pub fn try_from_request_and_size(request: Request, size: usize) -> Option<Self> {
+        let length = u16::try_from(size).ok()?;
+        Some(Self {
+            channel: request.channel,
+            command: request.command,
+            length,
+        })
+    }
+
 }
 
 #[derive(Copy,Clone,Debug,Eq,PartialEq)]
@@ -94,6 +109,37 @@ impl MessageState {
         self.next_sequence += 1;
         self.transmitted += PACKET_SIZE - 5;
     }
+
+    /// Store a continuation packet's payload in `message`, which is sized
+    /// to the declared length. Returns true once the message is complete.
+    ///
+    /// Nothing is copied if the message is already complete, even though
+    /// that can't happen for lengths the init packet leaves unfinished.
+    pub fn receive_continuation(&mut self, message: &mut [u8], payload: &[u8]) -> bool {
+        let remaining = message.len() - self.transmitted;
+        if remaining > PACKET_SIZE - 5 {
+            message[self.transmitted..][..PACKET_SIZE - 5].copy_from_slice(payload);
+            self.absorb_packet();
+            false
+        } else {
+            message[self.transmitted..].copy_from_slice(&payload[..remaining]);
+            true
+        }
+    }
+
+    /// Build the next continuation packet of `message`, and whether it's the last one.
+    pub fn continuation_packet(&self, channel: u32, message: &[u8]) -> ([u8; PACKET_SIZE], bool) {
+        // zeros leftover bytes
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[..4].copy_from_slice(&channel.to_be_bytes());
+        packet[4] = self.next_sequence;
+
+        let remaining = message.len() - self.transmitted;
+        let last_packet = 5 + remaining <= PACKET_SIZE;
+        let chunk = if last_packet { remaining } else { PACKET_SIZE - 5 };
+        packet[5..][..chunk].copy_from_slice(&message[self.transmitted..][..chunk]);
+        (packet, last_packet)
+    }
 }
 
 #[derive(Copy,Clone,Debug,Eq,PartialEq)]
@@ -188,6 +234,471 @@ impl Into<u8> for Command {
     }
 }
 
+/// CTAPHID transport level errors, sent as payload of `Command::Error`.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+#[repr(u8)]
+pub enum CtapHidError {
+    InvalidCommand = 0x01,
+    InvalidParameter = 0x02,
+    InvalidLength = 0x03,
+    InvalidSequence = 0x04,
+    MessageTimeout = 0x05,
+    ChannelBusy = 0x06,
+    LockRequired = 0x0a,
+    InvalidChannel = 0x0b,
+    Other = 0x7f,
+}
+
+/// CTAP2 status codes, the first byte of every `Command::Cbor` response.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+#[repr(u8)]
+pub enum CtapStatus {
+    Success = 0x00,
+    InvalidCommand = 0x01,
+    InvalidParameter = 0x02,
+    InvalidLength = 0x03,
+    InvalidSeq = 0x04,
+    Timeout = 0x05,
+    ChannelBusy = 0x06,
+    LockRequired = 0x0A,
+    InvalidChannel = 0x0B,
+    CborUnexpectedType = 0x11,
+    InvalidCbor = 0x12,
+    MissingParameter = 0x14,
+    LimitExceeded = 0x15,
+    UnsupportedExtension = 0x16,
+    CredentialExcluded = 0x19,
+    Processing = 0x21,
+    InvalidCredential = 0x22,
+    UserActionPending = 0x23,
+    OperationPending = 0x24,
+    NoOperations = 0x25,
+    UnsupportedAlgorithm = 0x26,
+    OperationDenied = 0x27,
+    KeyStoreFull = 0x28,
+    NotBusy = 0x29,
+    NoOperationPending = 0x2A,
+    UnsupportedOption = 0x2B,
+    InvalidOption = 0x2C,
+    KeepaliveCancel = 0x2D,
+    NoCredentials = 0x2E,
+    UserActionTimeout = 0x2F,
+    NotAllowed = 0x30,
+    PinInvalid = 0x31,
+    PinBlocked = 0x32,
+    PinAuthInvalid = 0x33,
+    PinAuthBlocked = 0x34,
+    PinNotSet = 0x35,
+    PinRequired = 0x36,
+    PinPolicyViolation = 0x37,
+    PinTokenExpired = 0x38,
+    RequestTooLarge = 0x39,
+    ActionTimeout = 0x3A,
+    UpRequired = 0x3B,
+    Other = 0x7F,
+    SpecLast = 0xDF,
+    ExtensionFirst = 0xE0,
+    ExtensionLast = 0xEF,
+    VendorFirst = 0xF0,
+    VendorLast = 0xFF,
+}
+
+impl Into<u8> for CtapStatus {
+    fn into(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Status byte of CTAPHID_KEEPALIVE packets.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+#[repr(u8)]
+pub enum KeepAliveStatus {
+    /// the authenticator is still processing the request
+    Processing = 1,
+    /// the authenticator is waiting for user presence
+    UpNeeded = 2,
+}
+
+impl Into<u8> for KeepAliveStatus {
+    fn into(self) -> u8 {
+        self as u8
+    }
+}
+
+// initialization packet with a payload that fits
+fn build_single_packet(channel: u32, command: Command, payload: &[u8]) -> [u8; PACKET_SIZE] {
+    debug_assert!(payload.len() <= PACKET_SIZE - 7);
+    build_init_packet(channel, command, payload)
+}
+
+// initialization packet declaring the full message length,
+// carrying as much of the message as fits
+fn build_init_packet(channel: u32, command: Command, message: &[u8]) -> [u8; PACKET_SIZE] {
+    // zeros leftover bytes
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[..4].copy_from_slice(&channel.to_be_bytes());
+    packet[4] = command.into_u8() | 0x80;
+    packet[5..7].copy_from_slice(&(message.len() as u16).to_be_bytes());
+    let chunk = message.len().min(PACKET_SIZE - 7);
+    packet[7..][..chunk].copy_from_slice(&message[..chunk]);
+    packet
+}
+
+/// All packets of a message, for callers moving packets themselves
+/// instead of going through the USB endpoints.
+///
+/// `more_pending` tells whether there are packets left to take.
+pub struct MessagePackets<'a> {
+    channel: u32,
+    command: Command,
+    message: &'a [u8],
+    // None until the initialization packet is taken
+    state: Option<MessageState>,
+    done: bool,
+}
+
+impl<'a> MessagePackets<'a> {
+    pub fn new(channel: u32, command: Command, message: &'a [u8]) -> Self {
+        debug_assert!(message.len() <= MESSAGE_SIZE);
+        Self { channel, command, message, state: None, done: false }
+    }
+
+    pub fn more_pending(&self) -> bool {
+        !self.done
+    }
+}
+
+impl<'a> Iterator for MessagePackets<'a> {
+    type Item = [u8; PACKET_SIZE];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.state {
+            None => {
+                let packet = build_init_packet(self.channel, self.command, self.message);
+                if self.message.len() <= PACKET_SIZE - 7 {
+                    self.done = true;
+                } else {
+                    self.state = Some(MessageState::default());
+                }
+                Some(packet)
+            },
+            Some(mut message_state) => {
+                let (packet, last_packet) = message_state.continuation_packet(self.channel, self.message);
+                if last_packet {
+                    self.done = true;
+                } else {
+                    message_state.absorb_packet();
+                    self.state = Some(message_state);
+                }
+                Some(packet)
+            },
+        }
+    }
+}
+
+/// A CTAPHID_KEEPALIVE packet, which has a single status byte as payload.
+pub fn build_keepalive(channel: u32, status: KeepAliveStatus) -> [u8; PACKET_SIZE] {
+    build_single_packet(channel, Command::KeepAlive, &[status.into()])
+}
+
+/// What to do with initialization packets declaring a length above the
+/// message buffer's size (`MESSAGE_SIZE` by default).
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub enum OversizePolicy {
+    /// Answer with ERR_INVALID_LEN, as the spec demands.
+    Reject,
+    /// Clamp to the buffer size and process the truncated message,
+    /// the excess continuation packets are then dropped as spurious.
+    ///
+    /// Only meant to tolerate non-conforming hosts during development:
+    /// the authenticator ends up acting on a message the host did not
+    /// send as such, which must never be relied upon for anything
+    /// security relevant.
+    Clamp,
+}
+
+impl Default for OversizePolicy {
+    fn default() -> Self {
+        OversizePolicy::Reject
+    }
+}
+
+/// Accessors for the fields of a raw CTAPHID packet.
+///
+/// Initialization packets are laid out as
+/// `channel (4, BE) | 0x80 | command (1) | length (2, BE) | payload (57)`,
+/// continuation packets as `channel (4, BE) | sequence (1) | payload (59)`.
+#[derive(Copy,Clone,Debug)]
+pub struct PacketView<'a>(&'a [u8; PACKET_SIZE]);
+
+impl<'a> PacketView<'a> {
+    pub fn new(packet: &'a [u8; PACKET_SIZE]) -> Self {
+        Self(packet)
+    }
+
+    pub fn channel(&self) -> u32 {
+        let packet = self.0;
+        u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]])
+    }
+
+    /// command (with high bit) for initialization packets,
+    /// sequence number for continuation packets
+    pub fn command_or_seq(&self) -> u8 {
+        self.0[4]
+    }
+
+    pub fn is_init(&self) -> bool {
+        self.command_or_seq() & 0x80 != 0
+    }
+
+    /// only meaningful for initialization packets
+    pub fn command(&self) -> u8 {
+        self.command_or_seq() & !0x80
+    }
+
+    /// only meaningful for continuation packets
+    pub fn sequence(&self) -> u8 {
+        self.command_or_seq()
+    }
+
+    /// only meaningful for initialization packets
+    pub fn declared_length(&self) -> u16 {
+        u16::from_be_bytes([self.0[5], self.0[6]])
+    }
+
+    pub fn init_payload(&self) -> &'a [u8] {
+        &self.0[7..]
+    }
+
+    pub fn cont_payload(&self) -> &'a [u8] {
+        &self.0[5..]
+    }
+}
+
+/// Capability flags reported in the CTAPHID_INIT response.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub struct Capabilities(pub u8);
+
+impl Capabilities {
+    /// implements WINK
+    pub const WINK: Self = Capabilities(0x01);
+    /// implements LOCK
+    pub const LOCK: Self = Capabilities(0x02);
+    /// implements CBOR
+    pub const CBOR: Self = Capabilities(0x04);
+    /// does *not* implement MSG
+    pub const NMSG: Self = Capabilities(0x08);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+// payload of the response to an INIT on the broadcast channel, returns its size
+fn write_init_response(buffer: &mut [u8], nonce: [u8; 8], channel: u32, capabilities: Capabilities) -> usize {
+    // echoed, so the host can tell the response to its request apart
+    buffer[..8].copy_from_slice(&nonce);
+    buffer[8..12].copy_from_slice(&channel.to_be_bytes());
+    // CTAPHID protocol version
+    buffer[12] = 2;
+    // major device version number
+    buffer[13] = 0;
+    // minor device version number
+    buffer[14] = 0;
+    // build device version number
+    buffer[15] = 0;
+    buffer[16] = capabilities.0;
+    17
+}
+
+// NMSG unless the app answers CTAPHID_MSG, whatever else it set
+fn advertised_capabilities(capabilities: Capabilities, ctap1: bool) -> Capabilities {
+    if ctap1 {
+        Capabilities(capabilities.0 & !Capabilities::NMSG.0)
+    } else {
+        capabilities | Capabilities::NMSG
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::WINK | Self::LOCK | Self::CBOR
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// Suggested visual indication for CTAPHID_WINK.
+///
+/// The WINK command itself carries no payload, so the pipe always
+/// hands out the default, but this way the LED driver knows how long
+/// (and how fast) to blink.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub struct WinkPattern {
+    /// total duration of the indication
+    pub duration_ms: u32,
+    /// one on/off cycle of the LED
+    pub period_ms: u32,
+}
+
+impl Default for WinkPattern {
+    fn default() -> Self {
+        Self {
+            duration_ms: 1_000,
+            period_ms: 250,
+        }
+    }
+}
+
+/// What became of a request, as remembered with the `transaction-history` feature.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub struct TransactionSummary {
+    pub channel: u32,
+    /// the response's command, `Command::Error` for CTAPHID errors
+    pub command: Command,
+    /// CTAPHID error or CTAP2 status code, zero otherwise
+    pub status: u8,
+    /// of the response
+    pub length: u16,
+}
+
+/// The last `TRANSACTION_HISTORY_LENGTH` transactions, oldest first.
+#[cfg(feature = "transaction-history")]
+#[derive(Copy,Clone,Debug)]
+pub struct TransactionHistory {
+    transactions: [TransactionSummary; TRANSACTION_HISTORY_LENGTH],
+    len: usize,
+}
+
+#[cfg(feature = "transaction-history")]
+impl Default for TransactionHistory {
+    fn default() -> Self {
+        let empty = TransactionSummary { channel: 0, command: Command::Error, status: 0, length: 0 };
+        Self {
+            transactions: [empty; TRANSACTION_HISTORY_LENGTH],
+            len: 0,
+        }
+    }
+}
+
+#[cfg(feature = "transaction-history")]
+impl TransactionHistory {
+    /// Remember `transaction`, forgetting the oldest one if full.
+    pub fn push(&mut self, transaction: TransactionSummary) {
+        if self.len == TRANSACTION_HISTORY_LENGTH {
+            // few and small, shifting keeps them contiguous and in order
+            self.transactions.rotate_left(1);
+            self.len -= 1;
+        }
+        self.transactions[self.len] = transaction;
+        self.len += 1;
+    }
+
+    pub fn as_slice(&self) -> &[TransactionSummary] {
+        &self.transactions[..self.len]
+    }
+}
+
+/// Only lets one channel at a time stream vendor messages (e.g., a
+/// firmware update), so partial transfers can't interleave.
+///
+/// A stream ends when released, or when its channel has been silent for
+/// `VENDOR_STREAM_TIMEOUT_MILLISECONDS`, so a stalled host can't keep
+/// everyone else out.
+#[derive(Copy,Clone,Debug,Default,Eq,PartialEq)]
+pub struct StreamLock {
+    // channel, and when we last heard from it
+    owner: Option<(u32, u32)>,
+}
+
+impl StreamLock {
+    /// Start or continue the stream on `channel`, false if another one is active.
+    pub fn try_acquire(&mut self, channel: u32, now_ms: u32) -> bool {
+        match self.owner {
+            Some((owner, _)) if owner != channel && self.is_active(now_ms) => false,
+            _ => {
+                self.owner = Some((channel, now_ms));
+                true
+            }
+        }
+    }
+
+    /// End the stream, if `channel` owns it.
+    pub fn release(&mut self, channel: u32) {
+        if let Some((owner, _)) = self.owner {
+            if owner == channel {
+                self.owner = None;
+            }
+        }
+    }
+
+    pub fn is_active(&self, now_ms: u32) -> bool {
+        match self.owner {
+            Some((_, last_ms)) => now_ms.wrapping_sub(last_ms) < VENDOR_STREAM_TIMEOUT_MILLISECONDS,
+            None => false,
+        }
+    }
+}
+
+/// CTAPHID_LOCK: one channel gets exclusive access for up to 10 seconds,
+/// the others are answered with ERR_CHANNEL_BUSY meanwhile.
+///
+/// Expiry relies on the time passed to `Pipe::tick`.
+#[derive(Copy,Clone,Debug,Default,Eq,PartialEq)]
+pub struct ChannelLock {
+    // channel, and when the lock expires
+    owner: Option<(u32, u32)>,
+}
+
+impl ChannelLock {
+    /// Lock (or re-lock) for `seconds`, zero releases the lock.
+    pub fn lock(&mut self, channel: u32, seconds: u8, now_ms: u32) {
+        self.owner = match seconds {
+            0 => None,
+            seconds => Some((channel, now_ms.wrapping_add(seconds as u32 * 1_000))),
+        };
+    }
+
+    pub fn owner(&self, now_ms: u32) -> Option<u32> {
+        match self.owner {
+            Some((channel, _)) if self.remaining_ms(now_ms).is_some() => Some(channel),
+            _ => None,
+        }
+    }
+
+    pub fn remaining_ms(&self, now_ms: u32) -> Option<u32> {
+        match self.owner {
+            // wrapping arithmetic, like everywhere else we deal with time
+            Some((_, expires_ms)) if (expires_ms.wrapping_sub(now_ms) as i32) > 0 =>
+                Some(expires_ms.wrapping_sub(now_ms)),
+            _ => None,
+        }
+    }
+
+    /// whether requests on `channel` are locked out
+    pub fn blocks(&self, channel: u32, now_ms: u32) -> bool {
+        match self.owner(now_ms) {
+            Some(owner) => owner != channel,
+            None => false,
+        }
+    }
+}
+
+// CTAPHID_LOCK's payload, the lock time in seconds, at most 10
+fn lock_seconds(payload: &[u8]) -> Result<u8, CtapHidError> {
+    match payload {
+        &[seconds] if seconds <= 10 => Ok(seconds),
+        _ => Err(CtapHidError::InvalidParameter),
+    }
+}
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 #[allow(unused)]
@@ -210,7 +721,10 @@ pub enum State {
     Sending((Response, MessageState)),
 }
 
-pub struct Pipe<'alloc, Bus: UsbBus> {
+/// `N` is the size of the message buffer, and so the largest message
+/// accepted. The spec's `MESSAGE_SIZE` costs 7609 bytes of RAM, devices
+/// that only ever see small CBOR can get away with e.g. 1200.
+pub struct Pipe<'alloc, Bus: UsbBus, const N: usize = MESSAGE_SIZE> {
 
     read_endpoint: EndpointOut<'alloc, Bus>,
     write_endpoint: EndpointIn<'alloc, Bus>,
@@ -219,15 +733,57 @@ pub struct Pipe<'alloc, Bus: UsbBus> {
     pub rpc: TransportEndpoint,
 
     // shared between requests and responses, due to size
-    buffer: [u8; MESSAGE_SIZE],
+    buffer: [u8; N],
 
     // we assign channel IDs one by one, this is the one last assigned
     // TODO: move into "app"
     last_channel: u32,
-
+    // channels up to this one were handed out via `take_allocated_channels`
+    reported_channel: u32,
+
+    // reported on each CTAPHID_INIT, the app may change them at runtime
+    capabilities: Capabilities,
+
+    oversize_policy: OversizePolicy,
+
+    // whether the authenticator handles vendor operations at all
+    vendor_operations: bool,
+    // whether CTAPHID_MSG is passed on to the app at all
+    ctap1: bool,
+    // how long after power-up authenticatorReset is passed on, if limited
+    reset_window_ms: Option<u32>,
+    // vendor operations are streams, one channel at a time
+    vendor_stream: StreamLock,
+    // CTAPHID_LOCK
+    lock: ChannelLock,
+
+    // set on CTAPHID_WINK, until the app picks it up
+    wink: Option<WinkPattern>,
+    // set when a request involving the user is passed on, until the app picks it up
+    user_interaction: Option<Operation>,
+
+    // we have no clock of our own, the app tells us the time via `tick`
+    now_ms: u32,
+    // when the authenticator got the request we're waiting on
+    operation_started_ms: u32,
+    // when the message being received or sent last moved along
+    last_progress_ms: u32,
+    // requests we gave up on, whose late responses must be discarded.
+    // several may be outstanding, if the host keeps cancelling.
+    abandoned_responses: u32,
+    // keepalives while waiting on the authenticator
+    keepalive_status: KeepAliveStatus,
+    last_keepalive_ms: u32,
+    // get-info can't fail in interesting ways, see `map_authenticator_error`
+    waiting_on_get_info: bool,
+    // CTAPHID_CANCEL arrived while receiving the request
+    cancelled: bool,
+
+    #[cfg(feature = "transaction-history")]
+    history: TransactionHistory,
 }
 
-impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
+impl<'alloc, Bus: UsbBus, const N: usize> Pipe<'alloc, Bus, N> {
 
     // pub fn borrow_mut_authenticator(&mut self) -> &mut Authenticator {
     //     &mut self.authenticator
@@ -239,13 +795,35 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
         rpc: TransportEndpoint,
     ) -> Self
     {
+        // the first packet of any request must fit, and CTAPHID can't carry more
+        assert!(PACKET_SIZE <= N && N <= MESSAGE_SIZE);
         Self {
             read_endpoint,
             write_endpoint,
             state: State::Idle,
             rpc,
-            buffer: [0u8; MESSAGE_SIZE],
+            buffer: [0u8; N],
             last_channel: 0,
+            reported_channel: 0,
+            capabilities: Capabilities::default(),
+            oversize_policy: OversizePolicy::default(),
+            vendor_operations: true,
+            ctap1: false,
+            reset_window_ms: Some(RESET_WINDOW_MILLISECONDS),
+            vendor_stream: StreamLock::default(),
+            lock: ChannelLock::default(),
+            wink: None,
+            user_interaction: None,
+            now_ms: 0,
+            operation_started_ms: 0,
+            last_progress_ms: 0,
+            abandoned_responses: 0,
+            keepalive_status: KeepAliveStatus::Processing,
+            last_keepalive_ms: 0,
+            waiting_on_get_info: false,
+            cancelled: false,
+            #[cfg(feature = "transaction-history")]
+            history: TransactionHistory::default(),
         }
     }
 
@@ -257,6 +835,182 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
         self.write_endpoint.address()
     }
 
+    /// Monotonic milliseconds, to be called regularly by the app.
+    ///
+    /// Used to give up on an authenticator that doesn't respond within
+    /// `OPERATION_TIMEOUT_MILLISECONDS`, so it can't block the channel forever,
+    /// and until then to send keepalives every `KEEPALIVE_INTERVAL_MILLISECONDS`.
+    /// Likewise, a message stalled for `MESSAGE_TIMEOUT_MILLISECONDS` in between
+    /// packets is abandoned with ERR_MSG_TIMEOUT.
+    pub fn tick(&mut self, now_ms: u32) {
+        self.now_ms = now_ms;
+
+        if let Some(channel) = stalled_channel(&self.state, self.last_progress_ms, now_ms) {
+            warn!("message timed out").ok();
+            self.state = State::Idle;
+            return self.send_error(channel, CtapHidError::MessageTimeout);
+        }
+
+        // MSG is answered by the app, in its own time
+        if let State::WaitingOnAuthenticator(request) = self.state {
+            if request.command == Command::Msg {
+                return;
+            }
+            let elapsed = now_ms.wrapping_sub(self.operation_started_ms);
+            if elapsed >= OPERATION_TIMEOUT_MILLISECONDS {
+                warn!("authenticator timed out").ok();
+                self.abandon_response();
+                let response = self.response_from_error(request, AuthenticatorError::UserActionTimeout);
+                self.start_sending(response);
+            } else if keepalive_due(self.last_keepalive_ms, now_ms) {
+                self.send_keepalive(self.keepalive_status);
+            }
+        }
+    }
+
+    /// Send a CTAPHID_KEEPALIVE for the request the authenticator works on,
+    /// if any. Best effort: if the endpoint is busy, it's dropped.
+    pub fn send_keepalive(&mut self, status: KeepAliveStatus) {
+        if let State::WaitingOnAuthenticator(request) = self.state {
+            let packet = build_keepalive(request.channel, status);
+            if self.write_endpoint.write(&packet).is_ok() {
+                self.last_keepalive_ms = self.now_ms;
+            }
+        }
+    }
+
+    /// The status sent in keepalives until the authenticator responds,
+    /// e.g. `UpNeeded` once it waits for the user's touch. Each request
+    /// starts out as `Processing`.
+    pub fn set_keepalive_status(&mut self, status: KeepAliveStatus) {
+        self.keepalive_status = status;
+    }
+
+    /// Change the capabilities reported to the host, takes effect
+    /// with the next CTAPHID_INIT (e.g., disable WINK after provisioning).
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn set_oversize_policy(&mut self, policy: OversizePolicy) {
+        self.oversize_policy = policy;
+    }
+
+    /// Forward vendor CBOR operations (0x40 to 0x7f) to the authenticator.
+    ///
+    /// On by default. Authenticators without vendor operations can turn it
+    /// off, so hosts probing for them get CTAP1_ERR_INVALID_COMMAND right away,
+    /// instead of an authenticator that doesn't know what to do with them.
+    pub fn set_vendor_operations(&mut self, enabled: bool) {
+        self.vendor_operations = enabled;
+    }
+
+    /// Pass CTAPHID_MSG (CTAP1/U2F) requests on to the app, see `pending_msg`.
+    ///
+    /// Off by default, then CTAPHID_INIT reports NMSG and CTAPHID_MSG is an
+    /// invalid command. Only enable it for authenticators implementing `Api::msg`.
+    pub fn set_ctap1(&mut self, enabled: bool) {
+        self.ctap1 = enabled;
+    }
+
+    /// Only pass on authenticatorReset this long after power-up, as measured
+    /// by `tick`, otherwise answer CTAP2_ERR_OPERATION_DENIED. `None` leaves
+    /// the decision to the authenticator, which must check user presence anyway.
+    pub fn set_reset_window(&mut self, window_ms: Option<u32>) {
+        self.reset_window_ms = window_ms;
+    }
+
+    /// To be called by the app once a vendor stream (e.g., firmware
+    /// update) is complete, so other channels may start theirs.
+    pub fn end_vendor_stream(&mut self, channel: u32) {
+        self.vendor_stream.release(channel);
+    }
+
+    /// The channel holding a CTAPHID_LOCK, if any, e.g. for diagnostics.
+    pub fn lock_owner(&self) -> Option<u32> {
+        self.lock.owner(self.now_ms)
+    }
+
+    /// Forget the host's transactions after a bus reset or suspend: a half
+    /// received message is discarded, and locks and vendor streams are
+    /// released. A late response of the authenticator is dropped.
+    /// Channel IDs are not handed out again.
+    pub fn reset(&mut self) {
+        let (state, abandoned) = after_disconnect(&self.state);
+        if abandoned {
+            self.abandon_response();
+        }
+        if self.state != State::Idle {
+            info!("host went away mid-transaction").ok();
+        }
+        self.state = state;
+        self.lock = ChannelLock::default();
+        self.vendor_stream = StreamLock::default();
+        self.cancelled = false;
+        self.wink = None;
+        self.user_interaction = None;
+    }
+
+    /// The last few responses and errors sent, oldest first.
+    #[cfg(feature = "transaction-history")]
+    pub fn recent_transactions(&self) -> &[TransactionSummary] {
+        self.history.as_slice()
+    }
+
+    /// How much longer the current CTAPHID_LOCK lasts, by the time last
+    /// passed to `tick`.
+    pub fn lock_remaining_ms(&self) -> Option<u32> {
+        self.lock.remaining_ms(self.now_ms)
+    }
+
+    /// The authenticator sits behind RPC, so the app (or whoever drives
+    /// the LEDs) polls this to learn that the host asked for a wink.
+    pub fn take_wink(&mut self) -> Option<WinkPattern> {
+        self.wink.take()
+    }
+
+    /// Make-credential or get-assertion was just passed on to the authenticator,
+    /// which may take a while waiting for the user, e.g. to light an LED
+    /// until the response is sent. Polled like `take_wink`.
+    pub fn take_user_interaction(&mut self) -> Option<Operation> {
+        self.user_interaction.take()
+    }
+
+    /// The APDU of a CTAPHID_MSG request, until the app answers it with
+    /// `answer_msg`, e.g. with what `authenticator::dispatch_msg` returns.
+    pub fn pending_msg(&self) -> Option<&[u8]> {
+        match self.state {
+            State::WaitingOnAuthenticator(request) if request.command == Command::Msg =>
+                Some(&self.buffer[..request.length as usize]),
+            _ => None,
+        }
+    }
+
+    /// Send the response APDU to the pending CTAPHID_MSG request, status word
+    /// included. Does nothing if there is none, e.g. after a `reset`.
+    pub fn answer_msg(&mut self, apdu: &[u8]) {
+        if let State::WaitingOnAuthenticator(request) = self.state {
+            if request.command != Command::Msg {
+                return;
+            }
+            if apdu.len() > N {
+                self.state = State::Idle;
+                return self.send_error(request.channel, CtapHidError::Other);
+            }
+            self.buffer[..apdu.len()].copy_from_slice(apdu);
+            let response = frame_response(&mut self.buffer, request, apdu.len());
+            self.start_sending(response);
+        }
+    }
+
+    /// Channels allocated by CTAPHID_INIT since the last call, for the app
+    /// to pass on to `Api::on_channel_allocated`.
+    pub fn take_allocated_channels(&mut self) -> Range<u32> {
+        let channels = newly_allocated(self.reported_channel, self.last_channel);
+        self.reported_channel = self.last_channel;
+        channels
+    }
+
     // used to generate the configuration descriptors
     pub(crate) fn read_endpoint(&self) -> &EndpointOut<'alloc, Bus> {
         &self.read_endpoint
@@ -272,7 +1026,6 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
     ///
     /// During these calls, we can be in states: Idle, Receiving, Dispatching.
     pub(crate) fn read_and_handle_packet(&mut self) {
-        // hprintln!("got a packet!").ok();
         let mut packet = [0u8; PACKET_SIZE];
         match self.read_endpoint.read(&mut packet) {
             Ok(PACKET_SIZE) => {},
@@ -282,7 +1035,7 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
                 // HID report descriptors) and although all bytes may not be needed in a
                 // particular packet, the full size always has to be sent.
                 // Unused bytes SHOULD be set to zero."
-                // hprintln!("OK but size {}", size).ok();
+                warn!("short packet, {} bytes", _size).ok();
                 return;
             },
             // usb-device lists WouldBlock or BufferOverflow as possible errors.
@@ -290,68 +1043,96 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
             // Err(UsbError::WouldBlock) => { return; },
             // Err(UsbError::BufferOverflow) => { return; },
             Err(_error) => {
-                // hprintln!("error no {}", error as i32).ok();
+                warn!("read error").ok();
                 return;
             },
         };
 
-        // packet is 64 bytes, reading 4 will not panic
-        let channel = u32::from_be_bytes(packet[..4].try_into().unwrap());
-        // hprintln!("channel {}", channel).ok();
+        let packet = PacketView::new(&packet);
+
+        let channel = packet.channel();
+        trace!("packet on channel {}", channel).ok();
 
-        let is_initialization = (packet[4] >> 7) != 0;
-        // hprintln!("is_initialization {}", is_initialization).ok();
+        let is_initialization = packet.is_init();
 
         if is_initialization {
             // case of initialization packet
 
+            // the one command that is expected while we're busy
+            if packet.command() == Command::Cancel.into_u8() {
+                return self.cancel(channel);
+            }
+
+            // INIT on the active channel starts it over, see `dispatch_request`
+            if packet.command() == Command::Init.into_u8() && resynchronizes(&self.state, channel) {
+                info!("channel {} resynchronized", channel).ok();
+                self.abort_transaction(channel);
+            }
+
             if !(self.state == State::Idle) {
-                // TODO: should we buffer "busy errors" and send them?
-                // vs. just failing silently
-                return;
+                return match busy_error(&self.state, channel) {
+                    Some(CtapHidError::ChannelBusy) => self.send_error(channel, CtapHidError::ChannelBusy),
+                    // the active channel started over, its message is lost
+                    Some(error) => {
+                        self.state = State::Idle;
+                        self.send_error(channel, error)
+                    },
+                    // the active channel is to take its response first
+                    None => {},
+                };
             }
 
-            let command_number = packet[4] & !0x80;
-            // hprintln!("command number {}", command_number).ok();
+            let command_number = packet.command();
+            trace!("command {}", command_number).ok();
 
             let command = match Command::try_from(command_number) {
                 Ok(command) => command,
-                // `solo ls` crashes here as it uses command 0x86
-                Err(_) => { return; },
+                // e.g. `solo ls` uses command 0x86
+                Err(_) => { return self.send_error(channel, CtapHidError::InvalidCommand); },
             };
 
-            // can't actually fail
-            let length = u16::from_be_bytes(packet[5..][..2].try_into().unwrap());
+            if let Err(error) = check_channel(channel, command, self.last_channel) {
+                return self.send_error(channel, error);
+            }
 
-            let request = Request { channel, command, length };
-            // hprintln!("request is {:?}", &request).ok();
+            if self.lock.blocks(channel, self.now_ms) {
+                return self.send_error(channel, CtapHidError::ChannelBusy);
+            }
 
-            if length > MESSAGE_SIZE as u16 {
-                // non-conforming client - we disregard it
-                // TODO: error msg-too-long
-                return;
+            let declared_length = packet.declared_length();
+
+            if let Err(error) = check_length(command, declared_length) {
+                return self.send_error(channel, error);
             }
 
-            // TODO: add some checks that request.length is OK.
-            // e.g., CTAPHID_INIT should have payload of length 8.
+            let length = match admit_length(command, declared_length, N, self.oversize_policy) {
+                Ok(length) => length,
+                Err(Rejection::Transport(error)) => return self.send_error(channel, error),
+                Err(Rejection::Cbor(error)) => {
+                    // answer right away, the continuation packets are dropped
+                    let request = Request { channel, command, length: declared_length };
+                    let response = self.response_from_error(request, error);
+                    return self.start_sending(response);
+                }
+            };
+
+            let request = Request { channel, command, length };
+            self.cancelled = false;
 
-            // hprintln!("receiving message of length {}", length).ok();
+            trace!("message of length {}", length).ok();
             if length > PACKET_SIZE as u16 - 7 {
                 // store received part of payload,
                 // prepare for continuation packets
                 self.buffer[..PACKET_SIZE - 7]
-                    .copy_from_slice(&packet[7..]);
-                self.state = State::Receiving((request, {
-                    let state = MessageState::default();
-                    // hprintln!("got {} so far", state.transmitted).ok();
-                    state
-                }));
+                    .copy_from_slice(packet.init_payload());
+                self.state = State::Receiving((request, MessageState::default()));
+                self.last_progress_ms = self.now_ms;
                 // we're done... wait for next packet
                 return;
             } else {
                 // request fits in one packet
                 self.buffer[..length as usize]
-                    .copy_from_slice(&packet[7..][..length as usize]);
+                    .copy_from_slice(&packet.init_payload()[..length as usize]);
                 self.dispatch_request(request);
                 return;
             }
@@ -359,173 +1140,180 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
             // case of continuation packet
             match self.state {
                 State::Receiving((request, mut message_state)) => {
-                    let sequence = packet[4];
-                    // hprintln!("receiving continuation packet {}", sequence).ok();
-                    if sequence != message_state.next_sequence {
-                        // error handling?
-                        // hprintln!("wrong sequence for continuation packet, expected {} received {}",
-                        //           message_state.next_sequence, sequence).ok();
-                        return;
-                    }
-                    if channel != request.channel {
-                        // error handling?
-                        // hprintln!("wrong channel for continuation packet, expected {} received {}",
-                        //           request.channel, channel).ok();
-                        return;
+                    let sequence = packet.sequence();
+                    trace!("continuation packet {}", sequence).ok();
+                    match check_continuation(&request, &message_state, channel, sequence) {
+                        Ok(()) => {},
+                        // someone else's transaction is unaffected
+                        Err(CtapHidError::ChannelBusy) => {
+                            return self.send_error(channel, CtapHidError::ChannelBusy);
+                        },
+                        // our own is aborted
+                        Err(error) => {
+                            self.state = State::Idle;
+                            return self.send_error(channel, error);
+                        },
                     }
 
                     let payload_length = request.length as usize;
-                    if message_state.transmitted + (PACKET_SIZE - 5) < payload_length {
-                        // hprintln!("transmitted {} + (PACKET_SIZE - 5) < {}",
-                        //           message_state.transmitted, payload_length).ok();
-                        // store received part of payload
-                        self.buffer[message_state.transmitted..][..PACKET_SIZE - 5]
-                            .copy_from_slice(&packet[5..]);
-                        message_state.absorb_packet();
+                    let complete = message_state.receive_continuation(
+                        &mut self.buffer[..payload_length], packet.cont_payload());
+                    if complete {
+                        self.dispatch_request(request);
+                    } else {
                         self.state = State::Receiving((request, message_state));
-                        // hprintln!("absorbed packet, awaiting next").ok();
+                        self.last_progress_ms = self.now_ms;
                         return;
-                    } else {
-                        let missing = request.length as usize - message_state.transmitted;
-                        self.buffer[message_state.transmitted..payload_length]
-                            .copy_from_slice(&packet[5..][..missing]);
-                        self.dispatch_request(request);
                     }
                 },
-                _ => {
-                    // unexpected continuation packet
+                ref state => {
+                    // no message to continue, the packet is spurious and ignored.
+                    // only another channel's, during a transaction, is told to wait.
+                    if let Some(error) = stray_continuation_error(state, channel) {
+                        self.send_error(channel, error);
+                    }
                     return;
                 },
             }
         }
     }
 
-    fn dispatch_request(&mut self, request: Request) {
-        // dispatch request further
-        match request.command {
-            Command::Init => {
-                // hprintln!("command INIT!").ok();
-                // hprintln!("data: {:?}", &self.buffer[..request.length as usize]).ok();
-                match request.channel {
-                    // broadcast channel ID - request for assignment
-                    0xFFFF_FFFF => {
-                        if request.length != 8 {
-                            // error
-                        } else {
-                            self.last_channel += 1;
-                            // hprintln!(
-                            //     "assigned channel {}", self.last_channel).ok();
-                            let _nonce = &self.buffer[..8];
-                            let response = Response {
-                                channel: 0xFFFF_FFFF,
-                                command: request.command,
-                                length: 17,
-                            };
-
-                            self.buffer[8..12].copy_from_slice(&self.last_channel.to_be_bytes());
-                            // CTAPHID protocol version
-                            self.buffer[12] = 2;
-                            // major device version number
-                            self.buffer[13] = 0;
-                            // minor device version number
-                            self.buffer[14] = 0;
-                            // build device version number
-                            self.buffer[15] = 0;
-                            // capabilities flags
-                            // 0x1: implements WINK
-                            // 0x4: implements CBOR
-                            // 0x8: does not implement MSG
-                            // self.buffer[16] = 0x01 | 0x08;
-                            self.buffer[16] = 0x01 | 0x04;
-                            self.start_sending(response);
-                        }
-                    },
-                    0 => {
-                        // this is an error / reserved number
-                    },
-                    _ => {
-                        // this is assumedly the active channel,
-                        // already allocated to a client
-                        // TODO: "reset"
-                    }
+    fn cancel(&mut self, channel: u32) {
+        match cancel_action(&self.state, channel) {
+            CancelAction::Ignore => {},
+            CancelAction::WhenReceived => {
+                info!("cancelled while receiving").ok();
+                self.cancelled = true;
+            },
+            CancelAction::Now(request) => {
+                info!("cancelled while processing").ok();
+                // the authenticator can't be interrupted, drop its late response
+                self.abandon_response();
+                let response = self.response_from_error(request, AuthenticatorError::KeepaliveCancel);
+                self.start_sending(response);
+            },
+        }
+    }
+
+    // the authenticator's response to the request we were waiting on will
+    // still come in, but the host has been answered already
+    // drop the transaction in progress, as if it never happened
+    fn abort_transaction(&mut self, channel: u32) {
+        let (state, abandoned) = after_disconnect(&self.state);
+        if abandoned {
+            self.abandon_response();
+        }
+        self.state = state;
+        self.vendor_stream.release(channel);
+        self.cancelled = false;
+    }
+
+    fn abandon_response(&mut self) {
+        self.abandoned_responses = self.abandoned_responses.saturating_add(1);
+    }
+
+    fn dispatch_request(&mut self, request: Request) {
+        // an internal bug, but in release builds the host deserves an answer
+        let ready = ready_to_dispatch(&self.state, &request);
+        debug_assert!(ready, "dispatching request in state {:?}", self.state);
+        if !ready {
+            error!("dispatching in wrong state").ok();
+            self.state = State::Idle;
+            return self.send_error(request.channel, CtapHidError::Other);
+        }
+
+        // dispatch request further
+        match request.command {
+            Command::Init => {
+                if request.length != 8 {
+                    // rejected on arrival already, see `check_length`
+                    self.state = State::Idle;
+                    return self.send_error(request.channel, CtapHidError::InvalidLength);
                 }
+                let channel = match request.channel {
+                    // broadcast channel ID - request for assignment
+                    0xFFFF_FFFF => {
+                        self.last_channel += 1;
+                        debug!("assigned channel {}", self.last_channel).ok();
+                        self.last_channel
+                    },
+                    // an allocated channel (see `check_channel`) is kept, its
+                    // transaction was aborted on arrival of this request
+                    channel => channel,
+                };
+                let mut nonce = [0u8; 8];
+                nonce.copy_from_slice(&self.buffer[..8]);
+                // capabilities flags, current as of this INIT
+                let capabilities = advertised_capabilities(self.capabilities, self.ctap1);
+                let size = write_init_response(&mut self.buffer, nonce, channel, capabilities);
+                let response = Response::from_request_and_size(request, size);
+                self.start_sending(response);
             },
 
             Command::Ping => {
-                // hprintln!("received PING!").ok();
-                // hprintln!("data: {:?}", &self.buffer[..request.length as usize]).ok();
                 let response = Response::from_request_and_size(request, request.length as usize);
                 self.start_sending(response);
             },
 
             Command::Wink => {
-                // hprintln!("received WINK!").ok();
-                // TODO: request.length should be zero
-                // TODO: callback "app"
-                let response = Response::from_request_and_size(request, 1);
+                // length was checked on arrival, the app passes this on to `Api::wink`
+                self.wink = Some(WinkPattern::default());
+                // the response is as empty as the request
+                let response = Response::from_request_and_size(request, 0);
                 self.start_sending(response);
             },
 
             Command::Cbor => {
-                // hprintln!("command CBOR!").ok();
                 self.handle_cbor(request);
             },
 
-            // Command::Msg => {
-            //     // hprintln!("command MSG!").ok();
-            //     self.handle_msg(request);
-            // },
+            Command::Lock => {
+                let seconds = match lock_seconds(&self.buffer[..request.length as usize]) {
+                    Ok(seconds) => seconds,
+                    Err(error) => {
+                        self.state = State::Idle;
+                        return self.send_error(request.channel, error);
+                    }
+                };
+                self.lock.lock(request.channel, seconds, self.now_ms);
+                let response = Response::from_request_and_size(request, 0);
+                self.start_sending(response);
+            },
+
+            Command::Msg if self.ctap1 => {
+                // the app picks up the APDU with `pending_msg`
+                self.state = State::WaitingOnAuthenticator(request);
+            },
 
             // TODO: handle other requests
             _ => {
-                // hprintln!("unknown command {:?}", request.command).ok();
+                info!("unhandled command {}", request.command.into_u8()).ok();
+                self.state = State::Idle;
+                self.send_error(request.channel, CtapHidError::InvalidCommand);
             },
         }
     }
 
-    // fn handle_msg(&mut self, request: Request) {
-    //     // this is the U2F/CTAP1 layer.
-    //     // we handle it by mapping to CTAP2, similar to how user agents
-    //     // map CTAP2 to CTAP1.
-    //     // hprintln!("data = {:?}", &self.buffer[..request.length as usize]).ok();
-
-    //     let command = ctap1::Command::try_from(&self.buffer[..request.length as usize]);
-    //     match command {
-    //         Err(error) => {
-    //             // hprintln!("ERROR").ok();
-    //             self.buffer[..2].copy_from_slice(&(error as u16).to_be_bytes());
-    //             let response = Response::from_request_and_size(request, 2);
-    //             self.start_sending(response);
-    //         },
-    //         Ok(command) => {
-    //             match command {
-    //                 ctap1::Command::Version => {
-    //                     // hprintln!("U2F_VERSION").ok();
-    //                     // GetVersion
-    //                     // self.buffer[0] = 0;
-    //                     self.buffer[..6].copy_from_slice(b"U2F_V2");
-    //                     // self.buffer[6..][..2].copy_from_slice(ctap1::NoError::to_be_bytes());
-    //                     self.buffer[6..][..2].copy_from_slice(&(ctap1::NO_ERROR).to_be_bytes());
-    //                     let response = Response::from_request_and_size(request, 8);
-    //                     // hprintln!("sending response: {:x?}", &self.buffer[..response.length as usize]).ok();
-    //                     self.start_sending(response);
-    //                 },
-    //                 ctap1::Command::Register(_register) => {
-    //                     // hprintln!("command {:?}", &register).ok();
-    //                     self.buffer[..2].copy_from_slice(&(ctap1::Error::InsNotSupported as u16).to_be_bytes());
-    //                     let response = Response::from_request_and_size(request, 1);
-    //                     self.start_sending(response);
-    //                 },
-    //                 ctap1::Command::Authenticate(_authenticate) => {
-    //                     // hprintln!("command {:?}", &authenticate).ok();
-    //                     self.buffer[..2].copy_from_slice(&(ctap1::Error::InsNotSupported as u16).to_be_bytes());
-    //                     let response = Response::from_request_and_size(request, 1);
-    //                     self.start_sending(response);
-    //                 }
-    //             }
-    //         }
-    //     }
-    // }
+    fn wait_on_authenticator(&mut self, request: Request, authenticator_request: ctap_types::authenticator::Request) {
+        use ctap_types::authenticator::{ctap2, Request};
+        let get_info = matches!(authenticator_request, Request::Ctap2(ctap2::Request::GetInfo));
+        let user_interaction = match authenticator_request {
+            Request::Ctap2(ctap2::Request::MakeCredential(_)) => Some(Operation::MakeCredential),
+            Request::Ctap2(ctap2::Request::GetAssertion(_)) => Some(Operation::GetAssertion),
+            _ => None,
+        };
+        if self.rpc.send.enqueue(authenticator_request).is_err() {
+            // authenticator hasn't even picked up the previous request
+            let response = self.response_from_error(request, AuthenticatorError::ChannelBusy);
+            return self.start_sending(response);
+        }
+        self.user_interaction = user_interaction;
+        self.operation_started_ms = self.now_ms;
+        self.keepalive_status = KeepAliveStatus::Processing;
+        self.last_keepalive_ms = self.now_ms;
+        self.waiting_on_get_info = get_info;
+        self.state = State::WaitingOnAuthenticator(request);
+    }
 
     fn response_from_error(&mut self, request: Request, error: AuthenticatorError) -> Response {
         self.buffer[0] = error as u8;
@@ -546,19 +1334,30 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
             1
         };
 
-        self.buffer[0] = 0;
-        Response::from_request_and_size(request, size)
+        self.buffer[0] = CtapStatus::Success.into();
+        frame_response(&mut self.buffer, request, size)
     }
 
     fn handle_cbor(&mut self, request: Request) {
+        if self.cancelled {
+            self.cancelled = false;
+            let response = self.response_from_error(request, AuthenticatorError::KeepaliveCancel);
+            return self.start_sending(response);
+        }
+
         let data = &self.buffer[..request.length as usize];
-        // hprintln!("data: {:?}", data).ok();
 
         if data.len() < 1 {
-            return;
+            // no operation byte at all, still the host deserves an answer
+            let response = self.response_from_error(request, AuthenticatorError::InvalidLength);
+            return self.start_sending(response);
         }
 
         let operation_u8: u8 = data[0];
+        // parameters must be a single well-formed CBOR item, with nothing after it
+        let well_formed = data.len() == 1 || cbor_well_formed(&data[1..]);
+        let length = request.length as usize;
+        let parameters = &data[1..];
 
         let operation = match Operation::try_from(operation_u8) {
             Ok(operation) => {
@@ -566,19 +1365,39 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
             },
             Err(_) => {
                 info!("authenticator command {:?}", operation_u8).ok();
-                self.buffer[0] = AuthenticatorError::InvalidCommand as u8;
-                let response = self::Response::from_request_and_size(request, 1);
+                let response = self.response_from_error(request, AuthenticatorError::InvalidCommand);
                 return self.start_sending(response);
             },
         };
 
+        // before the length check, as a missing key also makes for short parameters
+        if well_formed && first_missing_key(parameters, required_keys(&operation)).is_some() {
+            warn!("missing parameter").ok();
+            let response = self.response_from_error(request, AuthenticatorError::MissingParameter);
+            return self.start_sending(response);
+        }
+
+        // clearer than whatever deserialization makes of a truncated request
+        if length - 1 < minimum_parameters_length(&operation) {
+            warn!("parameters too short").ok();
+            let response = self.response_from_error(request, AuthenticatorError::InvalidLength);
+            return self.start_sending(response);
+        }
+
+        // vendor payloads are up to the vendor
+        if !well_formed && !matches!(operation, Operation::Vendor(_)) {
+            warn!("malformed CBOR parameters").ok();
+            let response = self.response_from_error(request, AuthenticatorError::InvalidCbor);
+            return self.start_sending(response);
+        }
+
         // use ctap_types::ctap2::*;
         use ctap_types::authenticator::*;
 
         match operation {
             Operation::MakeCredential => {
                 info!("authenticatorMakeCredential").ok();
-                let params: ctap2::make_credential::Parameters = match cbor_deserialize(&mut self.buffer[1..])
+                let params: ctap2::make_credential::Parameters = match cbor_deserialize(&mut self.buffer[1..length])
                 {
                     Ok(params) => params,
                     Err(_error) => {
@@ -588,75 +1407,65 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
                         return self.start_sending(response);
                     }
                 };
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::MakeCredential(params))).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::MakeCredential(params)));
             }
 
             Operation::GetAssertion => {
                 info!("authenticatorGetAssertion").ok();
 
-                let params: ctap2::get_assertion::Parameters = match cbor_deserialize(&mut self.buffer[1..])
+                let params: ctap2::get_assertion::Parameters = match cbor_deserialize(&mut self.buffer[1..length])
                 {
                     Ok(params) => params,
-                    Err(error) => {
+                    Err(_error) => {
                         // info!("GA deser error {:?}", error as u8).ok();
-                        // info!("GA deser error {:?}", error).ok();
-                        #[cfg(feature = "semihosting")]
-                        hprintln!("GA deser error {:?}", error).ok();
+                        warn!("GA deser error").ok();
                         let response = self.response_from_error(request, AuthenticatorError::InvalidCbor);
                         return self.start_sending(response);
                     }
                 };
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::GetAssertion(params))).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::GetAssertion(params)));
             }
 
             Operation::GetNextAssertion => {
                 info!("authenticatorGetNextAssertion").ok();
 
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::GetNextAssertion)).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::GetNextAssertion));
             }
 
             Operation::CredentialManagement => {
                 info!("authenticatorCredentialManagement").ok();
 
-                let params: ctap2::credential_management::Parameters = match cbor_deserialize(&mut self.buffer[1..])
+                let params: ctap2::credential_management::Parameters = match cbor_deserialize(&mut self.buffer[1..length])
                 {
                     Ok(params) => params,
-                    Err(error) => {
-                        #[cfg(feature = "semihosting")]
-                        hprintln!("CM deser error {:?}", error).ok();
+                    Err(_error) => {
+                        warn!("CM deser error").ok();
                         let response = self.response_from_error(request, AuthenticatorError::InvalidCbor);
                         return self.start_sending(response);
                     }
                 };
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::CredentialManagement(params))).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::CredentialManagement(params)));
             }
 
             Operation::Reset => {
                 info!("authenticatorReset").ok();
 
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::Reset)).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                if !reset_allowed(self.reset_window_ms, self.now_ms) {
+                    warn!("reset too late after power-up").ok();
+                    let response = self.response_from_error(request, AuthenticatorError::OperationDenied);
+                    return self.start_sending(response);
+                }
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::Reset));
             }
 
             Operation::GetInfo => {
                 info!("authenticatorGetInfo").ok();
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::GetInfo)).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::GetInfo));
             }
 
             Operation::ClientPin => {
                 info!("authenticatorClientPin").ok();
-                let params: ctap2::client_pin::Parameters = match cbor_deserialize(&mut self.buffer[1..])
+                let params: ctap2::client_pin::Parameters = match cbor_deserialize(&mut self.buffer[1..length])
                 {
                     Ok(params) => params,
                     Err(_error) => {
@@ -665,43 +1474,49 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
                         return self.start_sending(response);
                     }
                 };
-                // TODO: ensure earlier that RPC send queue is empty
-                self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::ClientPin(params))).unwrap();
-                self.state = State::WaitingOnAuthenticator(request);
+                self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::ClientPin(params)));
             }
 
             Operation::Vendor(vendor_operation) => {
                 info!("authenticatorVendor({:?})", &vendor_operation).ok();
 
                 let vo_u8: u8 = vendor_operation.into();
-                if vo_u8 == 0x41 {
-                    // copy-pasta for now
-                    let params: ctap2::credential_management::Parameters = match cbor_deserialize(&mut self.buffer[1..])
-                    {
-                        Ok(params) => params,
-                        Err(error) => {
-                            #[cfg(feature = "semihosting")]
-                            hprintln!("CM deser error {:?}", error).ok();
-                            let response = self.response_from_error(request, AuthenticatorError::InvalidCbor);
-                            return self.start_sending(response);
+                match route_vendor_operation(vo_u8, self.vendor_operations) {
+                    VendorRoute::CredentialManagement => {
+                        // copy-pasta for now
+                        let params: ctap2::credential_management::Parameters = match cbor_deserialize(&mut self.buffer[1..length])
+                        {
+                            Ok(params) => params,
+                            Err(_error) => {
+                                warn!("CM deser error").ok();
+                                let response = self.response_from_error(request, AuthenticatorError::InvalidCbor);
+                                return self.start_sending(response);
+                            }
+                        };
+                        self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::CredentialManagement(params)));
+                    }
+
+                    VendorRoute::Authenticator => {
+                        if !self.vendor_stream.try_acquire(request.channel, self.now_ms) {
+                            warn!("vendor stream busy").ok();
+                            self.state = State::Idle;
+                            return self.send_error(request.channel, CtapHidError::ChannelBusy);
                         }
-                    };
-                    // TODO: ensure earlier that RPC send queue is empty
-                    self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::CredentialManagement(params))).unwrap();
-                    self.state = State::WaitingOnAuthenticator(request);
+                        self.wait_on_authenticator(request, Request::Ctap2(ctap2::Request::Vendor(vendor_operation)));
+                    }
 
-                } else {
-                    // TODO: ensure earlier that RPC send queue is empty
-                    self.rpc.send.enqueue(Request::Ctap2(ctap2::Request::Vendor(vendor_operation))).unwrap();
-                    self.state = State::WaitingOnAuthenticator(request);
+                    VendorRoute::Unhandled => {
+                        let response = self.response_from_error(request, AuthenticatorError::InvalidCommand);
+                        self.start_sending(response);
+                    }
                 }
             }
 
-            unknown => {
-                let unknown: u8 = unknown.into();
-                info!("authenticator command {:?}", unknown).ok();
-                self.buffer[0] = AuthenticatorError::InvalidCommand as u8;
-                let response = self::Response::from_request_and_size(request, 1);
+            // everything we don't implement fails fast,
+            // instead of leaving the host waiting
+            _ => {
+                info!("authenticator command {:?}", operation_u8).ok();
+                let response = self.response_from_error(request, AuthenticatorError::InvalidCommand);
                 self.start_sending(response);
             }
         }
@@ -709,11 +1524,22 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
 
     pub fn handle_response(&mut self) {
         if let State::WaitingOnAuthenticator(request) = self.state {
+            // nothing comes over RPC for MSG, see `answer_msg`
+            if request.command == Command::Msg {
+                return;
+            }
+            // responses come in order, so the abandoned requests' come first
+            while self.abandoned_responses > 0 {
+                if self.rpc.recv.dequeue().is_none() {
+                    return;
+                }
+                self.abandoned_responses -= 1;
+            }
             if let Some(result) = self.rpc.recv.dequeue() {
-                // hprintln!("got response").ok();
                 match result {
                     Err(error) => {
-                        info!("error {}", error as u8).ok();
+                        debug!("error {}", error as u8).ok();
+                        let error = map_authenticator_error(self.waiting_on_get_info, error);
                         let response = self.response_from_error(request, error);
                         self.start_sending(response);
                     }
@@ -722,14 +1548,17 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
                         use ctap_types::authenticator::Response;
                         match response {
                             Response::Ctap1(_response) => {
-                                todo!("CTAP1 responses");
+                                // we never send CTAP1 requests, so this is a confused authenticator
+                                let response = self.response_from_error(request, AuthenticatorError::Other);
+                                self.start_sending(response);
                             }
 
                             Response::Ctap2(response) => {
                                 use ctap_types::authenticator::ctap2::Response;
-                                // hprintln!("authnr c2 resp: {:?}", &response).ok();
                                 let response = match response {
-                                    Response::GetInfo(response) => {
+                                    Response::GetInfo(mut response) => {
+                                        // we can't take more than fits our buffer
+                                        response.max_msg_size = Some(advertised_max_msg_size(response.max_msg_size, N));
                                         self.response_from_object(request, Some(&response))
                                     }
 
@@ -765,8 +1594,6 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
                                     //     todo!("what about all this");
                                     // }
                                 };
-                                #[cfg(feature = "semihosting")]
-                                // hprintln!("response: {:?}", &self.buffer[..response.length as usize]).ok();
                                 self.start_sending(response);
                             }
                         }
@@ -776,124 +1603,1831 @@ impl<'alloc, Bus: UsbBus> Pipe<'alloc, Bus> {
         }
     }
 
+    /// Whether the response being sent reports an error,
+    /// on the transport level or as CBOR status code.
+    pub(crate) fn responding_with_error(&self) -> bool {
+        match self.state {
+            State::WaitingToSend(response) | State::Sending((response, _)) =>
+                is_error_response(response.command, self.buffer[0]),
+            _ => false,
+        }
+    }
+
+    /// Reply with a single `Command::Error` packet.
+    ///
+    /// This goes straight to the endpoint, bypassing buffer and state,
+    /// so it can't clobber a transaction in progress on another channel.
+    /// Best effort: if the endpoint is busy, the error is dropped.
+    fn send_error(&mut self, channel: u32, error: CtapHidError) {
+        self.record_transaction(TransactionSummary {
+            channel,
+            command: Command::Error,
+            status: error as u8,
+            length: 1,
+        });
+        let packet = build_single_packet(channel, Command::Error, &[error as u8]);
+        self.write_endpoint.write(&packet).ok();
+    }
+
     fn start_sending(&mut self, response: Response) {
+        let status = match response.command {
+            Command::Cbor if response.length > 0 => self.buffer[0],
+            _ => 0,
+        };
+        self.record_transaction(TransactionSummary {
+            channel: response.channel,
+            command: response.command,
+            status,
+            length: response.length,
+        });
         self.state = State::WaitingToSend(response);
+        self.last_progress_ms = self.now_ms;
         self.maybe_write_packet();
     }
 
+    #[cfg(feature = "transaction-history")]
+    fn record_transaction(&mut self, transaction: TransactionSummary) {
+        self.history.push(transaction);
+    }
+
+    // compiled away without the feature
+    #[cfg(not(feature = "transaction-history"))]
+    fn record_transaction(&mut self, _transaction: TransactionSummary) {}
+
+    /// Send as many packets of the current response as the endpoint
+    /// accepts, instead of just one, until it would block or we're done.
+    pub fn flush(&mut self) {
+        loop {
+            let before = self.state.clone();
+            self.maybe_write_packet();
+            match self.state {
+                // progress was made, and there's more to send
+                State::WaitingToSend(_) | State::Sending(_) if self.state != before => continue,
+                _ => break,
+            }
+        }
+    }
+
     // called from poll, and when a packet has been sent
     pub(crate) fn maybe_write_packet(&mut self) {
+        let write_endpoint = &self.write_endpoint;
+        let before = self.state.clone();
+        self.state = write_packet(before.clone(), &self.buffer, |packet| write_endpoint.write(packet));
+        if self.state != before {
+            self.last_progress_ms = self.now_ms;
+        }
+    }
+}
 
-        match self.state {
-            State::WaitingToSend(response) => {
+// get-info has no error conditions of its own, whatever went wrong
+// (e.g., reading config from flash) is CTAP1_ERR_OTHER to the host
+fn map_authenticator_error(get_info: bool, error: AuthenticatorError) -> AuthenticatorError {
+    if get_info {
+        AuthenticatorError::Other
+    } else {
+        error
+    }
+}
+
+// maxMsgSize for authenticatorGetInfo, the authenticator's unless our buffer is smaller
+fn advertised_max_msg_size(authenticator: Option<usize>, buffer_size: usize) -> usize {
+    match authenticator {
+        Some(size) => size.min(buffer_size),
+        None => buffer_size,
+    }
+}
+
+// channels are assigned one by one, so those after `reported` up to `last`
+fn newly_allocated(reported_channel: u32, last_channel: u32) -> Range<u32> {
+    (reported_channel + 1)..(last_channel + 1)
+}
+
+// channels are assigned one by one, so the ones in use are 1 to `last_channel`,
+// the broadcast channel is only for requesting one
+fn check_channel(channel: u32, command: Command, last_channel: u32) -> Result<(), CtapHidError> {
+    match channel {
+        0xFFFF_FFFF if command == Command::Init => Ok(()),
+        channel if channel != 0 && channel <= last_channel => Ok(()),
+        _ => Err(CtapHidError::InvalidChannel),
+    }
+}
+
+// write the next packet of the response being sent, returning the new state.
+// if the endpoint is still busy, the state is unchanged and
+// `poll` (via `flush`) tries again later.
+fn write_packet<W>(state: State, buffer: &[u8], write: W) -> State
+where
+    W: FnOnce(&[u8]) -> Result<usize, UsbError>,
+{
+    let (packet, sent) = match state {
+        State::WaitingToSend(response) => {
+            let fits_in_one_packet = 7 + response.length as usize <= PACKET_SIZE;
+            let packet = build_init_packet(
+                response.channel, response.command, &buffer[..response.length as usize]);
+            let sent = if fits_in_one_packet {
+                State::Idle
+            } else {
+                State::Sending((response, MessageState::default()))
+            };
+            (packet, sent)
+        },
+
+        State::Sending((response, mut message_state)) => {
+            let (packet, last_packet) = message_state.continuation_packet(
+                response.channel, &buffer[..response.length as usize]);
+            let sent = if last_packet {
+                State::Idle
+            } else {
+                message_state.absorb_packet();
+                State::Sending((response, message_state))
+            };
+            (packet, sent)
+        },
+
+        // nothing to send
+        _ => return state,
+    };
+
+    // try actually sending
+    match write(&packet) {
+        // fine, can't write, try later
+        Err(UsbError::WouldBlock) => state,
+        // goodie, this worked
+        Ok(PACKET_SIZE) => sent,
+        Ok(_) => {
+            error!("short write").ok();
+            // short write, abandon the response
+            State::Idle
+        },
+        // weird USB error, nothing sensible left to do:
+        // abandon the response rather than hard faulting
+        Err(_) => State::Idle,
+    }
+}
+
+// U2F status words
+// the clock starts at power-up, and `tick` isn't expected to wrap before the window ends
+fn reset_allowed(window_ms: Option<u32>, now_ms: u32) -> bool {
+    match window_ms {
+        Some(window_ms) => now_ms <= window_ms,
+        None => true,
+    }
+}
+
+// requests come complete in their init packet, or at the end of receiving them
+fn ready_to_dispatch(state: &State, request: &Request) -> bool {
+    match state {
+        State::Idle => true,
+        State::Receiving((receiving, _)) => receiving == request,
+        _ => false,
+    }
+}
+
+// the first keepalive goes out one interval after the request was passed on
+// channel of a message that neither arrived nor went out in time
+fn stalled_channel(state: &State, last_progress_ms: u32, now_ms: u32) -> Option<u32> {
+    let channel = match state {
+        State::Receiving((request, _)) => request.channel,
+        State::WaitingToSend(response) | State::Sending((response, _)) => response.channel,
+        _ => return None,
+    };
+    if now_ms.wrapping_sub(last_progress_ms) >= MESSAGE_TIMEOUT_MILLISECONDS {
+        Some(channel)
+    } else {
+        None
+    }
+}
+
+fn keepalive_due(last_keepalive_ms: u32, now_ms: u32) -> bool {
+    now_ms.wrapping_sub(last_keepalive_ms) >= KEEPALIVE_INTERVAL_MILLISECONDS
+}
+
+// INIT on an allocated channel aborts its pending transaction, if any.
+// the broadcast channel's INIT responses aren't a transaction to abort.
+fn resynchronizes(state: &State, channel: u32) -> bool {
+    let active = match *state {
+        State::Idle => return false,
+        State::Receiving((request, _)) | State::WaitingOnAuthenticator(request) => request.channel,
+        State::WaitingToSend(response) | State::Sending((response, _)) => response.channel,
+    };
+    active == channel && channel != 0xFFFF_FFFF
+}
+
+// init packets while not idle: other channels must wait their turn, until
+// the response is sent, the active one was expected to continue its message.
+// transport-level commands such as WINK are no exception, one
+// transaction at a time keeps the buffer (and the LEDs) to the request.
+fn busy_error(state: &State, channel: u32) -> Option<CtapHidError> {
+    match *state {
+        State::Idle => None,
+        State::Receiving((request, _)) if request.channel == channel => Some(CtapHidError::InvalidSequence),
+        // nothing to tell, the response is on its way
+        State::WaitingToSend(response) | State::Sending((response, _)) if response.channel == channel => None,
+        _ => Some(CtapHidError::ChannelBusy),
+    }
+}
+
+// the response in the first `size` bytes of `buffer`, if its length can be
+// sent at all. can't happen with our buffer sizes, but if it did, an error
+// beats a truncated length that doesn't match what follows.
+fn frame_response(buffer: &mut [u8], request: Request, size: usize) -> Response {
+    match Response::try_from_request_and_size(request, size) {
+        Some(response) => response,
+        None => {
+            buffer[0] = CtapHidError::Other as u8;
+            Response { channel: request.channel, command: Command::Error, length: 1 }
+        },
+    }
+}
+
+// the host is gone, so is any transaction. the authenticator can't be
+// interrupted though, and its response will still come in (except for MSG,
+// which the app answers).
+fn after_disconnect(state: &State) -> (State, bool) {
+    let abandoned = matches!(state, State::WaitingOnAuthenticator(request) if request.command != Command::Msg);
+    (State::Idle, abandoned)
+}
+
+// continuation packets outside of `Receiving` have no message to join. the
+// spec has spurious ones ignored, but also has any other channel answered with
+// ERR_CHANNEL_BUSY during a transaction, as for init packets.
+fn stray_continuation_error(state: &State, channel: u32) -> Option<CtapHidError> {
+    match *state {
+        State::WaitingOnAuthenticator(request) if request.channel != channel => Some(CtapHidError::ChannelBusy),
+        State::WaitingToSend(response) | State::Sending((response, _)) if response.channel != channel =>
+            Some(CtapHidError::ChannelBusy),
+        _ => None,
+    }
+}
+
+// continuation packets must follow their init packet in order, on its channel
+fn check_continuation(request: &Request, message_state: &MessageState, channel: u32, sequence: u8)
+    -> Result<(), CtapHidError>
+{
+    if channel != request.channel {
+        Err(CtapHidError::ChannelBusy)
+    } else if sequence != message_state.next_sequence {
+        Err(CtapHidError::InvalidSequence)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+enum CancelAction {
+    Ignore,
+    /// don't pass the request on once it is complete
+    WhenReceived,
+    /// answer the request right away
+    Now(Request),
+}
+
+// CTAPHID_CANCEL only concerns CBOR requests on its own channel
+fn cancel_action(state: &State, channel: u32) -> CancelAction {
+    match *state {
+        State::Receiving((request, _)) if request.channel == channel && request.command == Command::Cbor =>
+            CancelAction::WhenReceived,
+        State::WaitingOnAuthenticator(request) if request.channel == channel && request.command == Command::Cbor =>
+            CancelAction::Now(request),
+        _ => CancelAction::Ignore,
+    }
+}
+
+// CBOR responses start with their status code
+fn is_error_response(command: Command, first_byte: u8) -> bool {
+    match command {
+        Command::Error => true,
+        Command::Cbor => first_byte != 0,
+        _ => false,
+    }
+}
+
+/// How a request is turned down.
+#[derive(Copy,Clone,Debug)]
+enum Rejection {
+    /// CTAPHID_ERROR
+    Transport(CtapHidError),
+    /// CBOR response with just the status code
+    Cbor(AuthenticatorError),
+}
+
+// hosts handle CTAP2_ERR_REQUEST_TOO_LARGE differently from a transport
+// error (e.g., by retrying with fewer allow list entries), so CBOR
+// requests get that one
+fn oversize_rejection(command: Command) -> Rejection {
+    match command {
+        Command::Cbor => Rejection::Cbor(AuthenticatorError::RequestTooLarge),
+        _ => Rejection::Transport(CtapHidError::InvalidLength),
+    }
+}
+
+// the length we'll receive of a message declaring `length`, given our buffer
+fn admit_length(command: Command, length: u16, buffer_size: usize, policy: OversizePolicy)
+    -> Result<u16, Rejection>
+{
+    if length as usize <= buffer_size {
+        return Ok(length);
+    }
+    // non-conforming client, or a message larger than we chose to take
+    match policy {
+        OversizePolicy::Reject => Err(oversize_rejection(command)),
+        OversizePolicy::Clamp => Ok(buffer_size as u16),
+    }
+}
+
+// INIT carries an 8 byte nonce, anything else would only make us wait
+// for continuation packets that need never come. WINK carries nothing.
+fn check_length(command: Command, length: u16) -> Result<(), CtapHidError> {
+    match command {
+        Command::Init if length != 8 => Err(CtapHidError::InvalidLength),
+        Command::Wink if length != 0 => Err(CtapHidError::InvalidLength),
+        _ => Ok(()),
+    }
+}
+
+// lower bound on the CBOR parameters' length, from their mandatory members
+fn minimum_parameters_length(operation: &Operation) -> usize {
+    match operation {
+        // map(4), 1: bstr(32), 2: {"id": ""}, 3: {"id": h''}, 4: []
+        Operation::MakeCredential => 1 + (1 + 34) + (1 + 5) + (1 + 5) + (1 + 1),
+        // map(2), 1: "", 2: bstr(32)
+        Operation::GetAssertion => 1 + (1 + 1) + (1 + 34),
+        // map(2), 1: pinProtocol, 2: subCommand
+        Operation::ClientPin => 1 + 2 + 2,
+        // map(1), 1: subCommand
+        Operation::CredentialManagement => 1 + 2,
+        _ => 0,
+    }
+}
+
+// keys of the parameters map that may not be left out
+fn required_keys(operation: &Operation) -> &'static [u64] {
+    match operation {
+        // clientDataHash, rp, user, pubKeyCredParams
+        Operation::MakeCredential => &[1, 2, 3, 4],
+        _ => &[],
+    }
+}
+
+// first of the `required` keys that the parameters map lacks,
+// parameters that aren't a well-formed map are not our concern here
+fn first_missing_key(parameters: &[u8], required: &[u64]) -> Option<u64> {
+    if required.is_empty() {
+        return None;
+    }
+    let (major, entries, header) = cbor_header(parameters)?;
+    if major != 5 {
+        return None;
+    }
+
+    let mut present = 0u64;
+    let mut offset = header;
+    for _ in 0..entries {
+        let key = parameters.get(offset..)?;
+        if let (0, key_value, _) = cbor_header(key)? {
+            if let Some(index) = required.iter().position(|&required| required == key_value) {
+                present |= 1 << index;
+            }
+        }
+        offset += cbor_item_length(key, 0)?;
+        offset += cbor_item_length(parameters.get(offset..)?, 0)?;
+    }
+
+    required.iter().enumerate()
+        .find(|&(index, _)| present & (1 << index) == 0)
+        .map(|(_, &key)| key)
+}
+
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+enum VendorRoute {
+    // prototype credential management, which some hosts use as 0x41
+    CredentialManagement,
+    Authenticator,
+    Unhandled,
+}
+
+fn route_vendor_operation(operation: u8, vendor_operations: bool) -> VendorRoute {
+    match operation {
+        0x41 => VendorRoute::CredentialManagement,
+        _ if vendor_operations => VendorRoute::Authenticator,
+        _ => VendorRoute::Unhandled,
+    }
+}
+
+// CTAP2 allows authenticators to reject nesting beyond 4, we're a bit more lenient
+const MAX_CBOR_NESTING: usize = 8;
+
+/// Whether `data` is exactly one well-formed CBOR data item.
+///
+/// Only definite lengths are accepted, as CTAP2 mandates canonical CBOR.
+/// This is purely structural, it says nothing about the item matching
+/// the parameters expected by an operation.
+pub fn cbor_well_formed(data: &[u8]) -> bool {
+    cbor_item_length(data, 0) == Some(data.len())
+}
+
+// number of bytes taken by the first data item of `data`
+fn cbor_item_length(data: &[u8], depth: usize) -> Option<usize> {
+    if depth > MAX_CBOR_NESTING {
+        return None;
+    }
+
+    let (major, argument, header) = cbor_header(data)?;
+
+    match major {
+        // unsigned and negative integers, simple values and floats
+        0 | 1 | 7 => Some(header),
+        // byte and text strings
+        2 | 3 => {
+            let end = header.checked_add(usize::try_from(argument).ok()?)?;
+            if end <= data.len() { Some(end) } else { None }
+        }
+        // arrays and maps, every item consumes at least one byte
+        4 | 5 => {
+            let items = if major == 5 { argument.checked_mul(2)? } else { argument };
+            let mut offset = header;
+            for _ in 0..items {
+                offset += cbor_item_length(&data[offset..], depth + 1)?;
+            }
+            Some(offset)
+        }
+        // tags
+        6 => Some(header + cbor_item_length(&data[header..], depth + 1)?),
+        _ => None,
+    }
+}
+
+// major type, argument and size of the header of the first data item of `data`
+fn cbor_header(data: &[u8]) -> Option<(u8, u64, usize)> {
+    let initial = *data.get(0)?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    let argument_size = match info {
+        0..=23 => 0,
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        // reserved, or indefinite length
+        _ => return None,
+    };
+    let header = 1 + argument_size;
+    let mut argument = if argument_size == 0 { info as u64 } else { 0 };
+    for byte in data.get(1..header)? {
+        argument = (argument << 8) | *byte as u64;
+    }
+
+    Some((major, argument, header))
+}
 
-                // zeros leftover bytes
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::class::mock::{self, allocate_channel, receive, MockBus, Packets};
+    use usb_device::bus::UsbBusAllocator;
+
+    // status of the one CBOR response written since, which must be on `channel`
+    fn cbor_status(packets: &Packets, channel: u32) -> u8 {
+        let written = packets.take_written();
+        assert_eq!(written.len(), 1);
+        assert_eq!(&written[0][..4], &channel.to_be_bytes());
+        assert_eq!(written[0][4], 0x80 | Command::Cbor.into_u8());
+        written[0][7]
+    }
+
+    #[test]
+    fn packet_view_of_initialization_packet() {
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[..4].copy_from_slice(&[0xca, 0xfe, 0xba, 0xbe]);
+        packet[4] = 0x80 | 0x10;
+        packet[5..7].copy_from_slice(&[0x01, 0x2c]);
+        packet[7] = 0xaa;
+        packet[PACKET_SIZE - 1] = 0xbb;
+
+        let view = PacketView::new(&packet);
+        assert!(view.is_init());
+        assert_eq!(view.channel(), 0xcafe_babe);
+        assert_eq!(view.command_or_seq(), 0x90);
+        assert_eq!(view.command(), 0x10);
+        assert_eq!(view.declared_length(), 300);
+        assert_eq!(view.init_payload().len(), PACKET_SIZE - 7);
+        assert_eq!(view.init_payload()[0], 0xaa);
+        assert_eq!(view.init_payload()[PACKET_SIZE - 8], 0xbb);
+    }
+
+    #[test]
+    fn packet_view_of_continuation_packet() {
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[..4].copy_from_slice(&[0, 0, 0, 1]);
+        packet[4] = 0x7f;
+        packet[5] = 0xaa;
+
+        let view = PacketView::new(&packet);
+        assert!(!view.is_init());
+        assert_eq!(view.channel(), 1);
+        assert_eq!(view.sequence(), 0x7f);
+        assert_eq!(view.cont_payload().len(), PACKET_SIZE - 5);
+        assert_eq!(view.cont_payload()[0], 0xaa);
+    }
+
+    #[test]
+    fn reset_only_after_power_up() {
+        use ctap_types::authenticator::{ctap2, Response};
+
+        let window = Some(RESET_WINDOW_MILLISECONDS);
+        // the app might not tick at all
+        assert!(reset_allowed(window, 0));
+        assert!(reset_allowed(window, 10_000));
+        assert!(!reset_allowed(window, 10_001));
+        assert!(reset_allowed(None, 3_600_000));
+
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // passed on right after power-up, success is a bare 0x00
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), 0x00);
+
+        // denied by the pipe itself later on
+        pipe.tick(RESET_WINDOW_MILLISECONDS + 1);
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::OperationDenied as u8);
+        assert!(authenticator.request().is_none());
+    }
+
+    #[test]
+    fn msg_only_with_ctap1() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+
+        receive(&mut pipe, &packets, 0xFFFF_FFFF, Command::Init, &[0x42; 8]);
+        let written = packets.take_written();
+        assert!(Capabilities(written[0][7 + 16]).contains(Capabilities::NMSG));
+        let channel = u32::from_be_bytes([written[0][15], written[0][16], written[0][17], written[0][18]]);
+
+        receive(&mut pipe, &packets, channel, Command::Msg, &[0x00, 0x03, 0x00, 0x00]);
+        let written = packets.take_written();
+        assert_eq!(written[0][4], 0x80 | Command::Error.into_u8());
+        assert_eq!(written[0][7], CtapHidError::InvalidCommand as u8);
+        assert!(pipe.pending_msg().is_none());
+
+        // the app answers MSG, so hosts may try U2F
+        pipe.set_ctap1(true);
+        receive(&mut pipe, &packets, 0xFFFF_FFFF, Command::Init, &[0x42; 8]);
+        let capabilities = Capabilities(packets.take_written()[0][7 + 16]);
+        assert!(!capabilities.contains(Capabilities::NMSG));
+        assert!(capabilities.contains(Capabilities::CBOR));
+    }
+
+    #[test]
+    fn msg_is_answered_by_the_app() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_ctap1(true);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // U2F_VERSION, with extended length Le
+        let apdu = [0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00];
+        receive(&mut pipe, &packets, channel, Command::Msg, &apdu);
+        assert!(packets.take_written().is_empty());
+        assert!(authenticator.request().is_none());
+        assert_eq!(pipe.pending_msg(), Some(&apdu[..]));
+
+        // neither time nor RPC answers it
+        pipe.tick(OPERATION_TIMEOUT_MILLISECONDS + 1);
+        pipe.handle_response();
+        assert!(packets.take_written().is_empty());
+
+        pipe.answer_msg(b"U2F_V2\x90\x00");
+        let response = build_single_packet(channel, Command::Msg, b"U2F_V2\x90\x00");
+        assert_eq!(packets.take_written(), [response.to_vec()]);
+        assert!(pipe.pending_msg().is_none());
+
+        // late answers go nowhere
+        pipe.answer_msg(b"\x90\x00");
+        assert!(packets.take_written().is_empty());
+    }
+
+    #[test]
+    fn dispatch_only_when_request_is_complete() {
+        let request = Request { channel: 2, command: Command::Cbor, length: 100 };
+        assert!(ready_to_dispatch(&State::Idle, &request));
+        let receiving = State::Receiving((request, MessageState::default()));
+        assert!(ready_to_dispatch(&receiving, &request));
+
+        // internal confusion: some other request, or still busy with the previous one
+        let other = Request { channel: 3, ..request };
+        assert!(!ready_to_dispatch(&receiving, &other));
+        assert!(!ready_to_dispatch(&State::WaitingOnAuthenticator(request), &request));
+        let response = Response::from_request_and_size(request, 10);
+        assert!(!ready_to_dispatch(&State::WaitingToSend(response), &request));
+        assert!(!ready_to_dispatch(&State::Sending((response, MessageState::default())), &request));
+    }
+
+    // a bug on our side, loud in debug builds, while release builds recover
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "dispatching request in state"))]
+    fn dispatch_in_wrong_state() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+
+        let request = Request { channel: 2, command: Command::Ping, length: 8 };
+        pipe.state = State::WaitingOnAuthenticator(request);
+        pipe.dispatch_request(request);
+
+        let other = build_single_packet(2, Command::Error, &[CtapHidError::Other as u8]);
+        assert_eq!(packets.take_written(), [other.to_vec()]);
+        assert_eq!(pipe.state, State::Idle);
+    }
+
+    #[test]
+    fn keepalives_while_authenticator_works() {
+        // the authenticator got the request at 5 ms, ticks come every 30 ms
+        let mut last_keepalive_ms = 5;
+        let mut keepalives = 0;
+        for now_ms in (35..=500).step_by(30) {
+            if keepalive_due(last_keepalive_ms, now_ms) {
+                last_keepalive_ms = now_ms;
+                keepalives += 1;
+            }
+        }
+        // at 125, 245, 365 and 485 ms
+        assert_eq!((keepalives, last_keepalive_ms), (4, 485));
+
+        // the clock wraps around
+        assert!(!keepalive_due(u32::max_value() - 10, 50));
+        assert!(keepalive_due(u32::max_value() - 10, 100));
+    }
+
+    #[test]
+    fn keepalive_frame_layout() {
+        let packet = build_keepalive(0xcafe_babe, KeepAliveStatus::UpNeeded);
+        assert_eq!(&packet[..4], &[0xca, 0xfe, 0xba, 0xbe]);
+        // CTAPHID_KEEPALIVE with initialization bit
+        assert_eq!(packet[4], 0xbb);
+        assert_eq!(&packet[5..7], &[0x00, 0x01]);
+        assert_eq!(packet[7], 0x02);
+        assert!(packet[8..].iter().all(|byte| *byte == 0));
+
+        let packet = build_keepalive(1, KeepAliveStatus::Processing);
+        assert_eq!(packet[7], 0x01);
+    }
+
+    #[test]
+    fn multi_packet_messages_are_reassembled_exactly() {
+        let channel = 0xcafe_babe;
+        let mut original = [0u8; MESSAGE_SIZE];
+        for (i, byte) in original.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+
+        // 57 + 59 = 116 ends exactly on a packet boundary
+        for &length in [58, 116, 117, 200, MESSAGE_SIZE].iter() {
+            let message = &original[..length];
+
+            // host to device: initialization packet, then continuation packets
+            let mut packet = [0u8; PACKET_SIZE];
+            packet[..4].copy_from_slice(&channel.to_be_bytes());
+            // CTAPHID_PING
+            packet[4] = 0x80 | 0x01;
+            packet[5..7].copy_from_slice(&(length as u16).to_be_bytes());
+            packet[7..].copy_from_slice(&message[..PACKET_SIZE - 7]);
+            let init = PacketView::new(&packet);
+            assert_eq!(init.declared_length() as usize, length);
+
+            let mut buffer = [0u8; MESSAGE_SIZE];
+            buffer[..PACKET_SIZE - 7].copy_from_slice(init.init_payload());
+            let mut message_state = MessageState::default();
+            let chunks = message[PACKET_SIZE - 7..].chunks(PACKET_SIZE - 5);
+            let last = chunks.len() - 1;
+            for (sequence, chunk) in chunks.enumerate() {
                 let mut packet = [0u8; PACKET_SIZE];
-                packet[..4].copy_from_slice(&response.channel.to_be_bytes());
-                // packet[4] = response.command.into() | 0x80u8;
-                packet[4] = response.command.into_u8() | 0x80;
-                packet[5..7].copy_from_slice(&response.length.to_be_bytes());
-
-                let fits_in_one_packet = 7 + response.length as usize <= PACKET_SIZE;
-                if fits_in_one_packet {
-                    packet[7..][..response.length as usize]
-                        .copy_from_slice( &self.buffer[..response.length as usize]);
-                    self.state = State::Idle;
-                } else {
-                    packet[7..].copy_from_slice(&self.buffer[..PACKET_SIZE - 7]);
+                packet[..4].copy_from_slice(&channel.to_be_bytes());
+                packet[4] = sequence as u8;
+                packet[5..][..chunk.len()].copy_from_slice(chunk);
+                let cont = PacketView::new(&packet);
+                assert_eq!(cont.sequence(), message_state.next_sequence);
+                let complete = message_state.receive_continuation(&mut buffer[..length], cont.cont_payload());
+                assert_eq!(complete, sequence == last);
+            }
+            assert_eq!(&buffer[..length], message);
+            // nothing written past the declared length
+            assert!(buffer[length..].iter().all(|byte| *byte == 0));
+
+            // device to host: the ping response echoes the buffer
+            let packet = build_init_packet(channel, Command::Ping, &buffer[..length]);
+            let init = PacketView::new(&packet);
+            assert_eq!(init.command(), 0x01);
+            assert_eq!(init.declared_length() as usize, length);
+            let mut echoed = [0u8; MESSAGE_SIZE];
+            echoed[..PACKET_SIZE - 7].copy_from_slice(init.init_payload());
+            let mut received = PACKET_SIZE - 7;
+
+            let mut message_state = MessageState::default();
+            loop {
+                let (packet, last_packet) = message_state.continuation_packet(channel, &buffer[..length]);
+                let cont = PacketView::new(&packet);
+                assert_eq!(cont.channel(), channel);
+                assert_eq!(cont.sequence(), message_state.next_sequence);
+                let chunk = (length - received).min(PACKET_SIZE - 5);
+                echoed[received..][..chunk].copy_from_slice(&cont.cont_payload()[..chunk]);
+                received += chunk;
+                // unused bytes are zeroed
+                assert!(cont.cont_payload()[chunk..].iter().all(|byte| *byte == 0));
+                if last_packet {
+                    break;
                 }
+                message_state.absorb_packet();
+            }
+            assert_eq!(received, length);
+            assert_eq!(&echoed[..length], message);
+        }
+    }
 
-                // try actually sending
-                // hprintln!("attempting to write init packet {:?}, {:?}",
-                //           &packet[..32], &packet[32..]).ok();
-                let result = self.write_endpoint.write(&packet);
+    #[test]
+    fn message_packets_of_multi_packet_response() {
+        let message = [0x42u8; 200];
+        let mut packets = MessagePackets::new(7, Command::Cbor, &message);
+        assert!(packets.more_pending());
 
-                match result {
-                    Err(UsbError::WouldBlock) => {
-                        // fine, can't write try later
-                        // this shouldn't happen probably
-                    },
-                    Err(_) => {
-                        // hprintln!("weird USB errrorrr").ok();
-                        panic!("unexpected error writing packet!");
-                    },
-                    Ok(PACKET_SIZE) => {
-                        // goodie, this worked
-                        if fits_in_one_packet {
-                            self.state = State::Idle;
-                            // hprintln!("StartSent {} bytes, idle again", response.length).ok();
-                            // hprintln!("IDLE again").ok();
-                        } else {
-                            self.state = State::Sending((response, MessageState::default()));
-                            // hprintln!(
-                            //     "StartSent {} of {} bytes, waiting to send again",
-                            //     PACKET_SIZE - 7, response.length).ok();
-                            // hprintln!("State: {:?}", &self.state).ok();
-                        }
-                    },
-                    Ok(_) => {
-                        // hprintln!("short write").ok();
-                        panic!("unexpected size writing packet!");
-                    },
+        let packet = packets.next().unwrap();
+        let init = PacketView::new(&packet);
+        assert!(init.is_init());
+        assert_eq!(init.command(), 0x10);
+        assert_eq!(init.declared_length(), 200);
+
+        // 57 + 59 + 59 + 25
+        let mut packet = [0u8; PACKET_SIZE];
+        for sequence in 0..3 {
+            assert!(packets.more_pending());
+            packet = packets.next().unwrap();
+            let cont = PacketView::new(&packet);
+            assert_eq!(cont.channel(), 7);
+            assert_eq!(cont.sequence(), sequence);
+        }
+        let payload = PacketView::new(&packet).cont_payload();
+        assert!(payload[..25].iter().all(|byte| *byte == 0x42));
+        assert!(payload[25..].iter().all(|byte| *byte == 0));
+        assert!(!packets.more_pending());
+        assert!(packets.next().is_none());
+
+        // short responses are a single packet
+        let mut packets = MessagePackets::new(7, Command::Ping, &message[..57]);
+        assert!(packets.next().is_some());
+        assert!(!packets.more_pending());
+        assert_eq!(packets.count(), 0);
+    }
+
+    #[test]
+    fn cbor_well_formedness() {
+        // {1: 2}
+        assert!(cbor_well_formed(&[0xa1, 0x01, 0x02]));
+        // {1: [h'aabb', "x"], -1: {}}
+        assert!(cbor_well_formed(&[0xa2, 0x01, 0x82, 0x42, 0xaa, 0xbb, 0x61, 0x78, 0x20, 0xa0]));
+        // 1000, two byte argument
+        assert!(cbor_well_formed(&[0x19, 0x03, 0xe8]));
+
+        // trailing garbage
+        assert!(!cbor_well_formed(&[0xa1, 0x01, 0x02, 0xff]));
+        assert!(!cbor_well_formed(&[0xa1, 0x01, 0x02, 0x00]));
+        // truncated map, string and argument
+        assert!(!cbor_well_formed(&[0xa2, 0x01, 0x02]));
+        assert!(!cbor_well_formed(&[0x43, 0xaa, 0xbb]));
+        assert!(!cbor_well_formed(&[0x19, 0x03]));
+        // indefinite length map
+        assert!(!cbor_well_formed(&[0xbf, 0x01, 0x02, 0xff]));
+        // huge declared lengths don't overflow
+        assert!(!cbor_well_formed(&[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]));
+        assert!(!cbor_well_formed(&[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]));
+        // excessive nesting
+        assert!(!cbor_well_formed(&[0x81; 16]));
+        assert!(!cbor_well_formed(&[]));
+    }
+
+    #[test]
+    fn vendor_operation_routing() {
+        // the prototype credential management is always there
+        assert_eq!(route_vendor_operation(0x41, false), VendorRoute::CredentialManagement);
+        assert_eq!(route_vendor_operation(0x41, true), VendorRoute::CredentialManagement);
+
+        assert_eq!(route_vendor_operation(0x42, false), VendorRoute::Unhandled);
+        assert_eq!(route_vendor_operation(0x42, true), VendorRoute::Authenticator);
+        assert_eq!(route_vendor_operation(0x7f, true), VendorRoute::Authenticator);
+    }
+
+    #[test]
+    fn unsupported_operations_get_an_error() {
+        use ctap_types::authenticator::{ctap2, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // vendor operations are passed on by default
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x40, 0xa0]);
+        assert!(authenticator.request().is_some());
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Vendor)));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), 0);
+
+        // unless the authenticator has none
+        pipe.set_vendor_operations(false);
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x40, 0xa0]);
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::InvalidCommand as u8);
+        assert_eq!(pipe.state, State::Idle);
+
+        // not even an operation
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[]);
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::InvalidLength as u8);
+        assert_eq!(pipe.state, State::Idle);
+
+        // neither of these bothers the authenticator
+        assert!(authenticator.request().is_none());
+    }
+
+    #[test]
+    fn one_vendor_stream_at_a_time() {
+        let mut lock = StreamLock::default();
+        assert!(lock.try_acquire(1, 0));
+        // the owner may continue, others have to wait
+        assert!(lock.try_acquire(1, 100));
+        assert!(!lock.try_acquire(2, 200));
+
+        // a stalled stream is abandoned
+        let stalled = 100 + VENDOR_STREAM_TIMEOUT_MILLISECONDS;
+        assert!(!lock.is_active(stalled));
+        assert!(lock.try_acquire(2, stalled));
+        assert!(!lock.try_acquire(1, stalled + 1));
+
+        // only the owner can release
+        lock.release(1);
+        assert!(lock.is_active(stalled + 1));
+        lock.release(2);
+        assert!(!lock.is_active(stalled + 1));
+        assert!(lock.try_acquire(1, stalled + 1));
+    }
+
+    #[test]
+    fn truncated_get_assertion() {
+        // {1: "", 2: h'00..00'}, the shortest possible get-assertion
+        let mut parameters = [0u8; 38];
+        parameters[..5].copy_from_slice(&[0xa2, 0x01, 0x60, 0x02, 0x58]);
+        parameters[5] = 32;
+        assert!(cbor_well_formed(&parameters));
+        assert_eq!(minimum_parameters_length(&Operation::GetAssertion), parameters.len());
+
+
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // one byte short of the hash, still well-formed
+        let mut request = [0u8; 1 + 37];
+        request[0] = 0x02;
+        request[1..7].copy_from_slice(&[0xa2, 0x01, 0x60, 0x02, 0x58, 31]);
+        receive(&mut pipe, &packets, channel, Command::Cbor, &request);
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::InvalidLength as u8);
+        assert!(authenticator.request().is_none());
+
+        // the full one goes to the authenticator
+        let mut request = [0u8; 1 + 38];
+        request[0] = 0x02;
+        request[1..].copy_from_slice(&parameters);
+        receive(&mut pipe, &packets, channel, Command::Cbor, &request);
+        assert!(packets.take_written().is_empty());
+        assert!(authenticator.request().is_some());
+    }
+
+    #[test]
+    fn user_interaction_is_announced() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // get-info involves no user
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x04]);
+        assert!(authenticator.request().is_some());
+        assert_eq!(pipe.take_user_interaction(), None);
+        authenticator.respond(Err(AuthenticatorError::Other));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::Other as u8);
+
+        // {1: "", 2: h'00..00'}, as soon as it is passed on
+        let mut request = [0u8; 1 + 38];
+        request[..7].copy_from_slice(&[0x02, 0xa2, 0x01, 0x60, 0x02, 0x58, 32]);
+        receive(&mut pipe, &packets, channel, Command::Cbor, &request);
+        assert_eq!(pipe.take_user_interaction(), Some(Operation::GetAssertion));
+        assert_eq!(pipe.take_user_interaction(), None);
+        assert!(authenticator.request().is_some());
+    }
+
+    #[test]
+    fn make_credential_missing_required_key() {
+        // {1: h'00..00', 2: {"id": "a"}, 3: {"id": h'01'}, 4: [{"alg": -7, "type": "public-key"}]}
+        let client_data_hash: &[u8] = &[0x01, 0x58, 0x20];
+        let rp: &[u8] = &[0x02, 0xa1, 0x62, b'i', b'd', 0x61, b'a'];
+        let user: &[u8] = &[0x03, 0xa1, 0x62, b'i', b'd', 0x41, 0x01];
+        let pub_key_cred_params: &[u8] = &[
+            0x04, 0x81, 0xa2, 0x63, b'a', b'l', b'g', 0x26,
+            0x64, b't', b'y', b'p', b'e', 0x6a,
+            b'p', b'u', b'b', b'l', b'i', b'c', b'-', b'k', b'e', b'y',
+        ];
+
+        let required = required_keys(&Operation::MakeCredential);
+        for omitted in 0..=4 {
+            let mut parameters = [0u8; 128];
+            let mut length = 1;
+            let mut entries = 0;
+            for key in 1..=4 {
+                if key == omitted {
+                    continue;
+                }
+                let entry = match key {
+                    1 => client_data_hash,
+                    2 => rp,
+                    3 => user,
+                    _ => pub_key_cred_params,
                 };
-            },
+                parameters[length..][..entry.len()].copy_from_slice(entry);
+                length += entry.len();
+                if key == 1 {
+                    // the hash itself, all zeros
+                    length += 32;
+                }
+                entries += 1;
+            }
+            parameters[0] = 0xa0 | entries;
+            let parameters = &parameters[..length];
+            assert!(cbor_well_formed(parameters));
 
-            State::Sending((response, mut message_state)) => {
-                // hprintln!("in StillSending").ok();
-                let mut packet = [0u8; PACKET_SIZE];
-                packet[..4].copy_from_slice(&response.channel.to_be_bytes());
-                packet[4] = message_state.next_sequence;
+            let missing = first_missing_key(parameters, required);
+            if omitted == 0 {
+                assert_eq!(missing, None);
+            } else {
+                assert_eq!(missing, Some(omitted as u64));
+            }
+        }
 
-                let sent = message_state.transmitted;
-                let remaining = response.length as usize - sent;
-                let last_packet = 5 + remaining <= PACKET_SIZE;
-                if last_packet {
-                    packet[5..][..remaining].copy_from_slice(
-                        &self.buffer[message_state.transmitted..][..remaining]);
-                } else {
-                    packet[5..].copy_from_slice(
-                        &self.buffer[message_state.transmitted..][..PACKET_SIZE - 5]);
+        // extra keys, in whatever order, don't hide a missing one
+        let parameters = [0xa3, 0x04, 0x80, 0x07, 0xa0, 0x02, 0xa0];
+        assert_eq!(first_missing_key(&parameters, required), Some(1));
+        // not a map, deserialization will complain about that
+        assert_eq!(first_missing_key(&[0x80], required), None);
+        assert_eq!(first_missing_key(&[0xa0], required_keys(&Operation::GetInfo)), None);
+    }
+
+    #[test]
+    fn lock_owner_until_expiry() {
+        let mut lock = ChannelLock::default();
+        assert_eq!(lock.owner(0), None);
+
+        lock.lock(3, 10, 1_000);
+        assert_eq!(lock.owner(1_000), Some(3));
+        assert_eq!(lock.remaining_ms(1_000), Some(10_000));
+        assert!(lock.blocks(4, 5_000));
+        assert!(!lock.blocks(3, 5_000));
+        assert_eq!(lock.remaining_ms(10_999), Some(1));
+
+        // expired
+        assert_eq!(lock.owner(11_000), None);
+        assert_eq!(lock.remaining_ms(11_000), None);
+        assert!(!lock.blocks(4, 11_000));
+
+        // released early, also across the clock wrapping
+        lock.lock(3, 1, u32::MAX - 100);
+        assert_eq!(lock.owner(100), Some(3));
+        lock.lock(3, 0, 200);
+        assert_eq!(lock.owner(200), None);
+    }
+
+    #[test]
+    fn lock_then_expire() {
+        assert_eq!(lock_seconds(&[10]), Ok(10));
+        assert_eq!(lock_seconds(&[11]), Err(CtapHidError::InvalidParameter));
+        assert_eq!(lock_seconds(&[]), Err(CtapHidError::InvalidParameter));
+        assert_eq!(lock_seconds(&[1, 0]), Err(CtapHidError::InvalidParameter));
+
+        let mut lock = ChannelLock::default();
+        lock.lock(1, lock_seconds(&[2]).unwrap(), 0);
+        assert!(lock.blocks(2, 1_999));
+        assert!(!lock.blocks(2, 2_000));
+        // zero releases
+        lock.lock(1, lock_seconds(&[2]).unwrap(), 3_000);
+        lock.lock(1, lock_seconds(&[0]).unwrap(), 3_001);
+        assert!(!lock.blocks(2, 3_002));
+    }
+
+    #[test]
+    fn small_message_buffer() {
+        const SMALL: usize = 1200;
+        let reject = OversizePolicy::Reject;
+        assert_eq!(admit_length(Command::Ping, 1200, SMALL, reject).ok(), Some(1200));
+        match admit_length(Command::Ping, 1201, SMALL, reject) {
+            Err(Rejection::Transport(error)) => assert_eq!(error, CtapHidError::InvalidLength),
+            result => panic!("unexpected {:?}", result),
+        }
+        match admit_length(Command::Cbor, 1201, SMALL, reject) {
+            Err(Rejection::Cbor(error)) => assert_eq!(error as u8, 0x39),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(admit_length(Command::Ping, 1201, SMALL, OversizePolicy::Clamp).ok(), Some(1200));
+        // fine with the default buffer
+        assert_eq!(admit_length(Command::Ping, 1201, MESSAGE_SIZE, reject).ok(), Some(1201));
+
+        // and what get-info tells the host
+        assert_eq!(advertised_max_msg_size(None, SMALL), SMALL);
+        assert_eq!(advertised_max_msg_size(Some(MESSAGE_SIZE), SMALL), SMALL);
+        assert_eq!(advertised_max_msg_size(Some(1024), SMALL), 1024);
+    }
+
+    #[test]
+    fn allocated_channels_reported_once() {
+        // two INITs before the app gets around to it, then another one
+        assert_eq!(newly_allocated(0, 2), 1..3);
+        assert_eq!(newly_allocated(2, 3), 3..4);
+        // nothing new
+        assert!(newly_allocated(3, 3).next().is_none());
+    }
+
+    #[test]
+    fn only_allocated_channels_are_valid() {
+        // nothing allocated yet
+        assert_eq!(check_channel(1, Command::Ping, 0), Err(CtapHidError::InvalidChannel));
+        assert_eq!(check_channel(0xFFFF_FFFF, Command::Init, 0), Ok(()));
+
+        assert_eq!(check_channel(1, Command::Ping, 2), Ok(()));
+        assert_eq!(check_channel(2, Command::Cbor, 2), Ok(()));
+        assert_eq!(check_channel(3, Command::Ping, 2), Err(CtapHidError::InvalidChannel));
+        // reserved
+        assert_eq!(check_channel(0, Command::Init, 2), Err(CtapHidError::InvalidChannel));
+        // broadcast is for INIT only
+        assert_eq!(check_channel(0xFFFF_FFFF, Command::Ping, 2), Err(CtapHidError::InvalidChannel));
+    }
+
+    #[test]
+    fn continuation_of_complete_message_copies_nothing() {
+        let payload = [0xffu8; PACKET_SIZE - 5];
+        let mut buffer = [0u8; 2 * PACKET_SIZE];
+
+        // declared length exactly what has been transmitted so far
+        for &length in [PACKET_SIZE - 7, PACKET_SIZE - 7 + PACKET_SIZE - 5].iter() {
+            let mut message_state = MessageState::default();
+            if length > PACKET_SIZE - 7 {
+                assert!(!message_state.receive_continuation(&mut buffer[..length + 1], &payload));
+                buffer = [0u8; 2 * PACKET_SIZE];
+            }
+            assert_eq!(message_state.transmitted, length);
+
+            let sequence = message_state.next_sequence;
+            assert!(message_state.receive_continuation(&mut buffer[..length], &payload));
+            assert_eq!(message_state.transmitted, length);
+            assert_eq!(message_state.next_sequence, sequence);
+            assert!(buffer.iter().all(|byte| *byte == 0));
+        }
+    }
+
+    #[test]
+    fn cancel_concerns_the_active_channel() {
+        // a multi-packet CBOR request, partially received
+        let request = Request { channel: 3, command: Command::Cbor, length: 300 };
+        let receiving = State::Receiving((request, MessageState::default()));
+        assert_eq!(cancel_action(&receiving, 3), CancelAction::WhenReceived);
+        assert_eq!(cancel_action(&receiving, 4), CancelAction::Ignore);
+
+        // now with the authenticator
+        let processing = State::WaitingOnAuthenticator(request);
+        assert_eq!(cancel_action(&processing, 3), CancelAction::Now(request));
+        assert_eq!(cancel_action(&processing, 4), CancelAction::Ignore);
+        assert_eq!(AuthenticatorError::KeepaliveCancel as u8, 0x2d);
+
+        // nothing to cancel
+        assert_eq!(cancel_action(&State::Idle, 3), CancelAction::Ignore);
+        let ping = Request { channel: 3, command: Command::Ping, length: 300 };
+        assert_eq!(cancel_action(&State::Receiving((ping, MessageState::default())), 3), CancelAction::Ignore);
+    }
+
+    #[test]
+    fn error_responses() {
+        assert!(is_error_response(Command::Cbor, 0x2e));
+        assert!(!is_error_response(Command::Cbor, 0x00));
+        assert!(is_error_response(Command::Error, 0x00));
+        // echoed data, whatever it looks like
+        assert!(!is_error_response(Command::Ping, 0x2e));
+    }
+
+    #[test]
+    fn oversized_cbor_requests_are_too_large() {
+        match oversize_rejection(Command::Cbor) {
+            Rejection::Cbor(error) => assert_eq!(error as u8, 0x39),
+            rejection => panic!("unexpected {:?}", rejection),
+        }
+        match oversize_rejection(Command::Ping) {
+            Rejection::Transport(error) => assert_eq!(error, CtapHidError::InvalidLength),
+            rejection => panic!("unexpected {:?}", rejection),
+        }
+    }
+
+    #[test]
+    fn oversized_messages_by_policy() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // the host declares more than any buffer takes, then sends what fits
+        fn receive_oversized(pipe: &mut Pipe<MockBus>, packets: &Packets, channel: u32, command: Command, message: &[u8]) {
+            for (i, mut packet) in MessagePackets::new(channel, command, message).enumerate() {
+                if i == 0 {
+                    packet[5..7].copy_from_slice(&[0xff, 0xff]);
                 }
+                packets.send(&packet);
+                pipe.read_and_handle_packet();
+            }
+        }
+        let mut message = [0u8; MESSAGE_SIZE];
+        for (i, byte) in message.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
 
-                // try actually sending
-                // hprintln!("attempting to write cont packet {:?}, {:?}",
-                //           &packet[..32], &packet[32..]).ok();
-                let result = self.write_endpoint.write(&packet);
+        // rejected, the continuation packets are dropped
+        receive_oversized(&mut pipe, &packets, channel, Command::Ping, &message);
+        let invalid_length = build_single_packet(channel, Command::Error, &[CtapHidError::InvalidLength as u8]);
+        assert_eq!(packets.take_written(), [invalid_length.to_vec()]);
+        assert_eq!(pipe.state, State::Idle);
+
+        receive_oversized(&mut pipe, &packets, channel, Command::Cbor, &message);
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::RequestTooLarge as u8);
+        assert_eq!(pipe.state, State::Idle);
+
+        // clamped, the message is processed as far as the buffer goes
+        pipe.set_oversize_policy(OversizePolicy::Clamp);
+        receive_oversized(&mut pipe, &packets, channel, Command::Ping, &message);
+        pipe.flush();
+        let written = packets.take_written();
+        assert_eq!(written.len(), MessagePackets::new(channel, Command::Ping, &message).count());
+        for (written, echoed) in written.iter().zip(MessagePackets::new(channel, Command::Ping, &message)) {
+            assert_eq!(written[..], echoed[..]);
+        }
+        assert_eq!(pipe.state, State::Idle);
+    }
 
-                match result {
-                    Err(UsbError::WouldBlock) => {
-                        // fine, can't write try later
-                        // this shouldn't happen probably
-                        // hprintln!("can't send seq {}, write endpoint busy",
-                        //           message_state.next_sequence).ok();
-                    },
-                    Err(_) => {
-                        // hprintln!("weird USB error").ok();
-                        panic!("unexpected error writing packet!");
-                    },
-                    Ok(PACKET_SIZE) => {
-                        // goodie, this worked
-                        if last_packet {
-                            self.state = State::Idle;
-                            // hprintln!("in IDLE state after {:?}", &message_state).ok();
-                        } else {
-                            message_state.absorb_packet();
-                            // DANGER! destructuring in the match arm copies out
-                            // message state, so need to update state
-                            // hprintln!("sent one more, now {:?}", &message_state).ok();
-                            self.state = State::Sending((response, message_state));
-                        }
-                    },
-                    Ok(_) => {
-                        debug!("short write").ok();
-                        panic!("unexpected size writing packet!");
-                    },
-                };
-            },
+    #[test]
+    fn init_must_carry_a_nonce() {
+        assert_eq!(check_length(Command::Init, 8), Ok(()));
+        // more than one packet, so it would otherwise enter the receiving state
+        assert_eq!(check_length(Command::Init, 200), Err(CtapHidError::InvalidLength));
+        assert_eq!(check_length(Command::Init, 0), Err(CtapHidError::InvalidLength));
+        assert_eq!(check_length(Command::Ping, 200), Ok(()));
+    }
 
-            // nothing to send
-            _ => {
-            },
+    #[test]
+    #[cfg(feature = "transaction-history")]
+    fn transaction_history_evicts_oldest() {
+        let transaction = |channel| TransactionSummary {
+            channel,
+            command: Command::Cbor,
+            status: 0,
+            length: 1,
+        };
+
+        let mut history = TransactionHistory::default();
+        assert!(history.as_slice().is_empty());
+        for channel in 1..=3 {
+            history.push(transaction(channel));
+        }
+        assert_eq!(history.as_slice(), &[transaction(1), transaction(2), transaction(3)]);
+
+        for channel in 4..=(TRANSACTION_HISTORY_LENGTH as u32 + 2) {
+            history.push(transaction(channel));
+        }
+        let transactions = history.as_slice();
+        assert_eq!(transactions.len(), TRANSACTION_HISTORY_LENGTH);
+        assert_eq!(transactions[0], transaction(3));
+        assert_eq!(transactions[TRANSACTION_HISTORY_LENGTH - 1], transaction(TRANSACTION_HISTORY_LENGTH as u32 + 2));
+    }
+
+    #[test]
+    fn init_echoes_nonce() {
+        let nonce = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        // the request's payload is overwritten
+        let mut buffer = [0xffu8; 32];
+        let capabilities = advertised_capabilities(Capabilities::default(), false);
+        let size = write_init_response(&mut buffer, nonce, 0x0000_0102, capabilities);
+
+        assert_eq!(size, 17);
+        assert_eq!(&buffer[..8], &nonce);
+        assert_eq!(&buffer[8..12], &[0x00, 0x00, 0x01, 0x02]);
+        assert_eq!(&buffer[12..16], &[2, 0, 0, 0]);
+        assert_eq!(buffer[16], capabilities.0);
+    }
+
+    #[test]
+    fn capabilities_changed_between_inits() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+
+        fn capabilities_reported(pipe: &mut Pipe<MockBus>, packets: &Packets) -> u8 {
+            receive(pipe, packets, 0xFFFF_FFFF, Command::Init, &[0x42; 8]);
+            packets.take_written()[0][7 + 16]
+        }
+        // WINK, LOCK, CBOR and NMSG
+        assert_eq!(capabilities_reported(&mut pipe, &packets), 0x0f);
+
+        // e.g. WINK disabled after provisioning
+        pipe.set_capabilities(Capabilities::LOCK | Capabilities::CBOR);
+        assert_eq!(capabilities_reported(&mut pipe, &packets), 0x0e);
+    }
+
+    #[test]
+    fn wink_carries_no_payload() {
+        assert_eq!(check_length(Command::Wink, 0), Ok(()));
+        assert_eq!(check_length(Command::Wink, 1), Err(CtapHidError::InvalidLength));
+    }
+
+    #[test]
+    fn stalled_message_times_out() {
+        let request = Request { channel: 7, command: Command::Cbor, length: 1000 };
+        let mut message_state = MessageState::default();
+        let mut buffer = [0u8; 1000];
+        // two continuation packets arrive, then nothing
+        message_state.receive_continuation(&mut buffer, &[0u8; PACKET_SIZE - 5]);
+        message_state.receive_continuation(&mut buffer, &[0u8; PACKET_SIZE - 5]);
+        let receiving = State::Receiving((request, message_state));
+
+        let last_progress_ms = 1_000;
+        assert_eq!(stalled_channel(&receiving, last_progress_ms, 1_000 + MESSAGE_TIMEOUT_MILLISECONDS - 1), None);
+        assert_eq!(stalled_channel(&receiving, last_progress_ms, 1_000 + MESSAGE_TIMEOUT_MILLISECONDS), Some(7));
+
+        // a host that doesn't pick up the response
+        let response = Response::from_request_and_size(request, 200);
+        let sending = State::Sending((response, MessageState::default()));
+        assert_eq!(stalled_channel(&sending, u32::MAX - 10, 600), Some(7));
+
+        // only messages in transit can stall, the authenticator has its own timeout
+        assert_eq!(stalled_channel(&State::Idle, 0, 10_000), None);
+        assert_eq!(stalled_channel(&State::WaitingOnAuthenticator(request), 0, 10_000), None);
+    }
+
+    #[test]
+    fn empty_ping() {
+        let request = Request { channel: 5, command: Command::Ping, length: 0 };
+        let response = Response::from_request_and_size(request, request.length as usize);
+        // leftovers of earlier messages must not leak out
+        let buffer = [0xffu8; MESSAGE_SIZE];
+
+        let mut sent = [0u8; PACKET_SIZE];
+        let state = write_packet(State::WaitingToSend(response), &buffer, |packet| {
+            sent.copy_from_slice(packet);
+            Ok(PACKET_SIZE)
+        });
+        // a single initialization packet, declaring no payload
+        assert_eq!(state, State::Idle);
+        assert_eq!(&sent[..7], &[0, 0, 0, 5, 0x81, 0, 0]);
+        assert!(sent[7..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn busy_endpoint_is_retried() {
+        let request = Request { channel: 5, command: Command::Ping, length: 100 };
+        let response = Response::from_request_and_size(request, 100);
+        let mut buffer = [0u8; MESSAGE_SIZE];
+        for (i, byte) in buffer[..100].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // endpoint busy at `start_sending`, the response waits
+        let mut state = State::WaitingToSend(response);
+        state = write_packet(state, &buffer, |_| Err(UsbError::WouldBlock));
+        assert_eq!(state, State::WaitingToSend(response));
+
+        // on the following polls, all of it goes out
+        let mut sent = [[0u8; PACKET_SIZE]; 2];
+        let mut packets = 0;
+        let mut attempts = 0;
+        while state != State::Idle {
+            attempts += 1;
+            // and busy again every other time
+            let ready = attempts % 2 == 0;
+            state = write_packet(state, &buffer, |packet| {
+                if !ready {
+                    return Err(UsbError::WouldBlock);
+                }
+                sent[packets].copy_from_slice(packet);
+                packets += 1;
+                Ok(PACKET_SIZE)
+            });
+        }
+        assert_eq!((attempts, packets), (4, 2));
+        assert_eq!(&sent[0][..7], &[0, 0, 0, 5, 0x80 | 0x01, 0, 100]);
+        assert_eq!(&sent[0][7..], &buffer[..PACKET_SIZE - 7]);
+        assert_eq!(&sent[1][..5], &[0, 0, 0, 5, 0]);
+        assert_eq!(&sent[1][5..][..100 - (PACKET_SIZE - 7)], &buffer[PACKET_SIZE - 7..100]);
+
+        // nothing to write when idle
+        let state = write_packet(State::Idle, &buffer, |_| panic!("nothing to send"));
+        assert_eq!(state, State::Idle);
+    }
+
+    #[test]
+    fn second_channel_is_busy() {
+        let request = Request { channel: 1, command: Command::Cbor, length: 200 };
+        let message_state = MessageState::default();
+        let receiving = State::Receiving((request, message_state));
+        assert_eq!(busy_error(&receiving, 2), Some(CtapHidError::ChannelBusy));
+        // the active channel continues as before
+        assert_eq!(check_continuation(&request, &message_state, 1, 0), Ok(()));
+        assert_eq!(busy_error(&receiving, 1), Some(CtapHidError::InvalidSequence));
+
+        let processing = State::WaitingOnAuthenticator(request);
+        assert_eq!(busy_error(&processing, 2), Some(CtapHidError::ChannelBusy));
+        assert_eq!(busy_error(&processing, 1), Some(CtapHidError::ChannelBusy));
+
+        // the busy error goes to the second channel
+        let packet = build_single_packet(2, Command::Error, &[CtapHidError::ChannelBusy as u8]);
+        assert_eq!(&packet[..8], &[0, 0, 0, 2, 0x80 | 0x3f, 0, 1, 0x06]);
+
+        assert_eq!(busy_error(&State::Idle, 2), None);
+    }
+
+    #[test]
+    fn init_resynchronizes_allocated_channel() {
+        use ctap_types::authenticator::{ctap2, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+        let other = allocate_channel(&mut pipe, &packets);
+        pipe.take_allocated_channels();
+
+        let nonce = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        let resynchronized = |pipe: &mut Pipe<MockBus>| {
+            receive(pipe, &packets, channel, Command::Init, &nonce);
+            let written = packets.take_written();
+            assert_eq!(written.len(), 1);
+            assert_eq!(&written[0][..4], &channel.to_be_bytes());
+            assert_eq!(written[0][4], 0x80 | Command::Init.into_u8());
+            assert_eq!(&written[0][5..7], &[0, 17]);
+            assert_eq!(&written[0][7..15], &nonce);
+            // no new channel
+            assert_eq!(&written[0][15..19], &channel.to_be_bytes());
+            assert_eq!(pipe.state, State::Idle);
+        };
+
+        // half a message is dropped
+        let message = [0u8; 100];
+        packets.send(&MessagePackets::new(channel, Command::Cbor, &message).next().unwrap());
+        pipe.read_and_handle_packet();
+        assert!(matches!(pipe.state, State::Receiving(_)));
+        resynchronized(&mut pipe);
+
+        // as is the authenticator's late response
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x04]);
+        assert!(authenticator.request().is_some());
+        resynchronized(&mut pipe);
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+        pipe.handle_response();
+        pipe.flush();
+        assert!(packets.take_written().is_empty());
+
+        // and a response yet to be sent
+        packets.limit_writes(Some(0));
+        receive(&mut pipe, &packets, channel, Command::Ping, &[0x11; 8]);
+        packets.limit_writes(None);
+        resynchronized(&mut pipe);
+
+        // other channels still have to wait
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x04]);
+        receive(&mut pipe, &packets, other, Command::Init, &nonce);
+        let written = packets.take_written();
+        assert_eq!(&written[0][..4], &other.to_be_bytes());
+        assert_eq!(written[0][7], CtapHidError::ChannelBusy as u8);
+        assert!(matches!(pipe.state, State::WaitingOnAuthenticator(_)));
+
+        assert!(pipe.take_allocated_channels().is_empty());
+    }
+
+    #[test]
+    fn second_channel_is_busy_while_sending() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let first = allocate_channel(&mut pipe, &packets);
+        let second = allocate_channel(&mut pipe, &packets);
+
+        // a two packet response, of which the host only took the first so far
+        let message = [0x11u8; 100];
+        packets.limit_writes(Some(1));
+        receive(&mut pipe, &packets, first, Command::Ping, &message);
+        assert_eq!(packets.take_written().len(), 1);
+
+        packets.limit_writes(Some(1));
+        receive(&mut pipe, &packets, second, Command::Ping, &[0x22; 8]);
+        let busy = build_single_packet(second, Command::Error, &[CtapHidError::ChannelBusy as u8]);
+        assert_eq!(packets.take_written(), [busy.to_vec()]);
+
+        // the first channel is told nothing, it gets the rest of its response
+        packets.limit_writes(Some(1));
+        receive(&mut pipe, &packets, first, Command::Ping, &[0x33; 8]);
+        assert!(packets.take_written().is_empty());
+        packets.limit_writes(None);
+        pipe.flush();
+        let mut rest = MessagePackets::new(first, Command::Ping, &message).skip(1);
+        assert_eq!(packets.take_written(), [rest.next().unwrap().to_vec()]);
+        assert_eq!(pipe.state, State::Idle);
+    }
+
+    #[test]
+    fn response_length_must_fit() {
+        let request = Request { channel: 5, command: Command::Cbor, length: 10 };
+        let largest = u16::max_value() as usize;
+        assert_eq!(Response::try_from_request_and_size(request, largest).map(|response| response.length), Some(0xffff));
+        assert_eq!(Response::try_from_request_and_size(request, largest + 1), None);
+
+        // in release builds, the host gets ERR_OTHER instead of a bogus length
+        let mut buffer = [0u8; 8];
+        let response = frame_response(&mut buffer, request, largest + 1);
+        assert_eq!(response, Response { channel: 5, command: Command::Error, length: 1 });
+        assert_eq!(buffer[0], 0x7f);
+        let response = frame_response(&mut buffer, request, 8);
+        assert_eq!(response, Response { channel: 5, command: Command::Cbor, length: 8 });
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn oversized_response_is_caught() {
+        let request = Request { channel: 5, command: Command::Cbor, length: 10 };
+        Response::from_request_and_size(request, 70_000);
+    }
+
+    #[test]
+    #[cfg(feature = "legacy-api")]
+    fn reset_round_trip_through_mock_authenticator() {
+        use crate::authenticator::{self, mock::{MockAuthenticator, Presence}, Ctap2Request};
+        use ctap_types::authenticator::{ctap2, Request, Response};
+
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_reset_window(None);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // what the app does with requests the pipe passes on
+        fn serve(rpc: &mut mock::MockRpc, device: &mut MockAuthenticator) {
+            match rpc.request() {
+                Some(Request::Ctap2(ctap2::Request::Reset)) => {},
+                _ => panic!("expected authenticatorReset"),
+            }
+            let response = match authenticator::dispatch(device, &Ctap2Request::Reset) {
+                Ok(_) => Ok(Response::Ctap2(ctap2::Response::Reset)),
+                Err(authenticator::Error::OperationDenied) => Err(AuthenticatorError::OperationDenied),
+                Err(error) => panic!("unexpected error {:?}", error),
+            };
+            rpc.respond(response);
+        }
+        let mut device = MockAuthenticator::default();
+        device.sign_count = 5;
+
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        serve(&mut authenticator, &mut device);
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), 0x00);
+        assert_eq!((device.sign_count, device.presence_requests), (0, 1));
+
+        // the user doesn't confirm
+        device.presence = Presence::Declined;
+        device.sign_count = 5;
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        serve(&mut authenticator, &mut device);
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::OperationDenied as u8);
+        assert_eq!((device.sign_count, device.presence_requests), (5, 2));
+        assert_eq!(pipe.state, State::Idle);
+    }
+
+    #[test]
+    fn stale_response_after_reset_is_dropped() {
+        use ctap_types::authenticator::{ctap2, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_reset_window(None);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // suspended while the authenticator works on a request
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+        pipe.reset();
+        assert!(packets.take_written().is_empty());
+
+        // after resuming, the host asks again
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+
+        // the response to the request from before the reset never goes out
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+        pipe.handle_response();
+        pipe.flush();
+        assert!(packets.take_written().is_empty());
+
+        authenticator.respond(Err(AuthenticatorError::OperationDenied));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::OperationDenied as u8);
+    }
+
+    #[test]
+    fn malformed_packets_never_panic() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_vendor_operations(true);
+        pipe.set_ctap1(true);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // answers go to whoever asked, as whole packets
+        let mut answered = |pipe: &mut Pipe<MockBus>, from: u32| {
+            pipe.flush();
+            for packet in packets.take_written() {
+                assert_eq!(packet.len(), PACKET_SIZE);
+                assert_eq!(&packet[..4], &from.to_be_bytes());
+            }
+            while authenticator.request().is_some() {}
+            pipe.reset();
+        };
+
+        let lengths = [0, 1, 7, 8, 57, 58, 1024, MESSAGE_SIZE as u16, MESSAGE_SIZE as u16 + 1, 0xffff];
+        let fillings = [0x00u8, 0xff, 0x80, 0xa1];
+        for &from in [channel, 0xffff_ffff, 0].iter() {
+            for command in 0x80..=0xffu8 {
+                for &length in lengths.iter() {
+                    for &filling in fillings.iter() {
+                        let mut packet = [filling; PACKET_SIZE];
+                        packet[..4].copy_from_slice(&from.to_be_bytes());
+                        packet[4] = command;
+                        packet[5..7].copy_from_slice(&length.to_be_bytes());
+                        packets.send(&packet);
+                        pipe.read_and_handle_packet();
+                        answered(&mut pipe, from);
+                    }
+                }
+            }
+        }
+
+        // short packets of any size are dropped
+        let init = MessagePackets::new(channel, Command::Ping, &[0x11; 8]).next().unwrap();
+        for size in 0..PACKET_SIZE {
+            packets.send(&init[..size]);
+            pipe.read_and_handle_packet();
+            answered(&mut pipe, channel);
+        }
+
+        // continuations of any sequence number, to a message in progress or none
+        let message = [0x11u8; 300];
+        for sequence in 0..=0x7fu8 {
+            for &receiving in [true, false].iter() {
+                if receiving {
+                    packets.send(&MessagePackets::new(channel, Command::Ping, &message).next().unwrap());
+                    pipe.read_and_handle_packet();
+                }
+                let mut continuation = [sequence; PACKET_SIZE];
+                continuation[..4].copy_from_slice(&channel.to_be_bytes());
+                continuation[4] = sequence;
+                packets.send(&continuation);
+                pipe.read_and_handle_packet();
+                answered(&mut pipe, channel);
+            }
+        }
+
+        // all the while, the pipe kept working
+        receive(&mut pipe, &packets, channel, Command::Ping, &message);
+        pipe.flush();
+        let written = packets.take_written();
+        assert_eq!(written.len(), MessagePackets::new(channel, Command::Ping, &message).count());
+        assert_eq!(pipe.state, State::Idle);
+    }
+
+    #[test]
+    fn spurious_continuation_while_idle() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // the second packet of a message whose first one never arrived
+        let message = [0x11u8; 100];
+        let continuation = MessagePackets::new(channel, Command::Ping, &message).nth(1).unwrap();
+        packets.send(&continuation);
+        pipe.read_and_handle_packet();
+        assert!(packets.take_written().is_empty());
+        assert_eq!(pipe.state, State::Idle);
+
+        // and the next message is unaffected
+        receive(&mut pipe, &packets, channel, Command::Ping, &message);
+        pipe.flush();
+        assert_eq!(packets.take_written().len(), 2);
+        assert_eq!(pipe.state, State::Idle);
+    }
+
+    #[test]
+    fn spurious_continuation_while_processing() {
+        let request = Request { channel: 1, command: Command::Cbor, length: 40 };
+        let processing = State::WaitingOnAuthenticator(request);
+        // the active channel's message is complete, extra packets are ignored
+        assert_eq!(stray_continuation_error(&processing, 1), None);
+        // anyone else is busy, as with init packets
+        assert_eq!(stray_continuation_error(&processing, 2), Some(CtapHidError::ChannelBusy));
+        assert_eq!(stray_continuation_error(&State::Idle, 2), None);
+    }
+
+    #[test]
+    fn spurious_continuation_while_sending() {
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, _authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        let first = allocate_channel(&mut pipe, &packets);
+        let second = allocate_channel(&mut pipe, &packets);
+
+        let message = [0x11u8; 100];
+        packets.limit_writes(Some(1));
+        receive(&mut pipe, &packets, first, Command::Ping, &message);
+        assert_eq!(packets.take_written().len(), 1);
+
+        // the active channel's stray packet is ignored, anyone else's is busy
+        let mut continuation = [0u8; PACKET_SIZE];
+        continuation[..4].copy_from_slice(&first.to_be_bytes());
+        packets.send(&continuation);
+        pipe.read_and_handle_packet();
+        assert!(packets.take_written().is_empty());
+
+        continuation[..4].copy_from_slice(&second.to_be_bytes());
+        packets.send(&continuation);
+        packets.limit_writes(Some(1));
+        pipe.read_and_handle_packet();
+        let busy = build_single_packet(second, Command::Error, &[CtapHidError::ChannelBusy as u8]);
+        assert_eq!(packets.take_written(), [busy.to_vec()]);
+
+        // and the response is unaffected
+        packets.limit_writes(None);
+        pipe.flush();
+        let mut rest = MessagePackets::new(first, Command::Ping, &message).skip(1);
+        assert_eq!(packets.take_written(), [rest.next().unwrap().to_vec()]);
+    }
+
+    #[test]
+    fn wink_during_cbor_processing_is_busy() {
+        use ctap_types::authenticator::{ctap2, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_reset_window(None);
+        let first = allocate_channel(&mut pipe, &packets);
+        let second = allocate_channel(&mut pipe, &packets);
+
+        receive(&mut pipe, &packets, first, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+
+        // same answer on the active channel as on others, and no wink
+        for &channel in [first, second].iter() {
+            receive(&mut pipe, &packets, channel, Command::Wink, &[]);
+            let busy = build_single_packet(channel, Command::Error, &[CtapHidError::ChannelBusy as u8]);
+            assert_eq!(packets.take_written(), [busy.to_vec()]);
+        }
+        assert_eq!(pipe.take_wink(), None);
+
+        // nothing was cancelled by it
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, first), 0x00);
+
+        // once idle, the wink happens, with an empty response
+        receive(&mut pipe, &packets, second, Command::Wink, &[]);
+        assert_eq!(packets.take_written(), [build_single_packet(second, Command::Wink, &[]).to_vec()]);
+        assert_eq!(pipe.take_wink(), Some(WinkPattern::default()));
+    }
+
+    #[test]
+    fn continuation_out_of_sequence_is_an_error() {
+        let request = Request { channel: 7, command: Command::Cbor, length: 200 };
+        let mut message_state = MessageState::default();
+        assert_eq!(check_continuation(&request, &message_state, 7, 0), Ok(()));
+        message_state.absorb_packet();
+        assert_eq!(check_continuation(&request, &message_state, 7, 1), Ok(()));
+
+        // skipped or repeated packets
+        let error = check_continuation(&request, &message_state, 7, 2).unwrap_err();
+        assert_eq!(error, CtapHidError::InvalidSequence);
+        assert_eq!(check_continuation(&request, &message_state, 7, 0), Err(error));
+        // another channel's packet doesn't disturb the transaction
+        assert_eq!(check_continuation(&request, &message_state, 8, 1), Err(CtapHidError::ChannelBusy));
+
+        // the error packet written for it
+        let packet = build_single_packet(7, Command::Error, &[error as u8]);
+        assert_eq!(&packet[..8], &[0, 0, 0, 7, 0x80 | 0x3f, 0, 1, 0x04]);
+        assert!(packet[8..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn get_info_errors_are_other() {
+        let error = map_authenticator_error(true, AuthenticatorError::InvalidCbor);
+        assert_eq!(error as u8, 0x7f);
+        let error = map_authenticator_error(false, AuthenticatorError::InvalidCbor);
+        assert_eq!(error as u8, 0x12);
+    }
+
+    #[test]
+    fn late_responses_after_cancels_are_dropped() {
+        use ctap_types::authenticator::{ctap2, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_reset_window(None);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        // two authenticatorReset requests, each cancelled while processed
+        for _ in 0..2 {
+            receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+            assert!(authenticator.request().is_some());
+            receive(&mut pipe, &packets, channel, Command::Cancel, &[]);
+            assert_eq!(cbor_status(&packets, channel), AuthenticatorError::KeepaliveCancel as u8);
+        }
+
+        // then a third one, which the authenticator denies
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+
+        // first come the late responses to the cancelled requests, they go nowhere
+        for _ in 0..2 {
+            authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+            pipe.handle_response();
+            assert!(packets.take_written().is_empty());
+        }
+        authenticator.respond(Err(AuthenticatorError::OperationDenied));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::OperationDenied as u8);
+    }
+
+    #[test]
+    fn keepalives_until_the_authenticator_times_out() {
+        use ctap_types::authenticator::{ctap2, Response};
+        let packets = Packets::default();
+        let allocate = UsbBusAllocator::new(MockBus::new(64, &packets));
+        let (rpc, mut authenticator) = mock::rpc();
+        let mut pipe = mock::pipe(&allocate, rpc);
+        pipe.set_reset_window(None);
+        let channel = allocate_channel(&mut pipe, &packets);
+
+        pipe.tick(1_000);
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+
+        // the authenticator never answers, the host hears from us regardless
+        let timeout_ms = 1_000 + OPERATION_TIMEOUT_MILLISECONDS;
+        for now_ms in (1_000 + KEEPALIVE_INTERVAL_MILLISECONDS..timeout_ms).step_by(KEEPALIVE_INTERVAL_MILLISECONDS as usize) {
+            pipe.tick(now_ms);
+            let keepalive = build_keepalive(channel, KeepAliveStatus::Processing);
+            assert_eq!(packets.take_written(), [keepalive.to_vec()]);
         }
+        pipe.tick(timeout_ms);
+        assert_eq!(cbor_status(&packets, channel), AuthenticatorError::UserActionTimeout as u8);
+
+        // the timed out request's response doesn't answer the next one
+        receive(&mut pipe, &packets, channel, Command::Cbor, &[0x07]);
+        assert!(authenticator.request().is_some());
+        authenticator.respond(Err(AuthenticatorError::OperationDenied));
+        pipe.handle_response();
+        assert!(packets.take_written().is_empty());
+        authenticator.respond(Ok(Response::Ctap2(ctap2::Response::Reset)));
+        pipe.handle_response();
+        assert_eq!(cbor_status(&packets, channel), 0x00);
     }
 }
@@ -0,0 +1,54 @@
+/*!
+The types most users of this crate need, in one place.
+
+`CtapHid` passes requests on to the authenticator as ctap-types' RPC,
+so the request, response and error types are ctap-types' as well.
+
+```
+use usbd_ctaphid::prelude::*;
+
+// transport-level commands and errors
+assert_eq!(Command::Cbor.into_u8(), 0x10);
+let error: u8 = CtapHidError::ChannelBusy as u8;
+assert_eq!(error, 0x06);
+
+// CTAP2 operations, and the status byte their responses start with
+fn status(operation: Operation) -> CtapStatus {
+    match operation {
+        Operation::MakeCredential | Operation::GetAssertion => CtapStatus::Success,
+        _ => CtapStatus::InvalidCommand,
+    }
+}
+let success: u8 = status(Operation::GetAssertion).into();
+assert_eq!(success, 0x00);
+
+let capabilities = Capabilities::WINK | Capabilities::CBOR;
+assert!(capabilities.contains(Capabilities::CBOR));
+
+// what the authenticator gets via the `TransportEndpoint`, and answers
+fn answer(request: Request) -> Result<Response, AuthenticatorError> {
+    match request {
+        Request::Ctap2(ctap2::Request::Reset) => Ok(Response::Ctap2(ctap2::Response::Reset)),
+        _ => Err(AuthenticatorError::InvalidCommand),
+    }
+}
+```
+*/
+
+pub use ctap_types::{
+    authenticator::{ctap2, Error as AuthenticatorError, Request, Response},
+    ctaphid::Operation,
+    rpc::TransportEndpoint,
+};
+
+pub use crate::{
+    class::{CtapHid, NoIndicator, StatusIndicator},
+    pipe::{
+        Capabilities,
+        Command,
+        CtapHidError,
+        CtapStatus,
+        OversizePolicy,
+        WinkPattern,
+    },
+};
@@ -36,9 +36,56 @@ pub fn cbor_serialize<T: serde::Serialize>(
 }
 
 pub fn cbor_deserialize<'de, T: serde::Deserialize<'de>>(
-    buffer: &'de [u8],
-) -> core::result::Result<T, ctapcbor::error::Error> {
-    ctapcbor::de::from_bytes(buffer)
+    buffer: &'de mut [u8],
+) -> core::result::Result<T, serde_cbor::Error> {
+    serde_cbor::de::from_mut_slice(buffer)
+}
+
+/// Serialize a COSE public key, in canonical order.
+///
+/// Goes through serde, unless the `cbor-encoder` feature selects the
+/// hand-rolled encoder in `crate::cbor`. Both produce the same bytes.
+#[cfg(not(feature = "cbor-encoder"))]
+pub fn serialize_cose_key(key: &cose::PublicKey, buffer: &mut [u8]) -> crate::authenticator::Result<usize> {
+    cbor_serialize(key, buffer).map_err(|_| crate::authenticator::Error::Other)
+}
+
+/// Serialize a COSE public key, in canonical order, using `crate::cbor`.
+#[cfg(feature = "cbor-encoder")]
+pub fn serialize_cose_key(key: &cose::PublicKey, buffer: &mut [u8]) -> crate::authenticator::Result<usize> {
+    encode_cose_key(key, buffer)
+}
+
+#[cfg(any(test, feature = "cbor-encoder"))]
+fn encode_cose_key(key: &cose::PublicKey, buffer: &mut [u8]) -> crate::authenticator::Result<usize> {
+    use crate::cbor::Encoder;
+    // kty: EC2, crv: P-256
+    fn ec2(encoder: &mut Encoder, alg: i64, x: &[u8], y: &[u8]) -> crate::cbor::Result<()> {
+        encoder.map(5)?
+            .int(1)?.int(2)?
+            .int(3)?.int(alg)?
+            .int(-1)?.int(1)?
+            .int(-2)?.bytes(x)?
+            .int(-3)?.bytes(y)?;
+        Ok(())
+    }
+    // kty: OKP, alg: EdDSA, crv: Ed25519
+    fn okp(encoder: &mut Encoder, x: &[u8]) -> crate::cbor::Result<()> {
+        encoder.map(4)?
+            .int(1)?.int(1)?
+            .int(3)?.int(-8)?
+            .int(-1)?.int(6)?
+            .int(-2)?.bytes(x)?;
+        Ok(())
+    }
+
+    let mut encoder = Encoder::new(buffer);
+    match key {
+        cose::PublicKey::P256Key(key) => ec2(&mut encoder, -7, &key.x, &key.y),
+        cose::PublicKey::EcdhEsHkdf256Key(key) => ec2(&mut encoder, -25, &key.x, &key.y),
+        cose::PublicKey::Ed25519Key(key) => okp(&mut encoder, &key.x),
+    }.map_err(|_| crate::authenticator::Error::Other)?;
+    Ok(encoder.written())
 }
 
 
@@ -112,6 +159,8 @@ pub struct PublicKeyCredentialParameters {
     pub key_type: String<consts::U10>,
 }
 
+/// Unknown keys are skipped, notably the `transports` hint browsers add
+/// to allow list entries, which we have no use for as a USB authenticator.
 #[derive(Clone,Debug,Eq,PartialEq,Serialize,Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PublicKeyCredentialDescriptor {
@@ -119,13 +168,178 @@ pub struct PublicKeyCredentialDescriptor {
     #[serde(rename = "type")]
     pub key_type: String<consts::U10>,
     // https://w3c.github.io/webauthn/#enumdef-authenticatortransport
-    // transports: ...
+    // transports: skipped
 }
 
-// TODO: this is a bit weird to model...
-// Need to be able to "skip unknown keys" in deserialization
-#[derive(Clone,Debug,Eq,PartialEq,Serialize,Deserialize)]
-pub struct AuthenticatorExtensions {}
+/// Deserialize a packed (integer-keyed) parameters struct, skipping keys it
+/// doesn't know instead of failing the whole request, as `DeserializeIndexed`
+/// would. Hosts add optional entries as the spec evolves.
+///
+/// `required` fields must be present, `default` ones may be absent.
+macro_rules! deserialize_indexed_skipping_unknown {
+    ($name:ident {
+        required { $($index:literal => $field:ident),* $(,)? }
+        default { $($default_index:literal => $default_field:ident),* $(,)? }
+    }) => {
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                use serde::de::{Error, IgnoredAny, MapAccess, Visitor};
+
+                struct IndexedVisitor;
+
+                impl<'de> Visitor<'de> for IndexedVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        formatter.write_str(concat!(stringify!($name), " as an integer-keyed map"))
+                    }
+
+                    fn visit_map<M>(self, mut map: M) -> core::result::Result<$name, M::Error>
+                    where
+                        M: MapAccess<'de>,
+                    {
+                        $(let mut $field = None;)*
+                        $(let mut $default_field = None;)*
+
+                        while let Some(key) = map.next_key::<u64>()? {
+                            match key {
+                                $($index => {
+                                    if $field.is_some() {
+                                        return Err(M::Error::duplicate_field(stringify!($field)));
+                                    }
+                                    $field = Some(map.next_value()?);
+                                },)*
+                                $($default_index => {
+                                    if $default_field.is_some() {
+                                        return Err(M::Error::duplicate_field(stringify!($default_field)));
+                                    }
+                                    $default_field = Some(map.next_value()?);
+                                },)*
+                                _ => { map.next_value::<IgnoredAny>()?; },
+                            }
+                        }
+
+                        Ok($name {
+                            $($field: $field.ok_or_else(|| M::Error::missing_field(stringify!($field)))?,)*
+                            $($default_field: $default_field.unwrap_or_default(),)*
+                        })
+                    }
+                }
+
+                deserializer.deserialize_map(IndexedVisitor)
+            }
+        }
+    };
+}
+
+/// Exclude list, remembering whether the host sent more entries than fit.
+///
+/// Acting on only part of the list could create a duplicate credential,
+/// so such requests are rejected with `LimitExceeded`, and it's up to the
+/// host to send smaller lists.
+#[derive(Clone,Debug,Default,Eq,PartialEq)]
+pub struct ExcludeList {
+    pub descriptors: Vec<PublicKeyCredentialDescriptor, consts::U16>,
+    pub limit_exceeded: bool,
+}
+
+impl core::ops::Deref for ExcludeList {
+    type Target = Vec<PublicKeyCredentialDescriptor, consts::U16>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.descriptors
+    }
+}
+
+impl Serialize for ExcludeList {
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.descriptors.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExcludeList {
+    fn deserialize<D>(deserializer: D) -> core::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{SeqAccess, Visitor};
+
+        struct ExcludeListVisitor;
+
+        impl<'de> Visitor<'de> for ExcludeListVisitor {
+            type Value = ExcludeList;
+
+            fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                formatter.write_str("a sequence of credential descriptors")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> core::result::Result<ExcludeList, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut exclude_list = ExcludeList::default();
+                // consume all entries, so the rest of the request still parses
+                while let Some(descriptor) = seq.next_element()? {
+                    if exclude_list.descriptors.push(descriptor).is_err() {
+                        exclude_list.limit_exceeded = true;
+                    }
+                }
+                Ok(exclude_list)
+            }
+        }
+
+        deserializer.deserialize_seq(ExcludeListVisitor)
+    }
+}
+
+/// Extension inputs of get-assertion, unknown ones are skipped.
+#[derive(Clone,Debug,Default,Eq,PartialEq,Serialize,Deserialize)]
+pub struct GetAssertionExtensions {
+    #[serde(rename = "hmac-secret")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hmac_secret: Option<HmacSecretInput>,
+}
+
+/// The hmac-secret input of get-assertion: one or two salts, encrypted
+/// with the shared secret of a key agreement (as in clientPin).
+#[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed,DeserializeIndexed)]
+#[serde_indexed(offset = 1)]
+pub struct HmacSecretInput {
+    // 0x01, the platform's key agreement key
+    pub key_agreement: cose::P256PublicKey,
+    // 0x02, 32 or 64 bytes
+    pub salt_enc: Bytes<consts::U64>,
+    // 0x03, first 16 bytes of HMAC-SHA-256 of salt_enc using the shared secret
+    pub salt_auth: Bytes<consts::U16>,
+}
+
+/// Extension outputs of get-assertion.
+#[derive(Clone,Debug,Default,Eq,PartialEq,Serialize,Deserialize)]
+pub struct GetAssertionExtensionsOutput {
+    // the one or two HMACs, encrypted with the shared secret
+    #[serde(rename = "hmac-secret")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hmac_secret: Option<Bytes<consts::U64>>,
+}
+
+/// Extension inputs of make-credential, unknown ones are skipped.
+///
+/// Doubles as the extension outputs, so the fields are in canonical order.
+#[derive(Clone,Debug,Default,Eq,PartialEq,Serialize,Deserialize)]
+pub struct MakeCredentialExtensions {
+    #[serde(rename = "credProtect")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<u8>,
+    #[serde(rename = "hmac-secret")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hmac_secret: Option<bool>,
+}
 
 #[derive(Clone,Debug,Eq,PartialEq,Serialize,Deserialize)]
 pub struct AuthenticatorOptions {
@@ -137,7 +351,7 @@ pub struct AuthenticatorOptions {
     pub uv: Option<bool>,
 }
 
-#[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed,DeserializeIndexed)]
+#[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed)]
 // #[serde(rename_all = "camelCase")]
 #[serde_indexed(offset = 1)]
 pub struct GetAssertionParameters {
@@ -145,7 +359,7 @@ pub struct GetAssertionParameters {
     pub client_data_hash: Bytes<consts::U32>,
     pub allow_list: Vec<PublicKeyCredentialDescriptor, consts::U8>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub extensions: Option<AuthenticatorExtensions>,
+    pub extensions: Option<GetAssertionExtensions>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<AuthenticatorOptions>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -154,7 +368,24 @@ pub struct GetAssertionParameters {
     pub pin_protocol: Option<u32>,
 }
 
-#[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed,DeserializeIndexed)]
+deserialize_indexed_skipping_unknown!(GetAssertionParameters {
+    required { 1 => rp_id, 2 => client_data_hash }
+    default { 3 => allow_list, 4 => extensions, 5 => options, 6 => pin_auth, 7 => pin_protocol }
+});
+
+impl GetAssertionParameters {
+    /// Requested user presence, absent means `true`.
+    pub fn up(&self) -> bool {
+        self.options.as_ref().and_then(|options| options.up).unwrap_or(true)
+    }
+
+    /// Requested user verification, absent means `false`.
+    pub fn uv(&self) -> bool {
+        self.options.as_ref().and_then(|options| options.uv).unwrap_or(false)
+    }
+}
+
+#[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed)]
 // #[serde(rename_all = "camelCase")]
 #[serde_indexed(offset = 1)]
 pub struct MakeCredentialParameters {
@@ -164,9 +395,9 @@ pub struct MakeCredentialParameters {
     // e.g. webauthn.io sends 10
     pub pub_key_cred_params: Vec<PublicKeyCredentialParameters, consts::U12>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub exclude_list: Option<Vec<PublicKeyCredentialDescriptor, consts::U16>>,
+    pub exclude_list: Option<ExcludeList>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub extensions: Option<AuthenticatorExtensions>,
+    pub extensions: Option<MakeCredentialExtensions>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<AuthenticatorOptions>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -175,6 +406,24 @@ pub struct MakeCredentialParameters {
     pub pin_protocol: Option<u32>,
 }
 
+deserialize_indexed_skipping_unknown!(MakeCredentialParameters {
+    required { 1 => client_data_hash, 2 => rp, 3 => user, 4 => pub_key_cred_params }
+    default { 5 => exclude_list, 6 => extensions, 7 => options, 8 => pin_auth, 9 => pin_protocol }
+});
+
+// There is no `up` here, make-credential always tests user presence.
+impl MakeCredentialParameters {
+    /// Requested resident key, absent means `false`.
+    pub fn rk(&self) -> bool {
+        self.options.as_ref().and_then(|options| options.rk).unwrap_or(false)
+    }
+
+    /// Requested user verification, absent means `false`.
+    pub fn uv(&self) -> bool {
+        self.options.as_ref().and_then(|options| options.uv).unwrap_or(false)
+    }
+}
+
 //// This is some pretty weird stuff ^^
 //// Example serialization:
 //// { 1: 2,  // kty (key type): tstr / int  [ 2 = EC2 = elliptic curve with x and y coordinate pair
@@ -224,22 +473,44 @@ impl AttestedCredentialData {
 
         // use existing `bytes` buffer
         let mut cbor_key = [0u8; 128];
-        let l = cbor_serialize(&self.credential_public_key, &mut cbor_key).unwrap();
+        let l = serialize_cose_key(&self.credential_public_key, &mut cbor_key).unwrap();
         bytes.extend_from_slice(&cbor_key[..l]).unwrap();
 
         Bytes::from(bytes)
     }
 }
 
+/// The signature counter, which relying parties use for clone detection.
+///
+/// On the wire it is a 32-bit unsigned *big-endian* integer; getting this
+/// wrong would look like a huge counter jump to the relying party.
+#[derive(Copy,Clone,Debug,Default,Eq,PartialEq,Ord,PartialOrd)]
+pub struct SignCount(pub u32);
+
+impl SignCount {
+    pub fn to_bytes(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl From<u32> for SignCount {
+    fn from(count: u32) -> Self {
+        Self(count)
+    }
+}
+
 #[derive(Clone,Debug,Eq,PartialEq)]
 // #[serde(rename_all = "camelCase")]
 pub struct AuthenticatorData {
     pub rp_id_hash: Bytes<consts::U32>,
     pub flags: u8,
-    pub sign_count: u32,
+    pub sign_count: SignCount,
     // this can get pretty long
     pub attested_credential_data: Option<Bytes<ATTESTED_CREDENTIAL_DATA_LENGTH>>,
-    // pub extensions: ?
+    // already CBOR-serialized map of extension outputs,
+    // the EXTENSION_DATA flag (0x80) needs to be set if present.
+    // an hmac-secret output with two salts takes 79 bytes.
+    pub extensions: Option<Bytes<consts::U80>>,
 }
 
 impl AuthenticatorData {
@@ -251,7 +522,7 @@ impl AuthenticatorData {
         // flags
         bytes.push(self.flags).unwrap();
         // signature counts as 32-bit unsigned big-endian integer.
-        bytes.extend_from_slice(&self.sign_count.to_be_bytes()).unwrap();
+        bytes.extend_from_slice(&self.sign_count.to_bytes()).unwrap();
         match &self.attested_credential_data {
             Some(ref attested_credential_data) => {
                 // finally the attested credential data
@@ -259,6 +530,9 @@ impl AuthenticatorData {
             },
             None => {},
         }
+        if let Some(ref extensions) = &self.extensions {
+            bytes.extend_from_slice(&extensions).unwrap();
+        }
 
         Bytes::from(bytes)
     }
@@ -287,7 +561,8 @@ pub struct NoneAttestationStatement {}
 pub struct PackedAttestationStatement {
     pub alg: i32,
     pub sig: Bytes<ASN1_SIGNATURE_LENGTH>,
-    pub x5c: Vec<Bytes<consts::U1024>, consts::U1>,
+    /// attestation certificate, optionally followed by an intermediate CA's
+    pub x5c: Vec<Bytes<consts::U1024>, consts::U2>,
 }
 
 #[derive(Clone,Debug,Eq,PartialEq,Serialize)]
@@ -306,6 +581,20 @@ pub struct AttestationObject {
     pub att_stmt: AttestationStatement,
 }
 
+/// The authenticatorMakeCredential response as it goes over the wire:
+/// status byte 0x00, then the attestation object, keyed 1 to 3.
+/// Returns the number of bytes written, overflow is `Error::Other`.
+pub fn serialize_make_credential_response(
+    attestation_object: &AttestationObject,
+    out: &mut [u8],
+) -> crate::authenticator::Result<usize> {
+    use crate::authenticator::Error;
+    let (status, rest) = out.split_first_mut().ok_or(Error::Other)?;
+    *status = 0x00;
+    let size = cbor_serialize(attestation_object, rest).map_err(|_| Error::Other)?;
+    Ok(1 + size)
+}
+
 pub type AssertionResponses = Vec<AssertionResponse, consts::U8>;
 
 #[derive(Clone,Debug,Eq,PartialEq,SerializeIndexed,DeserializeIndexed)]
@@ -353,10 +642,123 @@ pub struct AuthenticatorInfo {
     // only in FIDO_2_1_PRE, see https://git.io/JeNxG
     // can be: usb, nfc, ble, internal
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub transports: Option<Vec<Bytes<consts::U8>, consts::U4>>,
+    pub transports: Option<Vec<String<consts::U8>, consts::U4>>,
+
+    // keys are positional, so the CTAP 2.1 fields up to the ones
+    // we're after are all here, even if we don't use them
+
+    // 0x0A
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub algorithms: Option<Vec<PublicKeyCredentialParameters, consts::U4>>,
+
+    // 0x0B
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_serialized_large_blob_array: Option<usize>,
+
+    // 0x0C
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force_pin_change: Option<bool>,
+
+    // 0x0D
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pin_length: Option<usize>,
+
+    // 0x0E
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware_version: Option<usize>,
 
-    // #[serde(skip_serializing_if = "Option::is_none")]
-    // pub(crate) algorithms: Option<&'l[u8]>,
+    // 0x0F
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_cred_blob_length: Option<usize>,
+
+    // 0x10
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certifications: Option<Certifications>,
+
+    // 0x11
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rp_ids_for_set_min_pin_length: Option<usize>,
+
+    // 0x12
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_platform_uv_attempts: Option<usize>,
+
+    // 0x13
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uv_modality: Option<usize>,
+
+    // 0x14
+    // an estimate, e.g. free slots of the resident credential store
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_discoverable_credentials: Option<usize>,
+}
+
+/// Transports a device exposes, for get-info.
+///
+/// USB is always there, as this is a USB driver.
+#[derive(Copy,Clone,Debug,Eq,PartialEq)]
+pub struct Transports(pub u8);
+
+impl Transports {
+    pub const USB: Self = Transports(0x01);
+    pub const NFC: Self = Transports(0x02);
+    pub const BLE: Self = Transports(0x04);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// As listed in get-info.
+    pub fn names(self) -> Vec<String<consts::U8>, consts::U4> {
+        let mut names = Vec::new();
+        let all = [(Self::USB, "usb"), (Self::NFC, "nfc"), (Self::BLE, "ble")];
+        for (transport, name) in all.iter() {
+            if (self | Self::USB).contains(*transport) {
+                // at most three, they fit
+                names.push(String::from(*name)).ok();
+            }
+        }
+        names
+    }
+}
+
+impl Default for Transports {
+    fn default() -> Self {
+        Self::USB
+    }
+}
+
+impl core::ops::BitOr for Transports {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Transports(self.0 | other.0)
+    }
+}
+
+/// Certifications the authenticator has, by their levels.
+///
+/// Fields are in canonical order: shorter keys first.
+#[derive(Clone,Debug,Default,Eq,PartialEq,Serialize,Deserialize)]
+pub struct Certifications {
+    #[serde(rename = "FIDO")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fido: Option<u8>,
+    #[serde(rename = "CC-EAL")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc_eal: Option<u8>,
+    #[serde(rename = "FIPS-CMVP-2")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fips_cmvp_2: Option<u8>,
+    #[serde(rename = "FIPS-CMVP-3")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fips_cmvp_3: Option<u8>,
+    #[serde(rename = "FIPS-CMVP-2-PHY")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fips_cmvp_2_phy: Option<u8>,
+    #[serde(rename = "FIPS-CMVP-3-PHY")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fips_cmvp_3_phy: Option<u8>,
 }
 
 impl Default for AuthenticatorInfo {
@@ -376,7 +778,17 @@ impl Default for AuthenticatorInfo {
             max_creds_in_list: None,
             max_cred_id_length: None,
             transports: None,
-            // algorithms: None,
+            algorithms: None,
+            max_serialized_large_blob_array: None,
+            force_pin_change: None,
+            min_pin_length: None,
+            firmware_version: None,
+            max_cred_blob_length: None,
+            certifications: None,
+            max_rp_ids_for_set_min_pin_length: None,
+            preferred_platform_uv_attempts: None,
+            uv_modality: None,
+            remaining_discoverable_credentials: None,
         }
     }
 }
@@ -417,6 +829,32 @@ impl Default for AuthenticatorInfo {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_cose_key_backends_agree() {
+        let p256 = cose::PublicKey::P256Key(cose::P256PublicKey {
+            x: Bytes::try_from_slice(&[0x0a; 32]).unwrap(),
+            y: Bytes::try_from_slice(&[0x0b; 32]).unwrap(),
+        });
+        let ed25519 = cose::PublicKey::Ed25519Key(cose::Ed25519PublicKey {
+            x: Bytes::try_from_slice(&[0x0c; 32]).unwrap(),
+        });
+
+        for key in [p256, ed25519].iter() {
+            let mut via_serde = [0u8; 128];
+            let size = cbor_serialize(key, &mut via_serde).unwrap();
+            let mut via_encoder = [0u8; 128];
+            let encoded_size = encode_cose_key(key, &mut via_encoder).unwrap();
+            assert_eq!(&via_encoder[..encoded_size], &via_serde[..size]);
+        }
+
+        // too small is an error, not a panic
+        let mut buffer = [0u8; 40];
+        assert!(encode_cose_key(&cose::PublicKey::P256Key(cose::P256PublicKey {
+            x: Bytes::try_from_slice(&[0x0a; 32]).unwrap(),
+            y: Bytes::try_from_slice(&[0x0b; 32]).unwrap(),
+        }), &mut buffer).is_err());
+    }
+
     #[test]
     fn test_serialize() {
         let mut buffer = [0u8; 64];
@@ -435,6 +873,67 @@ mod tests {
         // panic!("");
     }
 
+    #[test]
+    fn test_sign_count_is_big_endian() {
+        let auth_data = AuthenticatorData {
+            rp_id_hash: Bytes::try_from_slice(&[0u8; 32]).unwrap(),
+            flags: 0x01,
+            sign_count: SignCount(0x01020304),
+            attested_credential_data: None,
+            extensions: None,
+        };
+        let serialized = auth_data.serialize();
+        // 32 bytes RP ID hash, 1 byte flags, then the counter
+        assert_eq!(&serialized[33..37], &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(serialized.len(), 37);
+    }
+
+    #[test]
+    fn test_auth_data_extensions_come_last() {
+        // {"credProtect": 1}
+        let extensions = [
+            0xa1u8, 0x6b, 0x63, 0x72, 0x65, 0x64, 0x50, 0x72, 0x6f, 0x74, 0x65, 0x63, 0x74, 0x01,
+        ];
+        let auth_data = AuthenticatorData {
+            rp_id_hash: Bytes::try_from_slice(&[0u8; 32]).unwrap(),
+            flags: 0x01 | 0x80,
+            sign_count: SignCount(1),
+            attested_credential_data: None,
+            extensions: Some(Bytes::try_from_slice(&extensions).unwrap()),
+        };
+        let serialized = auth_data.serialize();
+        assert_eq!(serialized[32], 0x81);
+        assert_eq!(&serialized[37..], &extensions);
+    }
+
+    #[test]
+    fn test_hmac_secret_output() {
+        let output = GetAssertionExtensionsOutput {
+            hmac_secret: Some(Bytes::try_from_slice(&[0x42; 64]).unwrap()),
+        };
+        let mut buffer = [0u8; 128];
+        let size = cbor_serialize(&output, &mut buffer).unwrap();
+        // {"hmac-secret": h'4242...'}
+        assert_eq!(size, 79);
+        assert_eq!(&buffer[..13], &[0xa1, 0x6b, 0x68, 0x6d, 0x61, 0x63, 0x2d, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74]);
+        assert_eq!(&buffer[13..15], &[0x58, 0x40]);
+        assert_eq!(&buffer[15..size], &[0x42; 64][..]);
+
+        // two salts' worth still fits the authenticator data
+        let auth_data = AuthenticatorData {
+            rp_id_hash: Bytes::try_from_slice(&[0u8; 32]).unwrap(),
+            flags: 0x01 | 0x80,
+            sign_count: SignCount(1),
+            attested_credential_data: None,
+            extensions: Some(Bytes::try_from_slice(&buffer[..size]).unwrap()),
+        };
+        assert_eq!(&auth_data.serialize()[37..], &buffer[..size]);
+
+        let empty = GetAssertionExtensionsOutput::default();
+        assert_eq!(cbor_serialize(&empty, &mut buffer).unwrap(), 1);
+        assert_eq!(buffer[0], 0xa0);
+    }
+
     #[test]
     fn test_client_data_hash() {
         let mut minimal = [
@@ -517,6 +1016,395 @@ mod tests {
         // assert!(make_cred_params.third_client_data_hash.len() > 0);
     }
 
+    #[test]
+    fn test_make_credential_options_deser() {
+        let mut buffer = [
+        0xa5u8,
+        0x1,
+        0x50, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x30, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46,
+        0x2,
+        0xa1, 0x62, 0x69, 0x64, 0x73, 0x68, 0x74, 0x74, 0x70, 0x73, 0x3a, 0x2f,
+        0x2f, 0x79, 0x61, 0x6d, 0x6e, 0x6f, 0x72, 0x64, 0x2e, 0x63, 0x6f, 0x6d,
+        0x3,
+        0xa1, 0x62, 0x69, 0x64, 0x47, 0x6e, 0x69, 0x63, 0x6b, 0x72, 0x61, 0x79,
+        0x4,
+            0x81,
+                0xa2,
+                    0x63, 0x61, 0x6c, 0x67, 0x26,
+                    0x64, 0x74, 0x79, 0x70, 0x65, 0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+        // options, seventh entry: {"rk": true}
+        0x7,
+            0xa1, 0x62, 0x72, 0x6b, 0xf5,
+        ];
+
+        let params: MakeCredentialParameters = serde_cbor::de::from_mut_slice(&mut buffer).unwrap();
+        assert_eq!(params.options, Some(AuthenticatorOptions { rk: Some(true), up: None, uv: None }));
+        assert!(params.rk());
+        assert!(!params.uv());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_skipped() {
+        let mut buffer = [
+        0xa6u8,
+        0x1,
+        0x50, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x30, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46,
+        0x2,
+        0xa1, 0x62, 0x69, 0x64, 0x73, 0x68, 0x74, 0x74, 0x70, 0x73, 0x3a, 0x2f,
+        0x2f, 0x79, 0x61, 0x6d, 0x6e, 0x6f, 0x72, 0x64, 0x2e, 0x63, 0x6f, 0x6d,
+        0x3,
+        0xa1, 0x62, 0x69, 0x64, 0x47, 0x6e, 0x69, 0x63, 0x6b, 0x72, 0x61, 0x79,
+        0x4,
+            0x81,
+                0xa2,
+                    0x63, 0x61, 0x6c, 0x67, 0x26,
+                    0x64, 0x74, 0x79, 0x70, 0x65, 0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+        // unknown key 10, e.g. enterpriseAttestation of CTAP 2.1: 1
+        0xa,
+            0x01,
+        // unknown key 20, with a nested value: [{1: 2}]
+        0x14,
+            0x81, 0xa1, 0x01, 0x02,
+        ];
+
+        let params: MakeCredentialParameters = serde_cbor::de::from_mut_slice(&mut buffer).unwrap();
+        assert_eq!(&params.client_data_hash[..], b"1234567890ABCDEF");
+        assert_eq!(params.pub_key_cred_params.len(), 1);
+        assert_eq!(params.options, None);
+
+        // {1: "a.b", 2: h'00..00', 8: 3}, the last key is unknown
+        let mut buffer = [0u8; 64];
+        buffer[..6].copy_from_slice(&[0xa3, 0x01, 0x63, 0x61, 0x2e, 0x62]);
+        buffer[6..8].copy_from_slice(&[0x02, 0x58]);
+        buffer[8] = 0x20;
+        buffer[41..43].copy_from_slice(&[0x08, 0x03]);
+        let params: GetAssertionParameters = serde_cbor::de::from_mut_slice(&mut buffer[..43]).unwrap();
+        assert_eq!(params.rp_id.as_str(), "a.b");
+        // absent allow list means empty
+        assert!(params.allow_list.is_empty());
+        assert!(params.up());
+
+        // required keys are still required: drop the client data hash
+        let mut buffer = [0xa1u8, 0x01, 0x63, 0x61, 0x2e, 0x62];
+        assert!(serde_cbor::de::from_mut_slice::<GetAssertionParameters>(&mut buffer).is_err());
+    }
+
+    // {"foo": {"a": [1, {"b": 2}]}, "bar": [[]]}
+    const NESTED_UNKNOWN_MAP: [u8; 20] = [
+        0xa2,
+            0x63, 0x66, 0x6f, 0x6f,
+            0xa1, 0x61, 0x61, 0x82, 0x01, 0xa1, 0x61, 0x62, 0x02,
+            0x63, 0x62, 0x61, 0x72,
+            0x81, 0x80,
+    ];
+
+    #[test]
+    fn test_make_credential_skips_nested_unknown_extensions() {
+        let mut buffer = [0u8; 128];
+        let prefix = [
+            0xa5u8,
+            0x1,
+            0x50, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x30, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46,
+            0x2,
+            0xa1, 0x62, 0x69, 0x64, 0x6b, 0x79, 0x61, 0x6d, 0x6e, 0x6f, 0x72, 0x64, 0x2e, 0x63, 0x6f, 0x6d,
+            0x3,
+            0xa1, 0x62, 0x69, 0x64, 0x47, 0x6e, 0x69, 0x63, 0x6b, 0x72, 0x61, 0x79,
+            0x4,
+            0x81,
+                0xa2,
+                    0x63, 0x61, 0x6c, 0x67, 0x26,
+                    0x64, 0x74, 0x79, 0x70, 0x65, 0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+            // extensions, which we know nothing about
+            0x6,
+        ];
+        buffer[..prefix.len()].copy_from_slice(&prefix);
+        buffer[prefix.len()..][..NESTED_UNKNOWN_MAP.len()].copy_from_slice(&NESTED_UNKNOWN_MAP);
+        let size = prefix.len() + NESTED_UNKNOWN_MAP.len();
+
+        use serde::de;
+        let mut deserializer = serde_cbor::de::Deserializer::from_mut_slice(&mut buffer[..size]);
+        let make_cred_params: MakeCredentialParameters = de::Deserialize::deserialize(&mut deserializer).unwrap();
+        assert_eq!(make_cred_params.extensions, Some(MakeCredentialExtensions::default()));
+    }
+
+    #[test]
+    fn test_oversized_exclude_list() {
+        const ENTRIES: usize = 20;
+        let mut buffer = [0u8; 512];
+        // array of 20
+        buffer[0] = 0x94;
+        let mut size = 1;
+        for i in 0..ENTRIES {
+            let entry = [
+                0xa2,
+                    0x62, 0x69, 0x64, 0x41, i as u8,
+                    0x64, 0x74, 0x79, 0x70, 0x65, 0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+            ];
+            buffer[size..][..entry.len()].copy_from_slice(&entry);
+            size += entry.len();
+        }
+
+        let exclude_list: ExcludeList = serde_cbor::de::from_mut_slice(&mut buffer[..size]).unwrap();
+        assert!(exclude_list.limit_exceeded);
+        assert_eq!(exclude_list.len(), 16);
+        assert_eq!(&exclude_list[15].id[..], &[15]);
+
+        // exactly full is fine
+        buffer[0] = 0x90;
+        let size = 1 + 16 * (size - 1) / ENTRIES;
+        let exclude_list: ExcludeList = serde_cbor::de::from_mut_slice(&mut buffer[..size]).unwrap();
+        assert!(!exclude_list.limit_exceeded);
+        assert_eq!(exclude_list.len(), 16);
+    }
+
+    #[test]
+    fn test_descriptor_with_transports() {
+        let mut buffer = [
+            0x82u8,
+            // {"id": h'01020304', "type": "public-key", "transports": ["usb", "nfc"]}
+            0xa3,
+            0x62, 0x69, 0x64,
+            0x44, 0x01, 0x02, 0x03, 0x04,
+            0x64, 0x74, 0x79, 0x70, 0x65,
+            0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+            0x6a, 0x74, 0x72, 0x61, 0x6e, 0x73, 0x70, 0x6f, 0x72, 0x74, 0x73,
+            0x82, 0x63, 0x75, 0x73, 0x62, 0x63, 0x6e, 0x66, 0x63,
+            // {"id": h'05', "type": "public-key"}
+            0xa2,
+            0x62, 0x69, 0x64,
+            0x41, 0x05,
+            0x64, 0x74, 0x79, 0x70, 0x65,
+            0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+        ];
+
+        let allow_list: Vec<PublicKeyCredentialDescriptor, consts::U2> =
+            serde_cbor::de::from_mut_slice(&mut buffer).unwrap();
+        assert_eq!(&allow_list[0].id[..], &[1, 2, 3, 4]);
+        assert_eq!(allow_list[0].key_type.as_str(), "public-key");
+        assert_eq!(&allow_list[1].id[..], &[5]);
+
+        // and we don't send transports back
+        let mut serialized = [0u8; 64];
+        let size = cbor_serialize(&allow_list[1], &mut serialized).unwrap();
+        assert_eq!(&serialized[..size], &[
+            0xa2,
+            0x62, 0x69, 0x64,
+            0x41, 0x05,
+            0x64, 0x74, 0x79, 0x70, 0x65,
+            0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+        ]);
+    }
+
+    #[test]
+    fn test_get_assertion_options() {
+        let mut buffer = [
+            0xa4u8,
+            0x1,
+            0x6b, 0x79, 0x61, 0x6d, 0x6e, 0x6f, 0x72, 0x64, 0x2e, 0x63, 0x6f, 0x6d,
+            0x2,
+            0x50, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x30, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46,
+            0x3,
+            0x80,
+            // {"uv": true}
+            0x5,
+            0xa1, 0x62, 0x75, 0x76, 0xf5,
+        ];
+
+        use serde::de;
+        let mut deserializer = serde_cbor::de::Deserializer::from_mut_slice(&mut buffer);
+        let params: GetAssertionParameters = de::Deserialize::deserialize(&mut deserializer).unwrap();
+        assert_eq!(params.options, Some(AuthenticatorOptions { rk: None, up: None, uv: Some(true) }));
+        assert!(params.uv());
+        assert!(params.up());
+
+        // without options, spec defaults apply
+        let params = GetAssertionParameters { options: None, ..params };
+        assert!(params.up());
+        assert!(!params.uv());
+    }
+
+    #[test]
+    fn test_transport_names() {
+        let names = Transports::default().names();
+        assert_eq!(&names[..], &[String::<consts::U8>::from("usb")]);
+
+        let names = (Transports::USB | Transports::NFC).names();
+        assert_eq!(&names[..], &[String::<consts::U8>::from("usb"), String::from("nfc")]);
+
+        // USB can't be left out
+        assert_eq!(Transports::BLE.names().len(), 2);
+
+        // text strings, not byte strings
+        let info = AuthenticatorInfo { transports: Some(Transports::default().names()), ..AuthenticatorInfo::default() };
+        let mut buffer = [0u8; 128];
+        let size = cbor_serialize(&info, &mut buffer).unwrap();
+        let usb = [0x09, 0x81, 0x63, b'u', b's', b'b'];
+        assert!(buffer[..size].windows(usb.len()).any(|window| window == usb));
+    }
+
+    #[test]
+    fn test_option_defaults() {
+        // an empty options map
+        let mut buffer = [0xa0u8];
+        use serde::de;
+        let mut deserializer = serde_cbor::de::Deserializer::from_mut_slice(&mut buffer);
+        let no_options: AuthenticatorOptions = de::Deserialize::deserialize(&mut deserializer).unwrap();
+        assert_eq!(no_options, AuthenticatorOptions { rk: None, up: None, uv: None });
+        let explicit = AuthenticatorOptions { rk: Some(true), up: Some(false), uv: Some(true) };
+
+        let get_assertion = GetAssertionParameters {
+            rp_id: String::from("yamnord.com"),
+            client_data_hash: Bytes::try_from_slice(&[0u8; 32]).unwrap(),
+            allow_list: Vec::new(),
+            extensions: None,
+            options: None,
+            pin_auth: None,
+            pin_protocol: None,
+        };
+        // absent map, and present map without the keys
+        for options in [None, Some(no_options.clone())].iter() {
+            let params = GetAssertionParameters { options: options.clone(), ..get_assertion.clone() };
+            assert!(params.up());
+            assert!(!params.uv());
+        }
+        let params = GetAssertionParameters { options: Some(explicit.clone()), ..get_assertion };
+        assert!(!params.up());
+        assert!(params.uv());
+
+        let make_credential = MakeCredentialParameters {
+            client_data_hash: Bytes::try_from_slice(&[0u8; 32]).unwrap(),
+            rp: PublicKeyCredentialRpEntity { id: String::from("yamnord.com"), name: None, url: None },
+            user: PublicKeyCredentialUserEntity {
+                id: Bytes::try_from_slice(&[1]).unwrap(),
+                icon: None,
+                name: None,
+                display_name: None,
+            },
+            pub_key_cred_params: Vec::new(),
+            exclude_list: None,
+            extensions: None,
+            options: None,
+            pin_auth: None,
+            pin_protocol: None,
+        };
+        for options in [None, Some(no_options)].iter() {
+            let params = MakeCredentialParameters { options: options.clone(), ..make_credential.clone() };
+            assert!(!params.rk());
+            assert!(!params.uv());
+        }
+        let params = MakeCredentialParameters { options: Some(explicit), ..make_credential };
+        assert!(params.rk());
+        assert!(params.uv());
+    }
+
+    #[test]
+    fn test_assertion_response_canonical_order() {
+        use core::str::FromStr;
+        let response = AssertionResponse {
+            credential: Some(PublicKeyCredentialDescriptor {
+                id: Bytes::try_from_slice(&[0x01, 0x02]).unwrap(),
+                key_type: String::from_str("public-key").unwrap(),
+            }),
+            auth_data: Bytes::try_from_slice(&[0xaa; 4]).unwrap(),
+            signature: Bytes::try_from_slice(&[0x30, 0x00]).unwrap(),
+            user: Some(PublicKeyCredentialUserEntity::from(Bytes::try_from_slice(b"u").unwrap())),
+            number_of_credentials: Some(1),
+        };
+
+        let mut buffer = [0u8; 64];
+        let size = cbor_serialize(&response, &mut buffer).unwrap();
+
+        // keys in ascending order, as python-fido2 (and CTAP2 canonical CBOR) expects
+        #[rustfmt::skip]
+        let expected = [
+            0xa5u8,
+            // credential: {"id": h'0102', "type": "public-key"}
+            0x01,
+                0xa2,
+                    0x62, 0x69, 0x64, 0x42, 0x01, 0x02,
+                    0x64, 0x74, 0x79, 0x70, 0x65,
+                    0x6a, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0x2d, 0x6b, 0x65, 0x79,
+            // authData
+            0x02, 0x44, 0xaa, 0xaa, 0xaa, 0xaa,
+            // signature
+            0x03, 0x42, 0x30, 0x00,
+            // user: {"id": h'75'}
+            0x04, 0xa1, 0x62, 0x69, 0x64, 0x41, 0x75,
+            // numberOfCredentials
+            0x05, 0x01,
+        ];
+        assert_eq!(&buffer[..size], &expected[..]);
+    }
+
+    #[test]
+    fn test_ctap_options_canonical_order() {
+        let options = CtapOptions {
+            rk: true,
+            up: true,
+            uv: Some(true),
+            plat: false,
+            client_pin: Some(false),
+            cred_protect: Some(true),
+        };
+
+        let mut buffer = [0u8; 64];
+        let size = cbor_serialize(&options, &mut buffer).unwrap();
+
+        // shorter keys first, then bytewise, as python-fido2 expects
+        #[rustfmt::skip]
+        let expected = [
+            0xa6u8,
+            // "rk": true
+            0x62, 0x72, 0x6b, 0xf5,
+            // "up": true
+            0x62, 0x75, 0x70, 0xf5,
+            // "uv": true
+            0x62, 0x75, 0x76, 0xf5,
+            // "plat": false
+            0x64, 0x70, 0x6c, 0x61, 0x74, 0xf4,
+            // "clientPin": false
+            0x69, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x50, 0x69, 0x6e, 0xf4,
+            // "credProtect": true
+            0x6b, 0x63, 0x72, 0x65, 0x64, 0x50, 0x72, 0x6f, 0x74, 0x65, 0x63, 0x74, 0xf5,
+        ];
+        assert_eq!(&buffer[..size], &expected[..]);
+    }
+
+    #[test]
+    fn test_get_info_ctap21_fields() {
+        use core::str::FromStr;
+        let mut versions = Vec::new();
+        versions.push(String::from_str("FIDO_2_1").unwrap()).unwrap();
+        let info = AuthenticatorInfo {
+            versions,
+            options: None,
+            max_msg_size: None,
+            certifications: Some(Certifications { fido: Some(1), cc_eal: Some(2), ..Default::default() }),
+            remaining_discoverable_credentials: Some(5),
+            ..AuthenticatorInfo::default()
+        };
+
+        let mut buffer = [0u8; 64];
+        let size = cbor_serialize(&info, &mut buffer).unwrap();
+
+        #[rustfmt::skip]
+        let expected = [
+            0xa4u8,
+            // versions: ["FIDO_2_1"]
+            0x01, 0x81, 0x68, 0x46, 0x49, 0x44, 0x4f, 0x5f, 0x32, 0x5f, 0x31,
+            // aaguid
+            0x03, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // certifications: {"FIDO": 1, "CC-EAL": 2}
+            0x10,
+                0xa2,
+                    0x64, 0x46, 0x49, 0x44, 0x4f, 0x01,
+                    0x66, 0x43, 0x43, 0x2d, 0x45, 0x41, 0x4c, 0x02,
+            // remainingDiscoverableCredentials
+            0x14, 0x05,
+        ];
+        assert_eq!(&buffer[..size], &expected[..]);
+    }
+
     // #[test]
     // fn test_make_credential_params() {
 
@@ -3,7 +3,6 @@
 //! only CTAP2.
 
 use core::convert::TryInto;
-// use cortex_m_semihosting::hprintln;
 use crate::{
     bytes::{Bytes, consts},
     types::{